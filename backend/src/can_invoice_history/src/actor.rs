@@ -1,52 +1,95 @@
 use std::cell::RefCell;
 
 use ic_cdk::{
-    caller, export_candid, post_upgrade, pre_upgrade, query,
-    storage::{stable_restore, stable_save},
-    update,
+    api::stable::stable64_size, caller, export_candid, init, post_upgrade, query,
+    storage::stable_restore, update,
 };
+use ic_stable_structures::{memory_manager::MemoryManager, DefaultMemoryImpl};
 use shared::{
     invoice_history::{
-        api::{GetInvoiceRequest, GetInvoiceResponse, PushBatchRequest, PushBatchResponse},
-        state::State,
+        api::{
+            GetInvoiceByIdRequest, GetInvoiceByIdResponse, GetInvoiceRequest, GetInvoiceResponse,
+            GetShopInvoicesRequest, GetShopInvoicesResponse, InitArgs, PushBatchRequest,
+            PushBatchResponse, SetNextRequest, SetNextResponse,
+        },
+        state::{LegacyState, State},
     },
+    utils::is_stable_saved_layout,
     ENV_VARS,
 };
 
+const WASM_PAGE_SIZE_BYTES: u64 = 64 * 1024;
+
 thread_local! {
-    static STATE: RefCell<State> = RefCell::default();
+    static STATE: RefCell<State> = RefCell::new(
+        State::init(&MemoryManager::init(DefaultMemoryImpl::default()))
+    );
 }
 
-#[pre_upgrade]
-fn pre_upgrade_hook() {
-    STATE
-        .with_borrow(|s| stable_save((s,)))
-        .expect("Unable to stable_save");
+#[init]
+fn init_hook(args: Option<InitArgs>) {
+    if let Some(args) = args {
+        STATE.with_borrow_mut(|s| s.set_start_idx(args.start_idx));
+    }
 }
 
 #[post_upgrade]
 fn post_upgrade_hook() {
-    let (state,): (State,) = stable_restore().expect("Unable to stable_restore");
+    // older archives kept the whole state on the heap, saving it with stable_save - move it to stable structures once
+    // (this has to happen before STATE is touched, since the memory manager overwrites the beginning of stable memory)
+    if is_stable_saved_layout() {
+        let (legacy_state,): (LegacyState,) = stable_restore().expect("Unable to stable_restore");
+
+        STATE.with_borrow_mut(|s| {
+            s.set_start_idx(legacy_state.start_idx.unwrap_or_default());
 
-    STATE.with_borrow_mut(|s| *s = state);
+            if let Some(next) = legacy_state.next {
+                s.set_next(next);
+            }
+
+            for invoice in legacy_state.log {
+                s.push(invoice);
+            }
+        });
+    }
 }
 
 #[update(guard=only_parent)]
 fn push_batch(req: PushBatchRequest) -> PushBatchResponse {
-    STATE.with_borrow_mut(|it| {
-        it.log.extend(req.batch);
+    let len = STATE.with_borrow_mut(|it| {
+        for invoice in req.batch {
+            it.push(invoice);
+        }
+
+        it.len()
     });
 
-    PushBatchResponse {}
+    PushBatchResponse {
+        len,
+        memory_size_bytes: memory_size_bytes(),
+    }
+}
+
+#[update(guard=only_parent)]
+fn set_next(req: SetNextRequest) -> SetNextResponse {
+    STATE.with_borrow_mut(|it| it.set_next(req.next));
+
+    SetNextResponse {}
 }
 
 #[query]
 fn get_invoice(req: GetInvoiceRequest) -> GetInvoiceResponse {
-    STATE.with_borrow(|state| {
-        let entry = state.log.get((req.idx) as usize).expect("No invoice found");
+    STATE.with_borrow(|state| state.get(req.idx))
+}
+
+#[query]
+fn get_invoice_by_id(req: GetInvoiceByIdRequest) -> GetInvoiceByIdResponse {
+    STATE.with_borrow(|state| state.get_by_id(&req.id))
+}
 
-        Ok(entry.clone())
-    })
+#[query]
+fn get_shop_invoices(req: GetShopInvoicesRequest) -> GetShopInvoicesResponse {
+    STATE.with_borrow(|state| state.get_shop_invoices(&req))
 }
 
 fn only_parent() -> Result<(), String> {
@@ -57,4 +100,14 @@ fn only_parent() -> Result<(), String> {
     }
 }
 
+fn memory_size_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    let heap_pages = core::arch::wasm32::memory_size(0) as u64;
+
+    #[cfg(not(target_arch = "wasm32"))]
+    let heap_pages = 0u64;
+
+    (heap_pages + stable64_size()) * WASM_PAGE_SIZE_BYTES
+}
+
 export_candid!();
@@ -1,23 +1,65 @@
 use std::cell::RefCell;
 
-use candid::{CandidType, Nat};
-use futures::join;
+use candid::{CandidType, Principal};
 use ic_cdk::{
-    api::time,
+    api::{call::RejectionCode, is_controller, time},
     caller, export_candid, id, init, post_upgrade, pre_upgrade, query, spawn,
-    storage::{stable_restore, stable_save},
+    storage::stable_restore,
     update,
 };
-use ic_e8s::d::EDs;
-use icrc_ledger_types::icrc1::{account::Account, transfer::TransferArg};
+use ic_stable_structures::{memory_manager::MemoryManager, DefaultMemoryImpl};
+use icrc_ledger_types::{
+    icrc1::{account::Account, transfer::Memo},
+    icrc2::transfer_from::{TransferFromArgs, TransferFromError},
+};
 use msq_pay_types::{
-    CreateInvoiceRequest, CreateInvoiceResponse, GetInvoiceRequest, GetInvoiceResponse,
-    InvoiceStatus, VerifyPaymentRequest, VerifyPaymentResponse,
+    CancelInvoiceRequest, CancelInvoiceResponse, CreateInvoiceRequest, CreateInvoiceResponse,
+    GetInvoiceByExternalOrderIdRequest, GetInvoiceByExternalOrderIdResponse, GetInvoiceRequest,
+    GetInvoiceResponse, InvoiceStatus, PayInvoiceRequest, PayInvoiceResponse, VerifyPaymentRequest,
+    VerifyPaymentResponse,
 };
 use serde::Deserialize;
 use shared::{
-    exchange_rates::api::{GetExchangeRatesRequest, GetExchangeRatesResponse},
-    payment_hub::state::State,
+    admins::api::{
+        GetAdminsRequest, GetAdminsResponse, GrantAdminRequest, GrantAdminResponse,
+        RevokeAdminRequest, RevokeAdminResponse,
+    },
+    balances::api::{
+        GetBalanceDiscrepanciesRequest, GetBalanceDiscrepanciesResponse, GetShopBalancesRequest,
+        GetShopBalancesResponse,
+    },
+    exchange_rates::api::{
+        GetExchangeRatesRequest, GetExchangeRatesResponse, SetShouldMockExchangeRatesRequest,
+        SetShouldMockExchangeRatesResponse,
+    },
+    fees::api::{
+        GetFeeScheduleRequest, GetFeeScheduleResponse, SetFeeScheduleRequest,
+        SetFeeScheduleResponse, SetShopFeeScheduleRequest, SetShopFeeScheduleResponse,
+    },
+    icp_ledger::{
+        api::{GetShopAccountIdentifierRequest, GetShopAccountIdentifierResponse},
+        types::AccountIdentifier,
+    },
+    invoice_archives::api::{
+        FindInvoiceArchiveRequest, FindInvoiceArchiveResponse, GetArchivedInvoiceRequest,
+        GetArchivedInvoiceResponse, GetInvoiceArchivesRequest, GetInvoiceArchivesResponse,
+        SetInvoiceArchiveWasmRequest, SetInvoiceArchiveWasmResponse, UpdateArchivingConfigRequest,
+        UpdateArchivingConfigResponse,
+    },
+    invoice_history::api::GetInvoiceByIdRequest,
+    invoices::{
+        api::{RefundInvoiceRequest, RefundInvoiceResponse},
+        state::InvoicesState,
+        types::NewInvoice,
+    },
+    payment_hub::{
+        api::{
+            GetPlatformSettingsRequest, GetPlatformSettingsResponse, SetFeeCollectorAccountRequest,
+            SetFeeCollectorAccountResponse,
+        },
+        migrations::{migrate, v0::StateV0, STATE_VERSION},
+        state::State,
+    },
     shops::api::{
         GetMyReferredShopsRequest, GetMyReferredShopsResponse, GetMyShopsRequest,
         GetMyShopsResponse, GetShopByIdRequest, GetShopByIdResponse, RegisterShopRequest,
@@ -31,19 +73,31 @@ use shared::{
         },
         types::Token,
     },
-    utils::{calc_shop_subaccount, ShopId},
+    utils::{calc_shop_subaccount, is_stable_saved_layout, ShopId, TransferTxn},
+    withdrawals::{
+        api::{
+            GetShopWithdrawalsRequest, GetShopWithdrawalsResponse, GetWithdrawalRequest,
+            GetWithdrawalResponse,
+        },
+        types::{WithdrawalLeg, WithdrawalLegKind, WithdrawalLegStatus},
+    },
+    ENV_VARS,
 };
 use timers::init_timers;
 use utils::{
-    get_current_exchange_rate_timestamp, icrc3_block_to_transfer_txn, init_invoice_ids_seed,
-    init_supported_tokens, refresh_exchange_rates, set_immediate, ICRC1CanisterClient,
+    get_current_exchange_rate_timestamp, icp_block_to_transfer_txn, icrc3_block_to_transfer_txn,
+    init_invoice_ids_seed, init_supported_tokens, invoice_account, is_icp_ledger,
+    process_withdrawal, refresh_exchange_rates, set_immediate, shop_account, ICRC1CanisterClient,
+    IcpLedgerCanisterClient, InvoiceHistoryCanisterClient, PAY_INVOICE_MAX_ATTEMPTS,
 };
 
 mod timers;
 mod utils;
 
 thread_local! {
-    pub static STATE: RefCell<State> = RefCell::default();
+    pub static STATE: RefCell<State> = RefCell::new(
+        State::init(&MemoryManager::init(DefaultMemoryImpl::default()))
+    );
 }
 
 #[derive(CandidType, Deserialize)]
@@ -56,9 +110,12 @@ struct InitArgs {
 #[init]
 fn init_hook(args: InitArgs) {
     STATE.with_borrow_mut(|s| {
+        s.set_version(STATE_VERSION);
         s.set_fee_collector_account(args.fee_collector_account);
         s.exchange_rates
             .set_should_mock(args.should_mock_exchange_rates);
+        s.invoice_archives
+            .init_root(ENV_VARS.invoice_history_canister_id);
     });
 
     init_timers();
@@ -72,14 +129,29 @@ fn init_hook(args: InitArgs) {
 
 #[pre_upgrade]
 fn pre_upgrade_hook() {
-    STATE.with_borrow(|s| stable_save((s,)).expect("Unable to stable_save"));
+    STATE.with_borrow_mut(|s| s.save_heap_state());
 }
 
 #[post_upgrade]
 fn post_upgrade_hook() {
-    let (state,): (State,) = stable_restore().expect("Unable to stable_restore");
+    // v0 states were saved with stable_save - restore it before STATE is touched,
+    // since the memory manager overwrites the beginning of stable memory
+    let v0_opt = if is_stable_saved_layout() {
+        let (v0,): (StateV0,) = stable_restore().expect("Unable to stable_restore");
+
+        Some(v0)
+    } else {
+        None
+    };
+
+    STATE.with_borrow_mut(|s| {
+        let report = migrate(s, v0_opt).expect("Unable to migrate the state");
+        ic_cdk::println!("{}", report);
 
-    STATE.with_borrow_mut(|s| *s = state);
+        s.invoice_archives
+            .init_root(ENV_VARS.invoice_history_canister_id);
+        s.invoice_archives.is_spawning = false;
+    });
 
     init_timers();
 
@@ -94,9 +166,8 @@ fn get_exchange_rates(req: GetExchangeRatesRequest) -> GetExchangeRatesResponse
     let rates = STATE.with_borrow(|it| {
         Some(
             it.exchange_rates
-                .get_rates(req.timestamp.unwrap_or(it.exchange_rates.last_updated_at))?
-                .iter()
-                .map(|(k, v)| (k.clone(), v.clone()))
+                .get_rates(req.timestamp.unwrap_or(it.exchange_rates.last_updated_at()))?
+                .into_iter()
                 .collect::<Vec<_>>(),
         )
     });
@@ -106,12 +177,35 @@ fn get_exchange_rates(req: GetExchangeRatesRequest) -> GetExchangeRatesResponse
 
 #[query]
 fn get_invoice(req: GetInvoiceRequest) -> GetInvoiceResponse {
-    let invoice_opt =
-        STATE.with_borrow(|it| it.invoices.all_invoices.get(&req.invoice_id).cloned());
+    let invoice_opt = STATE.with_borrow(|it| it.invoices.get(&req.invoice_id));
 
     GetInvoiceResponse { invoice_opt }
 }
 
+#[query]
+fn get_invoice_by_external_order_id(
+    req: GetInvoiceByExternalOrderIdRequest,
+) -> GetInvoiceByExternalOrderIdResponse {
+    STATE.with_borrow(|s| {
+        let caller = caller();
+        if !s.shops.can_create_invoices(&req.shop_id, &caller)
+            && !is_shop_owner_or_admin(s, &req.shop_id, &caller)
+        {
+            panic!("Access denied");
+        }
+
+        let invoice_id = s
+            .invoices
+            .get_id_by_external_order_id(req.shop_id, &req.external_order_id);
+        let invoice_opt = invoice_id.and_then(|id| s.invoices.get(&id));
+
+        GetInvoiceByExternalOrderIdResponse {
+            invoice_id,
+            invoice_opt,
+        }
+    })
+}
+
 #[update]
 fn create_invoice(req: CreateInvoiceRequest) -> CreateInvoiceResponse {
     let can_create = STATE.with_borrow(|s| s.shops.can_create_invoices(&req.shop_id, &caller()));
@@ -120,15 +214,60 @@ fn create_invoice(req: CreateInvoiceRequest) -> CreateInvoiceResponse {
     }
 
     let exchange_rates_timestamp = get_current_exchange_rate_timestamp();
+    let now = time();
 
     let invoice_id = STATE.with_borrow_mut(|it| {
-        it.invoices.create(
-            req.qty_usd,
-            req.shop_id,
-            time(),
+        // a retry of an already processed request
+        if let Some(key) = &req.idempotency_key {
+            if let Some(invoice_id) =
+                it.invoices
+                    .get_id_by_idempotency_key(req.shop_id, caller(), key, now)
+            {
+                return invoice_id;
+            }
+        }
+
+        let idempotency_key = req
+            .idempotency_key
+            .map(|key| (key, it.shops.idempotency_key_expires_at(&req.shop_id, now)));
+
+        let (qty_usd, token_price, fiat_price) = it
+            .price_invoice(
+                &req.shop_id,
+                req.qty_usd,
+                req.token_price,
+                exchange_rates_timestamp,
+            )
+            .unwrap_or_else(|e| panic!("Unable to create invoice: {}", e));
+
+        let expires_at = it
+            .shops
+            .invoice_expires_at(&req.shop_id, req.ttl, now)
+            .unwrap_or_else(|e| panic!("Unable to create invoice: {}", e));
+
+        let (rate_lock_policy, underpayment_tolerance) = it.shops.invoice_terms(&req.shop_id);
+
+        let new_invoice = NewInvoice {
+            shop_id: req.shop_id,
+            creator: caller(),
+            qty_usd,
+            token_price,
+            fiat_price,
             exchange_rates_timestamp,
-            caller(),
-        )
+            expires_at,
+            rate_lock_policy,
+            underpayment_tolerance,
+            metadata: req.metadata,
+            idempotency_key,
+            deposit_account_owner: req
+                .use_deposit_subaccount
+                .unwrap_or_default()
+                .then_some(id()),
+        };
+
+        it.invoices
+            .create(new_invoice, now)
+            .unwrap_or_else(|e| panic!("Unable to create invoice: {}", e))
     });
 
     CreateInvoiceResponse { invoice_id }
@@ -136,19 +275,22 @@ fn create_invoice(req: CreateInvoiceRequest) -> CreateInvoiceResponse {
 
 #[update]
 async fn verify_payment(req: VerifyPaymentRequest) -> VerifyPaymentResponse {
-    let (exchange_rates_timestamp, ttl, decimals) = STATE.with_borrow_mut(|s| {
+    let (prev_status, decimals, invoice) = STATE.with_borrow_mut(|s| {
         let invoice = s
             .invoices
-            .all_invoices
-            .get_mut(&req.invoice_id)
+            .get(&req.invoice_id)
             .ok_or("Access denied".to_string())?;
 
         if invoice.creator != caller() {
             return Err("Access denied".to_string());
         }
 
-        let ttl = match invoice.status {
-            InvoiceStatus::Created { ttl } => ttl,
+        // transfers to expired or cancelled invoices are only recorded, so there is no status to restore
+        let prev_status = match invoice.status {
+            InvoiceStatus::Created { .. } | InvoiceStatus::PartiallyPaid => {
+                Some(invoice.status.clone())
+            }
+            InvoiceStatus::Expired { .. } | InvoiceStatus::Cancelled { .. } => None,
             _ => return Err("The invoice is already paid".to_string()),
         };
 
@@ -159,54 +301,203 @@ async fn verify_payment(req: VerifyPaymentRequest) -> VerifyPaymentResponse {
             .fee
             .decimals;
 
-        invoice.status = InvoiceStatus::VerifyPayment;
+        if prev_status.is_some() {
+            s.invoices
+                .set_status(&req.invoice_id, InvoiceStatus::VerifyPayment);
+        }
 
-        Ok((invoice.exchange_rates_timestamp, ttl, decimals))
+        Ok((prev_status, decimals, invoice))
     })?;
 
-    let token = ICRC1CanisterClient::new(req.asset_id);
-    let block = token.find_block(req.block_idx).await?;
+    let txn_res = if is_icp_ledger(&req.asset_id) {
+        let block_idx = u64::try_from(&req.block_idx.0).unwrap_or(u64::MAX);
+
+        IcpLedgerCanisterClient::new(req.asset_id)
+            .find_block(block_idx)
+            .await
+            .and_then(|block| icp_block_to_transfer_txn(&block, req.asset_id, decimals, &invoice))
+    } else {
+        ICRC1CanisterClient::new(req.asset_id)
+            .find_block(req.block_idx.clone())
+            .await
+            .and_then(|block| icrc3_block_to_transfer_txn(&block, req.asset_id, decimals))
+    };
 
-    let txn = icrc3_block_to_transfer_txn(&block, req.asset_id, decimals)?;
+    // the invoice should not get stuck in VerifyPayment status, otherwise the block scanner would skip it
+    let txn = match txn_res {
+        Ok(it) => it,
+        Err(err) => {
+            if let Some(prev_status) = prev_status {
+                STATE.with_borrow_mut(|s| s.invoices.set_status(&req.invoice_id, prev_status));
+            }
 
-    let ticker = STATE
-        .with_borrow(|s| s.supported_tokens.ticker_by_token_id(&txn.token_id))
-        .ok_or("Unsuported token".to_string())?;
+            return Err(err);
+        }
+    };
 
-    let exchange_rate = STATE.with_borrow(|s| {
-        s.exchange_rates
-            .get_exchange_rate(&exchange_rates_timestamp, &ticker)
-            .clone()
-    });
+    STATE.with_borrow_mut(|s| match prev_status {
+        Some(prev_status) => s.settle_invoice_payment(
+            &req.invoice_id,
+            txn,
+            req.block_idx,
+            prev_status,
+            id(),
+            time(),
+        ),
+        None => s.settle_late_payment(&req.invoice_id, txn, req.block_idx, id(), time()),
+    })
+}
 
-    let result = STATE.with_borrow_mut(|s| {
-        s.invoices
-            .verify_payment(&req.invoice_id, txn, exchange_rate, id(), time())
-    });
+#[update]
+fn cancel_invoice(req: CancelInvoiceRequest) -> CancelInvoiceResponse {
+    STATE.with_borrow_mut(|s| s.cancel_invoice(&req.invoice_id, caller(), id(), time()))
+}
 
-    match result {
-        // if failed, reset the invoice and return the error
-        Err(err) => STATE.with_borrow_mut(|s| {
-            let invoice = s.invoices.all_invoices.get_mut(&req.invoice_id).unwrap();
-            invoice.status = InvoiceStatus::Created { ttl };
-
-            Err(err)
-        }),
-        // if succeed, maybe delete outdated and return the invoice
-        Ok((invoice, should_delete_outdated)) => {
-            STATE.with_borrow_mut(|s| {
-                // if the active invoice list is empty now - delete the outdated exchange rates
-                if should_delete_outdated {
-                    s.exchange_rates
-                        .delete_outdated(&invoice.exchange_rates_timestamp);
+#[update]
+async fn pay_invoice(req: PayInvoiceRequest) -> PayInvoiceResponse {
+    let payer = Account {
+        owner: caller(),
+        subaccount: req.from_subaccount,
+    };
+
+    let (prev_status, txn, fee) = STATE.with_borrow_mut(|s| {
+        let invoice = s
+            .invoices
+            .get(&req.invoice_id)
+            .ok_or("Invoice not found".to_string())?;
+
+        let prev_status = match invoice.status {
+            InvoiceStatus::Created { .. } | InvoiceStatus::PartiallyPaid => invoice.status.clone(),
+            InvoiceStatus::Expired { .. } | InvoiceStatus::Cancelled { .. } => {
+                return Err("The invoice is expired or cancelled".to_string())
+            }
+            _ => return Err("The invoice is already paid".to_string()),
+        };
+
+        if InvoicesState::is_expired(&invoice, time()) {
+            return Err("The invoice is expired or cancelled".to_string());
+        }
+
+        let fee = s
+            .supported_tokens
+            .get_by_id(&req.asset_id)
+            .ok_or("Token not found")?
+            .fee
+            .clone();
+
+        // only the part, which is not paid yet, is transferred
+        let qty = match &invoice.token_price {
+            Some(price) if price.token_id != req.asset_id => {
+                return Err(format!(
+                    "The invoice can only be paid in {}",
+                    price.token_id
+                ));
+            }
+            Some(price) => {
+                let price_qty = price.qty.clone().to_decimals(fee.decimals);
+                let paid_qty = InvoicesState::paid_token_qty(&invoice, fee.decimals);
+
+                if paid_qty >= price_qty {
+                    return Err("The invoice is already paid".to_string());
                 }
 
-                let shop = s.shops.shops.get_mut(&invoice.shop_id).unwrap();
-                shop.total_earned_usd += &invoice.qty_usd;
-            });
+                &price_qty - &paid_qty
+            }
+            None => {
+                // the amount is calculated using the exchange rate the invoice was locked with
+                let exchange_rate = s.find_payment_exchange_rate(&req.invoice_id, &req.asset_id)?;
+                let rate_eds = exchange_rate.to_dynamic().to_decimals(fee.decimals);
+                let paid_qty_usd = InvoicesState::paid_qty_usd(&invoice);
+
+                if paid_qty_usd >= invoice.qty_usd {
+                    return Err("The invoice is already paid".to_string());
+                }
+
+                // rounded up, so a single call always covers the rest, even with no underpayment tolerance
+                InvoicesState::token_qty_covering(&(&invoice.qty_usd - &paid_qty_usd), &rate_eds)
+            }
+        };
+
+        let txn = TransferTxn {
+            from: Some(payer),
+            to: invoice_account(&invoice),
+            qty,
+            token_id: req.asset_id,
+            memo: Some(InvoicesState::make_invoice_memo(&req.invoice_id)),
+        };
+
+        s.invoices
+            .set_status(&req.invoice_id, InvoiceStatus::VerifyPayment);
+
+        Ok((prev_status, txn, fee))
+    })?;
+
+    // the arguments never change between the attempts, so the ledger deduplicates the transfer
+    let arg = TransferFromArgs {
+        spender_subaccount: None,
+        from: payer,
+        to: txn.to,
+        amount: (&txn.qty).into(),
+        fee: Some(fee.into()),
+        memo: txn.memo.map(|it| Memo::from(it.to_vec())),
+        created_at_time: Some(req.created_at_time.unwrap_or_else(time)),
+    };
+
+    let token = ICRC1CanisterClient::new(req.asset_id);
+    let mut attempt = 1;
+
+    let transfer_result = loop {
+        match token.icrc2_transfer_from(arg.clone()).await {
+            Ok((Ok(block_idx),)) => break Ok(block_idx),
+            // an earlier attempt (or call) went through
+            Ok((Err(TransferFromError::Duplicate { duplicate_of }),)) => break Ok(duplicate_of),
+            Ok((Err(e),)) => break Err(format!("Unable to transfer the funds: {}", e)),
+            Err((RejectionCode::SysTransient, _)) if attempt < PAY_INVOICE_MAX_ATTEMPTS => {
+                attempt += 1;
+            }
+            Err((code, msg)) => {
+                break Err(format!(
+                    "Unable to call the token canister: [{:?}] {}",
+                    code, msg
+                ))
+            }
+        }
+    };
 
-            Ok(invoice)
+    // if the transfer did go through after all, the block scanner will settle the invoice by its memo
+    let block_idx = match transfer_result {
+        Ok(it) => it,
+        Err(err) => {
+            STATE.with_borrow_mut(|s| s.invoices.set_status(&req.invoice_id, prev_status));
+
+            return Err(err);
         }
+    };
+
+    STATE.with_borrow_mut(|s| {
+        s.settle_invoice_payment(&req.invoice_id, txn, block_idx, prev_status, id(), time())
+    })
+}
+
+#[update]
+async fn refund_invoice(req: RefundInvoiceRequest) -> RefundInvoiceResponse {
+    let withdrawal = STATE
+        .with_borrow_mut(|s| s.refund_invoice(req, caller(), time()))
+        .expect("Unable to refund the invoice");
+
+    process_withdrawal(withdrawal.id).await;
+
+    let block_idx =
+        STATE.with_borrow(
+            |s| match s.withdrawals.get(&withdrawal.id).unwrap().legs[0].status {
+                WithdrawalLegStatus::Completed { ref block_idx } => Some(block_idx.clone()),
+                _ => None,
+            },
+        );
+
+    RefundInvoiceResponse {
+        withdrawal_id: withdrawal.id,
+        block_idx,
     }
 }
 
@@ -215,14 +506,22 @@ pub fn register_shop(req: RegisterShopRequest) -> RegisterShopResponse {
     // TODO: validate req
 
     let id = STATE.with_borrow_mut(|s| {
-        s.shops.create_shop(
+        let id = s.shops.create_shop(
             req.invoice_creators,
             req.name,
             req.description,
             req.icon_base64,
             req.referal,
             caller(),
-        )
+        );
+
+        if let Some(pricing_currency) = req.pricing_currency {
+            s.shops
+                .set_pricing_currency(&id, pricing_currency)
+                .expect("Unable to set pricing currency");
+        }
+
+        id
     });
 
     RegisterShopResponse { shop_id: id }
@@ -233,17 +532,7 @@ pub fn update_shop(req: UpdateShopRequest) -> UpdateShopRespose {
     // TODO: validate req
 
     STATE
-        .with_borrow_mut(|s| {
-            s.shops.update_shop(
-                req.id,
-                req.new_owner_opt,
-                req.new_invoice_creators_opt,
-                req.new_name_opt,
-                req.new_description_opt,
-                req.new_icon_base64_opt,
-                caller(),
-            )
-        })
+        .with_borrow_mut(|s| s.shops.update_shop(req, caller()))
         .expect("Unable to update shop");
 
     UpdateShopRespose {}
@@ -265,7 +554,7 @@ pub fn get_my_referred_shops(_req: GetMyReferredShopsRequest) -> GetMyReferredSh
 
 #[query]
 pub fn get_shop_by_id(req: GetShopByIdRequest) -> GetShopByIdResponse {
-    let shop = STATE.with_borrow(|s| s.shops.shops.get(&req.id).map(|it| it.as_pub()));
+    let shop = STATE.with_borrow(|s| s.shops.get_shop(&req.id).map(|it| it.as_pub()));
 
     GetShopByIdResponse { shop }
 }
@@ -275,14 +564,24 @@ pub fn get_shop_subaccount(id: ShopId) -> [u8; 32] {
     calc_shop_subaccount(id)
 }
 
+#[query]
+pub fn get_shop_account_identifier(
+    req: GetShopAccountIdentifierRequest,
+) -> GetShopAccountIdentifierResponse {
+    let account_identifier = AccountIdentifier::from(&shop_account(req.shop_id));
+
+    GetShopAccountIdentifierResponse {
+        account_identifier: account_identifier.to_hex(),
+    }
+}
+
 #[update]
 pub async fn withdraw_profit(req: WithdrawProfitRequest) -> WithdrawProfitResponse {
     // TODO: validate request
 
     let owner = STATE.with_borrow(|s| {
         s.shops
-            .shops
-            .get(&req.shop_id)
+            .get_shop(&req.shop_id)
             .expect("Shop not found")
             .owner
     });
@@ -299,7 +598,25 @@ pub async fn withdraw_profit(req: WithdrawProfitRequest) -> WithdrawProfitRespon
         })
         .expect("Unsupported token");
 
-    let min_qty = &system_fee * 6u64;
+    let to_account_identifier = req.to_account_identifier.as_deref().map(|it| {
+        if !is_icp_ledger(&req.asset_id) {
+            panic!("Account identifiers are only supported by the ICP ledger");
+        }
+
+        AccountIdentifier::from_hex(it).expect("Invalid account identifier")
+    });
+
+    let (fee_collector_account_opt, referral_opt, fee_schedule, monthly_volume_usd) = STATE
+        .with_borrow(|s| {
+            let fee_collector = s.fee_collector_account;
+            let referral = s.shops.get_referral(&req.shop_id);
+            let fee_schedule = s.fees.schedule_for(&req.shop_id);
+            let monthly_volume_usd = s.fees.monthly_volume(req.shop_id, time());
+
+            (fee_collector, referral, fee_schedule, monthly_volume_usd)
+        });
+
+    let min_qty = &system_fee * fee_schedule.min_withdraw_fee_multiplier;
     let qty = req.qty.to_dynamic().to_decimals(system_fee.decimals);
 
     if qty < min_qty {
@@ -309,153 +626,160 @@ pub async fn withdraw_profit(req: WithdrawProfitRequest) -> WithdrawProfitRespon
         );
     }
 
-    let (fee_collector_account_opt, referral_opt) = STATE.with_borrow(|s| {
-        let fee_collector = s.fee_collector_account;
-        let referral = s.shops.get_referral(&req.shop_id);
-
-        (fee_collector, referral)
-    });
+    let split = fee_schedule.split(
+        qty,
+        &monthly_volume_usd,
+        fee_collector_account_opt.is_some(),
+        referral_opt.is_some(),
+    );
+    let (withdraw_qty, fmj_fee, referal_fee) =
+        (split.withdraw_qty, split.fmj_fee, split.referral_fee);
 
-    // fmj gets 3% fee from the withdrawn amount, referal gets 20% fee from the fmj fee
-    let (withdraw_qty, fmj_fee, referal_fee) = match (fee_collector_account_opt, referral_opt) {
-        (Some(_), Some(_)) => {
-            let withdraw_qty = &qty * 97u64 / 100u64;
-            let fmj_fee = (&qty - &withdraw_qty) * 80u64 / 100u64;
-            let referal_fee = &qty - &withdraw_qty - &fmj_fee;
+    if withdraw_qty <= system_fee {
+        panic!("Insufficient funds: nothing left to withdraw after fees");
+    }
 
-            (withdraw_qty, fmj_fee, referal_fee)
+    let now = time();
+
+    // all legs are journaled before any transfer is made, so the failed ones could be retried later
+    let withdrawal = STATE.with_borrow_mut(|s| {
+        let withdrawal =
+            s.withdrawals
+                .create(req.shop_id, req.asset_id, caller(), None, now, |id| {
+                    let mut legs = vec![WithdrawalLeg {
+                        to_account_identifier,
+                        ..WithdrawalLeg::new(
+                            id,
+                            WithdrawalLegKind::Withdraw,
+                            req.to,
+                            withdraw_qty,
+                            system_fee.clone(),
+                            req.memo,
+                            now,
+                        )
+                    }];
+
+                    // fee legs too small to cover the transfer fee are left in the shop's subaccount
+                    if let Some(fee_collector_account) = fee_collector_account_opt {
+                        if fmj_fee > system_fee {
+                            legs.push(WithdrawalLeg::new(
+                                id,
+                                WithdrawalLegKind::PlatformFee,
+                                fee_collector_account,
+                                fmj_fee,
+                                system_fee.clone(),
+                                None,
+                                now,
+                            ));
+                        }
+                    }
+
+                    if let Some(referral) = referral_opt {
+                        if referal_fee > system_fee {
+                            legs.push(WithdrawalLeg::new(
+                                id,
+                                WithdrawalLegKind::ReferralFee,
+                                Account {
+                                    owner: referral,
+                                    subaccount: None,
+                                },
+                                referal_fee,
+                                system_fee.clone(),
+                                None,
+                                now,
+                            ));
+                        }
+                    }
+
+                    legs
+                });
+
+        for leg in withdrawal.legs.iter() {
+            s.balances.debit(req.shop_id, req.asset_id, &leg.qty);
         }
-        (None, Some(_)) => {
-            let withdraw_qty = &qty * 97u64 / 100u64;
-            let fmj_fee = EDs::from((0u64, qty.decimals));
-            let referal_fee = &qty - &withdraw_qty;
 
-            (withdraw_qty, fmj_fee, referal_fee)
-        }
-        (Some(_), None) => {
-            let withdraw_qty = &qty * 97u64 / 100u64;
-            let fmj_fee = &qty - &withdraw_qty;
-            let referal_fee = EDs::from((0u64, qty.decimals));
+        withdrawal
+    });
 
-            (withdraw_qty, fmj_fee, referal_fee)
-        }
-        (None, None) => {
-            let withdraw_qty = qty;
-            let fmj_fee = EDs::from((0u64, withdraw_qty.decimals));
-            let referal_fee = EDs::from((0u64, withdraw_qty.decimals));
+    process_withdrawal(withdrawal.id).await;
 
-            (withdraw_qty, fmj_fee, referal_fee)
-        }
-    };
+    let block_idx =
+        STATE.with_borrow(
+            |s| match s.withdrawals.get(&withdrawal.id).unwrap().legs[0].status {
+                WithdrawalLegStatus::Completed { ref block_idx } => Some(block_idx.clone()),
+                _ => None,
+            },
+        );
 
-    let token = ICRC1CanisterClient::new(req.asset_id);
-    let shop_subaccount = calc_shop_subaccount(req.shop_id);
-
-    let withdraw_transfer_future = async {
-        let call_result = token
-            .icrc1_transfer(TransferArg {
-                from_subaccount: Some(shop_subaccount),
-                to: req.to,
-                fee: Some((&system_fee).into()),
-                memo: req.memo,
-                created_at_time: None,
-                amount: (withdraw_qty - &system_fee).into(),
-            })
-            .await;
-
-        if let Err((code, msg)) = call_result {
-            return Err(format!(
-                "Unable to make an ICRC-1 transfer call to {}: [{:?}] {}",
-                req.asset_id, code, msg
-            ));
-        }
+    WithdrawProfitResponse {
+        withdrawal_id: withdrawal.id,
+        block_idx,
+    }
+}
 
-        let (transfer_result,) = call_result.unwrap();
+#[query]
+fn get_withdrawal(req: GetWithdrawalRequest) -> GetWithdrawalResponse {
+    let withdrawal = STATE.with_borrow(|s| {
+        let withdrawal = s.withdrawals.get(&req.id)?;
 
-        if let Err(transfer_err) = transfer_result {
-            return Err(format!(
-                "Unable to make an ICRC-1 transfer to {}: {}",
-                req.asset_id, transfer_err
-            ));
+        if is_shop_owner_or_admin(s, &withdrawal.shop_id, &caller()) {
+            Some(withdrawal)
+        } else {
+            None
         }
+    });
 
-        let block_idx = transfer_result.unwrap();
+    GetWithdrawalResponse { withdrawal }
+}
 
-        Ok(block_idx)
-    };
+#[query]
+fn get_shop_withdrawals(req: GetShopWithdrawalsRequest) -> GetShopWithdrawalsResponse {
+    let withdrawals = STATE
+        .with_borrow(|s| {
+            if !is_shop_owner_or_admin(s, &req.shop_id, &caller()) {
+                return Err(String::from("Access denied"));
+            }
 
-    let fmj_fee_transfer_future = async {
-        if let Some(fee_collector_account) = fee_collector_account_opt {
-            let _result = token
-                .icrc1_transfer(TransferArg {
-                    from_subaccount: Some(shop_subaccount),
-                    to: fee_collector_account,
-                    fee: Some((&system_fee).into()),
-                    memo: None,
-                    created_at_time: None,
-                    amount: (fmj_fee - &system_fee).into(),
-                })
-                .await;
-        }
+            s.withdrawals
+                .get_by_shop(req.shop_id, req.start_after, req.limit)
+        })
+        .expect("Unable to get shop withdrawals");
 
-        // a stupid way of defining the type
-        if false {
-            return Err(String::new());
-        } else {
-            Ok(Nat::from(0u8))
-        }
-    };
+    GetShopWithdrawalsResponse { withdrawals }
+}
 
-    let referal_fee_transfer_future = async {
-        if let Some(referral) = referral_opt {
-            let result = token
-                .icrc1_transfer(TransferArg {
-                    from_subaccount: Some(shop_subaccount),
-                    to: Account {
-                        owner: referral,
-                        subaccount: None,
-                    },
-                    fee: Some((&system_fee).into()),
-                    memo: None,
-                    created_at_time: None,
-                    amount: (&referal_fee - &system_fee).into(),
-                })
-                .await;
-
-            if result.is_ok() {
-                STATE.with_borrow_mut(|s| {
-                    let earnings = s
-                        .shops
-                        .referral_to_shops
-                        .get_mut(&referral)
-                        .unwrap()
-                        .get_mut(&req.shop_id)
-                        .unwrap();
-
-                    *earnings += referal_fee.to_decimals(8).to_const();
-                })
+#[query]
+fn get_shop_balances(req: GetShopBalancesRequest) -> GetShopBalancesResponse {
+    let balances = STATE
+        .with_borrow(|s| {
+            if !is_shop_owner_or_admin(s, &req.shop_id, &caller()) {
+                return Err(String::from("Access denied"));
             }
-        }
 
-        if false {
-            return Err(String::new());
-        } else {
-            Ok(Nat::from(0u8))
-        }
-    };
+            Ok(s.balances.get_by_shop(req.shop_id))
+        })
+        .expect("Unable to get shop balances");
 
-    // complete all transfers in once (this may lead to problems)
-    let (withdraw_transfer_result, _, _) = join!(
-        withdraw_transfer_future,
-        fmj_fee_transfer_future,
-        referal_fee_transfer_future
-    );
+    GetShopBalancesResponse { balances }
+}
 
-    // only fail if the withdraw fails, ignore other failures
-    match withdraw_transfer_result {
-        Err(qty_transfer_error) => panic!("{}", qty_transfer_error),
-        Ok(block_idx) => WithdrawProfitResponse { block_idx },
-    }
+#[query(guard=only_admin)]
+fn get_balance_discrepancies(
+    _req: GetBalanceDiscrepanciesRequest,
+) -> GetBalanceDiscrepanciesResponse {
+    let discrepancies = STATE.with_borrow(|s| s.balances.get_discrepancies());
+
+    GetBalanceDiscrepanciesResponse { discrepancies }
+}
+
+fn is_shop_owner_or_admin(s: &State, shop_id: &ShopId, caller: &Principal) -> bool {
+    let is_owner = s
+        .shops
+        .get_shop(shop_id)
+        .map(|it| it.owner == *caller)
+        .unwrap_or_default();
+
+    is_owner || is_admin(s, caller)
 }
 
 #[query]
@@ -466,18 +790,163 @@ fn get_supported_tokens(_req: GetSupportedTokensRequest) -> GetSupportedTokensRe
     GetSupportedTokensResponse { supported_tokens }
 }
 
-#[update]
+#[update(guard=only_admin)]
 fn add_supported_token(req: AddSupportedTokenRequest) -> AddSupportedTokenResponse {
     STATE.with_borrow_mut(|it| it.supported_tokens.add_token(req.token));
 
     AddSupportedTokenResponse {}
 }
 
-#[update]
+#[update(guard=only_admin)]
 fn remove_supported_token(req: RemoveSupportedTokenRequest) -> RemoveSupportedTokenResponse {
     STATE.with_borrow_mut(|it| it.supported_tokens.remove_token(req.ticker));
 
     RemoveSupportedTokenResponse {}
 }
 
+#[query]
+fn get_invoice_archives(_req: GetInvoiceArchivesRequest) -> GetInvoiceArchivesResponse {
+    STATE.with_borrow(|s| GetInvoiceArchivesResponse {
+        archives: s.invoice_archives.archives.clone(),
+        config: s.invoice_archives.config,
+    })
+}
+
+#[query]
+fn find_invoice_archive(req: FindInvoiceArchiveRequest) -> FindInvoiceArchiveResponse {
+    STATE.with_borrow(|s| FindInvoiceArchiveResponse {
+        archive_opt: s.invoice_archives.find_by_idx(req.idx),
+    })
+}
+
+/**
+ * Archives only point to the next one, so this looks the invoice up in each of them - the newest first
+ */
+#[query(composite = true)]
+async fn get_archived_invoice(req: GetArchivedInvoiceRequest) -> GetArchivedInvoiceResponse {
+    let archives = STATE.with_borrow(|s| s.invoice_archives.archives.clone());
+
+    for archive in archives.into_iter().rev() {
+        let (resp,) = InvoiceHistoryCanisterClient::new(archive.canister_id)
+            .get_invoice_by_id(GetInvoiceByIdRequest { id: req.invoice_id })
+            .await
+            .unwrap_or_else(|(code, msg)| {
+                panic!("Unable to call the invoice archive: [{:?}] {}", code, msg)
+            });
+
+        if let Ok(invoice) = resp {
+            return GetArchivedInvoiceResponse {
+                invoice_opt: Some(invoice),
+                archive_opt: Some(archive.canister_id),
+            };
+        }
+    }
+
+    GetArchivedInvoiceResponse {
+        invoice_opt: None,
+        archive_opt: None,
+    }
+}
+
+#[update(guard=only_admin)]
+fn set_invoice_archive_wasm(req: SetInvoiceArchiveWasmRequest) -> SetInvoiceArchiveWasmResponse {
+    STATE.with_borrow_mut(|s| s.invoice_archives.archive_wasm = Some(req.wasm));
+
+    SetInvoiceArchiveWasmResponse {}
+}
+
+#[update(guard=only_admin)]
+fn update_archiving_config(req: UpdateArchivingConfigRequest) -> UpdateArchivingConfigResponse {
+    STATE.with_borrow_mut(|s| s.invoice_archives.config = req.config);
+
+    UpdateArchivingConfigResponse {}
+}
+
+#[query]
+fn get_fee_schedule(req: GetFeeScheduleRequest) -> GetFeeScheduleResponse {
+    let schedule = STATE.with_borrow(|s| match req.shop_id {
+        Some(shop_id) => s.fees.schedule_for(&shop_id),
+        None => s.fees.default_schedule.get().clone(),
+    });
+
+    GetFeeScheduleResponse { schedule }
+}
+
+#[update(guard=only_admin)]
+fn set_fee_schedule(req: SetFeeScheduleRequest) -> SetFeeScheduleResponse {
+    STATE
+        .with_borrow_mut(|s| s.fees.set_default_schedule(req.schedule))
+        .expect("Unable to set fee schedule");
+
+    SetFeeScheduleResponse {}
+}
+
+#[update(guard=only_admin)]
+fn set_shop_fee_schedule(req: SetShopFeeScheduleRequest) -> SetShopFeeScheduleResponse {
+    STATE
+        .with_borrow_mut(|s| s.fees.set_shop_schedule(req.shop_id, req.schedule))
+        .expect("Unable to set shop fee schedule");
+
+    SetShopFeeScheduleResponse {}
+}
+
+#[query]
+fn get_admins(_req: GetAdminsRequest) -> GetAdminsResponse {
+    let admins = STATE.with_borrow(|s| s.admins.get());
+
+    GetAdminsResponse { admins }
+}
+
+#[update(guard=only_admin)]
+fn grant_admin(req: GrantAdminRequest) -> GrantAdminResponse {
+    STATE.with_borrow_mut(|s| s.admins.grant(req.principal));
+
+    GrantAdminResponse {}
+}
+
+#[update(guard=only_admin)]
+fn revoke_admin(req: RevokeAdminRequest) -> RevokeAdminResponse {
+    STATE
+        .with_borrow_mut(|s| s.admins.revoke(&req.principal))
+        .expect("Unable to revoke admin");
+
+    RevokeAdminResponse {}
+}
+
+#[query]
+fn get_platform_settings(_req: GetPlatformSettingsRequest) -> GetPlatformSettingsResponse {
+    STATE.with_borrow(|s| GetPlatformSettingsResponse {
+        fee_collector_account: s.fee_collector_account,
+        should_mock_exchange_rates: s.exchange_rates.should_mock(),
+    })
+}
+
+#[update(guard=only_admin)]
+fn set_fee_collector_account(req: SetFeeCollectorAccountRequest) -> SetFeeCollectorAccountResponse {
+    STATE.with_borrow_mut(|s| s.set_fee_collector_account(req.fee_collector_account));
+
+    SetFeeCollectorAccountResponse {}
+}
+
+#[update(guard=only_admin)]
+fn set_should_mock_exchange_rates(
+    req: SetShouldMockExchangeRatesRequest,
+) -> SetShouldMockExchangeRatesResponse {
+    STATE.with_borrow_mut(|s| s.exchange_rates.set_should_mock(req.should_mock));
+
+    SetShouldMockExchangeRatesResponse {}
+}
+
+fn is_admin(s: &State, principal: &Principal) -> bool {
+    is_controller(principal) || s.admins.is_admin(principal)
+}
+
+fn only_admin() -> Result<(), String> {
+    if STATE.with_borrow(|s| is_admin(s, &caller())) {
+        Ok(())
+    } else {
+        Err(String::from("Access denied"))
+    }
+}
+
 export_candid!();
@@ -4,7 +4,11 @@ use chrono::{Datelike, Days, Duration, TimeZone, Utc};
 use ic_cdk::{api::time, spawn};
 use ic_cdk_timers::{set_timer, set_timer_interval};
 
-use crate::utils::{archive_inactive_invoices, garbage_collect_invoices, refresh_exchange_rates};
+use crate::utils::{
+    archive_inactive_invoices, garbage_collect_invoices, reconcile_shop_balances,
+    refresh_exchange_rates, retry_pending_withdrawals, scan_payment_blocks,
+    sweep_deposit_subaccounts,
+};
 
 // ------------------------ STATE -------------------------
 
@@ -24,13 +28,23 @@ pub fn init_timers() {
 
     let closest_2am_utc =
         StdDuration::from_nanos((next_2am_utc - now_utc).num_nanoseconds().unwrap() as u64);
+    let each_minute = Duration::minutes(1).to_std().unwrap();
     let each_10_minutes = Duration::minutes(10).to_std().unwrap();
+    let each_day = Duration::days(1).to_std().unwrap();
 
     set_timer(closest_2am_utc, handle_exchange_rates_fetch_timer);
 
     set_timer(closest_2am_utc, handle_archive_inactive_invoices_timer);
 
-    set_timer_interval(each_10_minutes, handle_discard_expired_invoices_interval);
+    set_timer_interval(each_minute, handle_discard_expired_invoices_interval);
+
+    set_timer_interval(each_10_minutes, handle_retry_pending_withdrawals_interval);
+
+    set_timer_interval(each_day, handle_reconcile_shop_balances_interval);
+
+    set_timer_interval(each_minute, handle_scan_payment_blocks_interval);
+
+    set_timer_interval(each_10_minutes, handle_sweep_deposit_subaccounts_interval);
 }
 
 fn handle_exchange_rates_fetch_timer() {
@@ -70,3 +84,19 @@ fn handle_archive_inactive_invoices_timer() {
 fn handle_discard_expired_invoices_interval() {
     garbage_collect_invoices();
 }
+
+fn handle_retry_pending_withdrawals_interval() {
+    spawn(retry_pending_withdrawals());
+}
+
+fn handle_reconcile_shop_balances_interval() {
+    spawn(reconcile_shop_balances());
+}
+
+fn handle_scan_payment_blocks_interval() {
+    spawn(scan_payment_blocks());
+}
+
+fn handle_sweep_deposit_subaccounts_interval() {
+    spawn(sweep_deposit_subaccounts());
+}
@@ -1,14 +1,18 @@
 use std::time::Duration;
 
+use candid::encode_args;
 use candid::{Nat, Principal};
-use futures::FutureExt;
+use futures::{future::join_all, FutureExt};
 use ic_cdk::{
     api::{
         call::{call_with_payment, CallResult},
-        management_canister::main::raw_rand,
+        management_canister::main::{
+            create_canister, install_code, raw_rand, CanisterInstallMode, CanisterSettings,
+            CreateCanisterArgument, InstallCodeArgument,
+        },
         time,
     },
-    call,
+    call, id,
 };
 use ic_cdk_timers::set_timer;
 use ic_e8s::d::EDs;
@@ -20,26 +24,55 @@ use icrc_ledger_types::{
     icrc::generic_value::ICRC3Value,
     icrc1::{
         account::Account,
-        transfer::{BlockIndex, TransferArg, TransferError},
+        transfer::{BlockIndex, Memo, TransferArg, TransferError},
     },
+    icrc2::transfer_from::{TransferFromArgs, TransferFromError},
     icrc3::blocks::{BlockWithId, GetBlocksRequest, GetBlocksResult},
 };
+use msq_pay_types::{Invoice, InvoiceId, InvoiceRefundStatus, InvoiceStatus, OverpaymentStatus};
+use num_bigint::BigUint;
 use shared::{
+    balances::types::BalanceDiscrepancy,
+    exchange_rates::types::BASE_CURRENCY,
+    icp_ledger::types::{
+        AccountIdentifier, GetBlocksArgs, IcpBlock, IcpTransferArgs, IcpTransferError,
+        IcpTransferResult, Operation, QueryArchiveResult, QueryBlocksResponse,
+    },
+    invoice_history::{
+        api::{
+            GetInvoiceByIdRequest, GetInvoiceByIdResponse, InitArgs, PushBatchRequest,
+            PushBatchResponse, SetNextRequest, SetNextResponse,
+        },
+        types::ArchivedInvoice,
+    },
+    invoices::{state::InvoicesState, types::DepositSweep},
+    payment_hub::state::State,
     supported_tokens::types::Token,
-    utils::{Timestamp, TransferTxn, EXCHANGE_RATES_CANISTER_ID},
+    utils::{
+        calc_shop_subaccount, ShopId, Timestamp, TokenId, TransferTxn, EXCHANGE_RATES_CANISTER_ID,
+        ICP_LEDGER_CANISTER_ID,
+    },
+    withdrawals::{
+        state::LegAttemptError,
+        types::{WithdrawalId, WithdrawalLeg, WithdrawalLegKind, WithdrawalLegStatus},
+    },
 };
 
 use crate::STATE;
 
 const XRC_ATTACHED_CYCLES: u64 = 1_000_000_000u64;
-const XRC_QUOTE_ASSET: &str = "USD";
+const XRC_QUOTE_ASSET: &str = BASE_CURRENCY;
+const ARCHIVE_BATCH_SIZE: usize = 100;
+pub const PAY_INVOICE_MAX_ATTEMPTS: u32 = 3;
+const MAX_SCANNED_BLOCKS_PER_TOKEN: u64 = 1_000;
+const RECONCILED_BALANCES_BATCH_SIZE: usize = 50;
 
 pub fn set_immediate(func: impl FnOnce() + 'static) {
     set_timer(Duration::ZERO, func);
 }
 
 pub async fn fetch_exchange_rates() -> Vec<ExchangeRate> {
-    let (should_mock, tickers) = STATE.with_borrow(|s| {
+    let (should_mock, tickers, currencies) = STATE.with_borrow(|s| {
         let should_mock = s.exchange_rates.should_mock();
         let tickers: Vec<_> = s
             .supported_tokens
@@ -47,16 +80,37 @@ pub async fn fetch_exchange_rates() -> Vec<ExchangeRate> {
             .map(|it| (it.ticker, it.xrc_ticker))
             .collect();
 
-        (should_mock, tickers)
+        (should_mock, tickers, s.shops.pricing_currencies())
     });
 
+    // tokens are queried by their XRC tickers, but the rates are stored under their own ones
+    let base_assets: Vec<_> = tickers
+        .into_iter()
+        .map(|(ticker, xrc_ticker)| {
+            let asset = Asset {
+                symbol: xrc_ticker.0.to_string(),
+                class: AssetClass::Cryptocurrency,
+            };
+
+            (ticker.0.to_string(), asset)
+        })
+        .chain(currencies.into_iter().map(|currency| {
+            let asset = Asset {
+                symbol: currency.clone(),
+                class: AssetClass::FiatCurrency,
+            };
+
+            (currency, asset)
+        }))
+        .collect();
+
     if should_mock {
-        return tickers
+        return base_assets
             .into_iter()
-            .map(|(ticker, _xrc_ticker)| ExchangeRate {
+            .map(|(symbol, base_asset)| ExchangeRate {
                 base_asset: Asset {
-                    symbol: ticker.0.to_string(),
-                    class: AssetClass::Cryptocurrency,
+                    symbol,
+                    class: base_asset.class,
                 },
                 quote_asset: Asset {
                     symbol: XRC_QUOTE_ASSET.to_string(),
@@ -81,12 +135,9 @@ pub async fn fetch_exchange_rates() -> Vec<ExchangeRate> {
 
     let mut results = Vec::new();
 
-    for (ticker, xrc_ticker) in tickers {
+    for (base_symbol, base_asset) in base_assets {
         let args = GetExchangeRateRequest {
-            base_asset: Asset {
-                symbol: xrc_ticker.0.to_string(),
-                class: AssetClass::Cryptocurrency,
-            },
+            base_asset,
             quote_asset: Asset {
                 symbol: XRC_QUOTE_ASSET.to_string(),
                 class: AssetClass::FiatCurrency,
@@ -94,8 +145,6 @@ pub async fn fetch_exchange_rates() -> Vec<ExchangeRate> {
             timestamp: None,
         };
 
-        let base_symbol = ticker.0.to_string();
-
         let res = call_with_payment::<(GetExchangeRateRequest,), (GetExchangeRateResult,)>(
             xrc_id,
             "get_exchange_rate",
@@ -142,10 +191,71 @@ impl ICRC1CanisterClient {
         call(self.canister_id, "icrc1_transfer", (arg,)).await
     }
 
+    pub async fn icrc2_transfer_from(
+        &self,
+        arg: TransferFromArgs,
+    ) -> CallResult<(Result<BlockIndex, TransferFromError>,)> {
+        call(self.canister_id, "icrc2_transfer_from", (arg,)).await
+    }
+
+    pub async fn icrc1_balance_of(&self, account: Account) -> CallResult<(Nat,)> {
+        call(self.canister_id, "icrc1_balance_of", (account,)).await
+    }
+
     pub async fn icrc3_get_blocks(&self, arg: GetBlocksRequest) -> CallResult<(GetBlocksResult,)> {
         call(self.canister_id, "icrc3_get_blocks", (vec![arg],)).await
     }
 
+    /**
+     * Fetches up to `length` blocks starting from `start`, following the archive callbacks for blocks which were
+     * already moved out of the ledger. Returns blocks sorted by index, along with the current log length.
+     */
+    pub async fn get_blocks(
+        &self,
+        start: u64,
+        length: u64,
+    ) -> Result<(Vec<BlockWithId>, u64), String> {
+        let (get_blocks_result,) = self
+            .icrc3_get_blocks(GetBlocksRequest {
+                start: Nat::from(start),
+                length: Nat::from(length),
+            })
+            .await
+            .map_err(|e| {
+                format!(
+                    "Unable to fetch ICRC3 blocks of token {}: [{:?}] {}",
+                    self.canister_id, e.0, e.1
+                )
+            })?;
+
+        let mut blocks = Vec::new();
+
+        for archive in get_blocks_result.archived_blocks {
+            let (archived_result,): (GetBlocksResult,) = call(
+                archive.callback.canister_id,
+                &archive.callback.method,
+                (archive.args,),
+            )
+            .await
+            .map_err(|e| {
+                format!(
+                    "Unable to fetch archived ICRC3 blocks of token {}: [{:?}] {}",
+                    self.canister_id, e.0, e.1
+                )
+            })?;
+
+            blocks.extend(archived_result.blocks);
+        }
+
+        blocks.extend(get_blocks_result.blocks);
+        blocks.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let log_length = u64::try_from(&get_blocks_result.log_length.0)
+            .map_err(|_| "Invalid log length from an ICRC-3 ledger".to_string())?;
+
+        Ok((blocks, log_length))
+    }
+
     pub async fn find_block(&self, idx: Nat) -> Result<BlockWithId, String> {
         let (mut get_blocks_result,) = self
             .icrc3_get_blocks(GetBlocksRequest {
@@ -200,6 +310,192 @@ impl ICRC1CanisterClient {
     }
 }
 
+pub fn is_icp_ledger(token_id: &TokenId) -> bool {
+    *token_id
+        == Principal::from_text(ICP_LEDGER_CANISTER_ID).expect("Invalid ICP ledger canister id")
+}
+
+pub fn shop_account(shop_id: ShopId) -> Account {
+    Account {
+        owner: id(),
+        subaccount: Some(calc_shop_subaccount(shop_id)),
+    }
+}
+
+/**
+ * The account the invoice should be paid to - either its own deposit subaccount or the shop one
+ */
+pub fn invoice_account(invoice: &Invoice) -> Account {
+    match invoice.deposit_subaccount {
+        Some(subaccount) => Account {
+            owner: id(),
+            subaccount: Some(subaccount),
+        },
+        None => shop_account(invoice.shop_id),
+    }
+}
+
+/**
+ * The ICP ledger predates ICRC-3 - its blocks are served by `query_blocks` and only refer to account identifiers
+ */
+#[derive(Clone, Copy)]
+pub struct IcpLedgerCanisterClient {
+    pub canister_id: Principal,
+}
+
+impl IcpLedgerCanisterClient {
+    pub fn new(canister_id: Principal) -> Self {
+        Self { canister_id }
+    }
+
+    pub async fn query_blocks(&self, arg: GetBlocksArgs) -> CallResult<(QueryBlocksResponse,)> {
+        call(self.canister_id, "query_blocks", (arg,)).await
+    }
+
+    pub async fn transfer(&self, arg: IcpTransferArgs) -> CallResult<(IcpTransferResult,)> {
+        call(self.canister_id, "transfer", (arg,)).await
+    }
+
+    /**
+     * Fetches up to `length` blocks starting from `start`, following the archive callbacks.
+     * Returns blocks with their indices, sorted, along with the current chain length.
+     */
+    pub async fn get_blocks(
+        &self,
+        start: u64,
+        length: u64,
+    ) -> Result<(Vec<(u64, IcpBlock)>, u64), String> {
+        let (response,) = self
+            .query_blocks(GetBlocksArgs { start, length })
+            .await
+            .map_err(|e| {
+                format!(
+                    "Unable to fetch blocks of ICP ledger {}: [{:?}] {}",
+                    self.canister_id, e.0, e.1
+                )
+            })?;
+
+        let mut blocks = Vec::new();
+
+        for archive in response.archived_blocks {
+            let (archived_result,): (QueryArchiveResult,) = call(
+                archive.callback.canister_id,
+                &archive.callback.method,
+                (GetBlocksArgs {
+                    start: archive.start,
+                    length: archive.length,
+                },),
+            )
+            .await
+            .map_err(|e| {
+                format!(
+                    "Unable to fetch archived blocks of ICP ledger {}: [{:?}] {}",
+                    self.canister_id, e.0, e.1
+                )
+            })?;
+
+            let archived_range = archived_result.map_err(|e| {
+                format!(
+                    "Unable to fetch archived blocks of ICP ledger {}: {:?}",
+                    self.canister_id, e
+                )
+            })?;
+
+            blocks.extend((archive.start..).zip(archived_range.blocks));
+        }
+
+        blocks.extend((response.first_block_index..).zip(response.blocks));
+        blocks.sort_by_key(|(idx, _)| *idx);
+
+        Ok((blocks, response.chain_length))
+    }
+
+    pub async fn find_block(&self, idx: u64) -> Result<IcpBlock, String> {
+        let (blocks, chain_length) = self.get_blocks(idx, 1).await?;
+
+        match blocks.into_iter().next() {
+            Some((block_idx, block)) if block_idx == idx => Ok(block),
+            _ => Err(format!(
+                "Block {} does not exist (total block len {})",
+                idx, chain_length
+            )),
+        }
+    }
+}
+
+/**
+ * Legacy ICP blocks can't be reversed into accounts, so the block is checked against the account of the invoice
+ * it is expected to pay for, and is converted into a transfer to that account, carrying the full invoice memo
+ */
+pub fn icp_block_to_transfer_txn(
+    block: &IcpBlock,
+    token_id: Principal,
+    token_decimals: u8,
+    invoice: &Invoice,
+) -> Result<TransferTxn, String> {
+    let (to, amount) = match &block.transaction.operation {
+        Some(Operation::Transfer { to, amount, .. }) => (to, amount),
+        _ => return Err("Invalid txn type".to_string()),
+    };
+
+    let expected_to = invoice_account(invoice);
+    let expected_to_account_identifier = AccountIdentifier::from(&expected_to);
+
+    if *to != expected_to_account_identifier {
+        return Err(format!(
+            "Invalid recepient account identifier: expected {}, actual {}",
+            expected_to_account_identifier.to_hex(),
+            to.to_hex()
+        ));
+    }
+
+    let invoice_memo = InvoicesState::make_invoice_memo(&invoice.id);
+
+    let memo_matches = match &block.transaction.icrc1_memo {
+        _ if invoice.deposit_subaccount.is_some() => true,
+        Some(memo) => memo.as_slice() == invoice_memo,
+        None => block.transaction.memo == InvoicesState::make_legacy_invoice_memo(&invoice.id),
+    };
+
+    if !memo_matches {
+        return Err("Txn memo field doesn't match the invoice one".to_string());
+    }
+
+    Ok(TransferTxn {
+        from: None,
+        to: expected_to,
+        qty: EDs::new(BigUint::from(amount.e8s), token_decimals),
+        token_id,
+        memo: Some(invoice_memo),
+    })
+}
+
+#[derive(Clone, Copy)]
+pub struct InvoiceHistoryCanisterClient {
+    pub canister_id: Principal,
+}
+
+impl InvoiceHistoryCanisterClient {
+    pub fn new(canister_id: Principal) -> Self {
+        Self { canister_id }
+    }
+
+    pub async fn push_batch(&self, arg: PushBatchRequest) -> CallResult<(PushBatchResponse,)> {
+        call(self.canister_id, "push_batch", (arg,)).await
+    }
+
+    pub async fn set_next(&self, arg: SetNextRequest) -> CallResult<(SetNextResponse,)> {
+        call(self.canister_id, "set_next", (arg,)).await
+    }
+
+    pub async fn get_invoice_by_id(
+        &self,
+        arg: GetInvoiceByIdRequest,
+    ) -> CallResult<(GetInvoiceByIdResponse,)> {
+        call(self.canister_id, "get_invoice_by_id", (arg,)).await
+    }
+}
+
 pub fn icrc3_block_to_transfer_txn(
     block: &BlockWithId,
     token_id: Principal,
@@ -262,8 +558,9 @@ pub fn icrc3_block_to_transfer_txn(
                                 _ => return Err("Invalid 'to_subaccount' field".to_string()),
                             };
 
-                            let mut to_subaccount = [0u8; 32];
-                            to_subaccount.copy_from_slice(&to_subaccount_slice);
+                            let to_subaccount: [u8; 32] = to_subaccount_slice
+                                .try_into()
+                                .map_err(|_| "Invalid 'to_subaccount' field".to_string())?;
 
                             Account {
                                 owner: to_owner,
@@ -282,17 +579,20 @@ pub fn icrc3_block_to_transfer_txn(
                             let from_owner_val = from_arr
                                 .get(0)
                                 .ok_or("No sender principal found in the block".to_string())?;
-                            let from_subaccount = from_arr.get(1).map(|it| {
-                                let from_subaccount_slice = match it {
-                                    ICRC3Value::Blob(b) => b.as_slice(),
-                                    _ => unreachable!("Invalid 'from_subaccount' field"),
-                                };
+                            let from_subaccount = match from_arr.get(1) {
+                                Some(ICRC3Value::Blob(b)) => {
+                                    let from_subaccount: [u8; 32] =
+                                        b.as_slice().try_into().map_err(|_| {
+                                            "Invalid 'from_subaccount' field".to_string()
+                                        })?;
 
-                                let mut from_subaccount = [0u8; 32];
-                                from_subaccount.copy_from_slice(&from_subaccount_slice);
-
-                                from_subaccount
-                            });
+                                    Some(from_subaccount)
+                                }
+                                Some(_) => {
+                                    return Err("Invalid 'from_subaccount' field".to_string())
+                                }
+                                None => None,
+                            };
 
                             let from_owner = match from_owner_val {
                                 ICRC3Value::Blob(b) => Principal::from_slice(b.as_slice()),
@@ -307,22 +607,15 @@ pub fn icrc3_block_to_transfer_txn(
                         _ => return Err("Invalid 'to' field".to_string()),
                     };
 
-                    let memo_val = tx_fields
-                        .get("memo")
-                        .ok_or("The block contains no 'memo' field".to_string())?;
-
-                    let memo = match memo_val {
-                        ICRC3Value::Blob(b) => {
-                            let mut res = [0u8; 32];
-                            res.copy_from_slice(b.as_slice());
-
-                            res
-                        }
-                        _ => return Err("Invalid 'memo' field".to_string()),
+                    // wallets, which can't set a memo, still pay to deposit subaccounts - these identify the invoice by themselves
+                    let memo = match tx_fields.get("memo") {
+                        Some(ICRC3Value::Blob(b)) => <[u8; 32]>::try_from(b.as_slice()).ok(),
+                        Some(_) => return Err("Invalid 'memo' field".to_string()),
+                        None => None,
                     };
 
                     Ok(TransferTxn {
-                        from,
+                        from: Some(from),
                         to,
                         qty: EDs::new(amount.0.clone(), token_decimals),
                         token_id,
@@ -344,13 +637,145 @@ pub fn init_supported_tokens(tokens: Vec<Token>) {
     });
 }
 
+/**
+ * Moves paid invoices to the current invoice history canister in batches, until there is nothing left to archive.
+ * If the history canister can't be reached, the batch is put back and will be retried on the next run.
+ * Once the current history canister is full, a new one is spawned and linked to it.
+ */
 pub async fn archive_inactive_invoices() {
-    let batch = STATE.with_borrow_mut(|s| s.invoices.prepare_archive_batch(100));
+    loop {
+        let batch = STATE.with_borrow_mut(|s| s.prepare_archive_batch(ARCHIVE_BATCH_SIZE, time()));
+
+        if batch.is_empty() {
+            break;
+        }
+
+        // only paid, refunded, expired or cancelled invoices are inactive, so this should never fail -
+        // but if it does, the invoice is set aside, so it doesn't block archiving of the rest
+        let (batch, archived_batch): (Vec<_>, Vec<_>) = batch
+            .into_iter()
+            .filter_map(|invoice| match ArchivedInvoice::try_from(invoice.clone()) {
+                Ok(archived) => Some((invoice, archived)),
+                Err(e) => {
+                    ic_cdk::println!("Unable to archive invoice {:?}: {}", invoice.id, e);
+                    STATE.with_borrow_mut(|s| s.invoices.set_aside_unarchivable(invoice));
+
+                    None
+                }
+            })
+            .unzip();
+
+        if batch.is_empty() {
+            continue;
+        }
 
-    // TODO: make external call
-    // TODO: if failed - reapply batch
+        let archive_id = STATE.with_borrow(|s| s.invoice_archives.current().canister_id);
+        let invoice_history = InvoiceHistoryCanisterClient::new(archive_id);
 
-    STATE.with_borrow_mut(|s| s.invoices.reapply_archive_batch(batch));
+        let result = invoice_history
+            .push_batch(PushBatchRequest {
+                batch: archived_batch,
+            })
+            .await;
+
+        match result {
+            Ok((resp,)) => {
+                let should_spawn_next = STATE.with_borrow_mut(|s| {
+                    s.invoice_archives
+                        .update_stats(archive_id, resp.len, resp.memory_size_bytes);
+
+                    s.invoice_archives.should_spawn_next()
+                });
+
+                if should_spawn_next {
+                    if let Err(e) = spawn_next_invoice_archive().await {
+                        ic_cdk::println!("Unable to spawn a new invoice archive: {}", e);
+                    }
+                }
+            }
+            Err(_) => {
+                STATE.with_borrow_mut(|s| s.invoices.reapply_archive_batch(batch));
+                break;
+            }
+        }
+    }
+}
+
+/**
+ * Creates a new invoice history canister, installs the uploaded archive wasm into it and links it to
+ * the current archive, so lookups could follow the chain. Since then all new invoices go to the new archive.
+ */
+pub async fn spawn_next_invoice_archive() -> Result<Principal, String> {
+    let (prev, config, wasm) = STATE.with_borrow_mut(|s| {
+        let wasm = s
+            .invoice_archives
+            .archive_wasm
+            .clone()
+            .ok_or("Invoice archive wasm is not uploaded yet".to_string())?;
+
+        s.invoice_archives.is_spawning = true;
+
+        Ok::<_, String>((
+            s.invoice_archives.current(),
+            s.invoice_archives.config,
+            wasm,
+        ))
+    })?;
+
+    let result = async {
+        let (record,) = create_canister(
+            CreateCanisterArgument {
+                settings: Some(CanisterSettings {
+                    controllers: Some(vec![id()]),
+                    compute_allocation: None,
+                    memory_allocation: None,
+                    freezing_threshold: None,
+                    reserved_cycles_limit: None,
+                }),
+            },
+            config.cycles_per_archive,
+        )
+        .await
+        .map_err(|(code, msg)| format!("Unable to create a canister: [{:?}] {}", code, msg))?;
+
+        let next = record.canister_id;
+
+        let arg = encode_args((Some(InitArgs {
+            start_idx: prev.end_idx(),
+        }),))
+        .map_err(|e| format!("Unable to encode init args: {}", e))?;
+
+        install_code(InstallCodeArgument {
+            mode: CanisterInstallMode::Install,
+            canister_id: next,
+            wasm_module: wasm,
+            arg,
+        })
+        .await
+        .map_err(|(code, msg)| {
+            format!("Unable to install the archive code: [{:?}] {}", code, msg)
+        })?;
+
+        InvoiceHistoryCanisterClient::new(prev.canister_id)
+            .set_next(SetNextRequest { next })
+            .await
+            .map_err(|(code, msg)| {
+                format!("Unable to link the new archive: [{:?}] {}", code, msg)
+            })?;
+
+        Ok(next)
+    }
+    .await;
+
+    STATE.with_borrow_mut(|s| {
+        if let Ok(next) = result {
+            s.invoice_archives.register_next(next);
+        }
+
+        s.invoice_archives.is_spawning = false;
+    });
+
+    result
 }
 
 /**
@@ -367,12 +792,15 @@ pub async fn refresh_exchange_rates() {
 }
 
 pub fn get_current_exchange_rate_timestamp() -> Timestamp {
-    STATE.with_borrow(|it| it.exchange_rates.last_updated_at)
+    STATE.with_borrow(|it| it.exchange_rates.last_updated_at())
 }
 
 #[inline]
 pub fn garbage_collect_invoices() {
-    STATE.with_borrow_mut(|s| s.purge_expired_invoices());
+    STATE.with_borrow_mut(|s| {
+        s.purge_expired_invoices(id(), time());
+        s.invoices.purge_expired_idempotency_keys(time());
+    });
 }
 
 #[inline]
@@ -381,3 +809,615 @@ pub async fn init_invoice_ids_seed() {
 
     STATE.with_borrow_mut(|it| it.invoices.init_id_seed(&rand));
 }
+
+pub async fn process_withdrawal(id: WithdrawalId) {
+    let withdrawal = match STATE.with_borrow(|s| s.withdrawals.get(&id)) {
+        Some(it) => it,
+        None => return,
+    };
+
+    let token = ICRC1CanisterClient::new(withdrawal.token_id);
+    let icp_ledger = IcpLedgerCanisterClient::new(withdrawal.token_id);
+    let shop_subaccount = calc_shop_subaccount(withdrawal.shop_id);
+
+    let attempts = withdrawal
+        .legs
+        .iter()
+        .enumerate()
+        .filter(|(_, leg)| leg.is_pending())
+        .map(|(idx, leg)| {
+            let token = &token;
+            let icp_ledger = &icp_ledger;
+            let withdrawal = &withdrawal;
+
+            async move {
+                // the arguments never change, so a retry of a transfer which already went through is reported as a duplicate
+                let result = match leg.icp_transfer_arg(shop_subaccount) {
+                    Some(arg) => match icp_ledger.transfer(arg).await {
+                        Ok((Ok(block_idx),)) => Ok(Nat::from(block_idx)),
+                        Ok((Err(IcpTransferError::TxDuplicate { duplicate_of }),)) => {
+                            Ok(Nat::from(duplicate_of))
+                        }
+                        Ok((Err(err),)) => Err(LegAttemptError::Fatal(format!("{:?}", err))),
+                        Err((code, msg)) => Err(LegAttemptError::Retryable(format!(
+                            "Unable to make an ICP transfer call to {}: [{:?}] {}",
+                            withdrawal.token_id, code, msg
+                        ))),
+                    },
+                    None => match token
+                        .icrc1_transfer(leg.transfer_arg(shop_subaccount))
+                        .await
+                    {
+                        Ok((Ok(block_idx),)) => Ok(block_idx),
+                        Ok((Err(TransferError::Duplicate { duplicate_of }),)) => Ok(duplicate_of),
+                        Ok((Err(
+                            err @ (TransferError::TemporarilyUnavailable
+                            | TransferError::GenericError { .. }),
+                        ),)) => Err(LegAttemptError::Retryable(err.to_string())),
+                        Ok((Err(err),)) => Err(LegAttemptError::Fatal(err.to_string())),
+                        Err((code, msg)) => Err(LegAttemptError::Retryable(format!(
+                            "Unable to make an ICRC-1 transfer call to {}: [{:?}] {}",
+                            withdrawal.token_id, code, msg
+                        ))),
+                    },
+                };
+
+                STATE.with_borrow_mut(|s| {
+                    let finished_leg = s.withdrawals.record_leg_attempt(id, idx, result);
+
+                    // the outcome of a refund is recorded on its invoice, unless the invoice is archived already
+                    if let (Some(leg), Some(invoice_id)) = (&finished_leg, &withdrawal.invoice_id) {
+                        match (leg.kind, &leg.status) {
+                            (
+                                WithdrawalLegKind::OverpaymentRefund,
+                                WithdrawalLegStatus::Completed { block_idx },
+                            ) => {
+                                s.invoices.set_overpayment_status(
+                                    invoice_id,
+                                    OverpaymentStatus::Refunded {
+                                        block_idx: block_idx.clone(),
+                                    },
+                                );
+                            }
+                            (
+                                WithdrawalLegKind::OverpaymentRefund,
+                                WithdrawalLegStatus::Failed { reason },
+                            ) => {
+                                s.invoices.set_overpayment_status(
+                                    invoice_id,
+                                    OverpaymentStatus::RefundFailed {
+                                        reason: reason.clone(),
+                                    },
+                                );
+                            }
+                            (
+                                WithdrawalLegKind::Refund,
+                                WithdrawalLegStatus::Completed { block_idx },
+                            ) => {
+                                s.complete_invoice_refund(
+                                    &withdrawal.shop_id,
+                                    invoice_id,
+                                    withdrawal.id,
+                                    InvoiceRefundStatus::Completed {
+                                        block_idx: block_idx.clone(),
+                                    },
+                                );
+                            }
+                            (WithdrawalLegKind::Refund, WithdrawalLegStatus::Failed { reason }) => {
+                                s.complete_invoice_refund(
+                                    &withdrawal.shop_id,
+                                    invoice_id,
+                                    withdrawal.id,
+                                    InvoiceRefundStatus::Failed {
+                                        reason: reason.clone(),
+                                    },
+                                );
+                            }
+                            _ => {}
+                        }
+                    }
+
+                    match finished_leg {
+                        Some(WithdrawalLeg {
+                            kind: WithdrawalLegKind::ReferralFee,
+                            status: WithdrawalLegStatus::Completed { .. },
+                            to,
+                            qty,
+                            ..
+                        }) => {
+                            s.shops.add_referral_earnings(
+                                to.owner,
+                                withdrawal.shop_id,
+                                qty.to_decimals(8).to_const(),
+                            );
+                        }
+                        // the funds of a failed leg are still in the shop's subaccount
+                        Some(WithdrawalLeg {
+                            status: WithdrawalLegStatus::Failed { .. },
+                            qty,
+                            ..
+                        }) => {
+                            s.balances
+                                .credit(withdrawal.shop_id, withdrawal.token_id, &qty);
+                        }
+                        _ => {}
+                    }
+                });
+            }
+        });
+
+    join_all(attempts).await;
+}
+
+pub async fn retry_pending_withdrawals() {
+    let ids = STATE.with_borrow(|s| s.withdrawals.pending_ids());
+
+    for id in ids {
+        process_withdrawal(id).await;
+    }
+}
+
+pub async fn reconcile_shop_balances() {
+    let (shop_ids, tokens) = STATE.with_borrow(|s| {
+        let shop_ids = s.shops.shops.iter().map(|(id, _)| id).collect::<Vec<_>>();
+        let tokens = s.supported_tokens.get().cloned().collect::<Vec<_>>();
+
+        (shop_ids, tokens)
+    });
+
+    let pairs = tokens
+        .iter()
+        .flat_map(|token| shop_ids.iter().map(move |shop_id| (*shop_id, token)))
+        .collect::<Vec<_>>();
+
+    // balances are fetched in batches of concurrent calls, so the number of outstanding calls stays bounded
+    for batch in pairs.chunks(RECONCILED_BALANCES_BATCH_SIZE) {
+        join_all(
+            batch
+                .iter()
+                .map(|(shop_id, token)| reconcile_shop_balance(*shop_id, token)),
+        )
+        .await;
+    }
+}
+
+async fn reconcile_shop_balance(shop_id: ShopId, token: &Token) {
+    let client = ICRC1CanisterClient::new(token.id);
+    let account = Account {
+        owner: id(),
+        subaccount: Some(calc_shop_subaccount(shop_id)),
+    };
+
+    let actual_balance = match client.icrc1_balance_of(account).await {
+        Ok((balance,)) => EDs::new(balance.0, token.fee.decimals),
+        Err((code, msg)) => {
+            ic_cdk::println!(
+                "Unable to fetch the balance of shop {} in {}: [{:?}] {}",
+                shop_id,
+                token.id,
+                code,
+                msg
+            );
+            return;
+        }
+    };
+
+    STATE.with_borrow_mut(|s| {
+        let internal_balance = s.balances.get(shop_id, token.id, token.fee.decimals);
+
+        // pending withdrawal and refund legs are debited from the balance before they are transferred
+        let in_flight = s
+            .withdrawals
+            .pending_qty(shop_id, token.id, token.fee.decimals);
+
+        if &internal_balance + &in_flight == actual_balance {
+            s.balances.clear_discrepancy(shop_id, token.id);
+            return;
+        }
+
+        ic_cdk::println!(
+            "Balance discrepancy of shop {} in {}: internal {}, actual {}",
+            shop_id,
+            token.id,
+            internal_balance,
+            actual_balance
+        );
+
+        s.balances.report_discrepancy(BalanceDiscrepancy {
+            shop_id,
+            token_id: token.id,
+            internal_balance,
+            actual_balance,
+            checked_at: time(),
+        });
+    });
+}
+
+/**
+ * Scans new blocks of each supported token and settles the active invoices, which incoming transfers pay for,
+ * so payers don't have to submit block indices themselves. Transfers are matched by the invoice memo.
+ */
+pub async fn scan_payment_blocks() {
+    // a scan may take longer than the timer interval - overlapping scans would fetch and settle the same blocks twice
+    let tokens_opt = STATE.with_borrow_mut(|s| {
+        if s.block_scanner.is_scanning {
+            return None;
+        }
+
+        s.block_scanner.is_scanning = true;
+
+        Some(s.supported_tokens.get().cloned().collect::<Vec<_>>())
+    });
+
+    let tokens = match tokens_opt {
+        Some(it) => it,
+        None => return,
+    };
+
+    for token in tokens {
+        let result = if is_icp_ledger(&token.id) {
+            scan_icp_blocks(&token).await
+        } else {
+            scan_token_blocks(&token).await
+        };
+
+        if let Err(e) = result {
+            ic_cdk::println!("Unable to scan blocks of {}: {}", token.id, e);
+        }
+    }
+
+    STATE.with_borrow_mut(|s| s.block_scanner.is_scanning = false);
+}
+
+async fn scan_token_blocks(token: &Token) -> Result<(), String> {
+    let client = ICRC1CanisterClient::new(token.id);
+    let cursor_opt = STATE.with_borrow(|s| s.block_scanner.get_cursor(&token.id));
+
+    let (blocks, log_length) = client
+        .get_blocks(cursor_opt.unwrap_or_default(), MAX_SCANNED_BLOCKS_PER_TOKEN)
+        .await?;
+
+    // a newly supported token is only scanned from its current tip, older transfers can't pay for any invoice
+    let mut next_block_idx = match cursor_opt {
+        Some(it) => it,
+        None => {
+            STATE.with_borrow_mut(|s| s.block_scanner.set_cursor(token.id, log_length));
+            return Ok(());
+        }
+    };
+
+    let this_canister_id = id();
+
+    STATE.with_borrow_mut(|s| {
+        for block in blocks {
+            // only move the cursor through a contiguous range of blocks, the rest is fetched next time
+            if block.id != next_block_idx {
+                break;
+            }
+            next_block_idx += 1;
+
+            // non-transfer blocks (mints, burns, approvals) are skipped
+            let txn = match icrc3_block_to_transfer_txn(&block, token.id, token.fee.decimals) {
+                Ok(it) => it,
+                Err(_) => continue,
+            };
+
+            if txn.to.owner != this_canister_id {
+                continue;
+            }
+
+            let invoice_id_opt = txn
+                .to
+                .subaccount
+                .and_then(|it| s.invoices.get_active_by_deposit_address(&it))
+                .or_else(|| {
+                    txn.memo
+                        .and_then(|memo| s.invoices.get_active_by_memo(&memo))
+                });
+
+            if let Some(invoice_id) = invoice_id_opt {
+                settle_scanned_payment(s, &invoice_id, token, block.id, |_| Ok(txn));
+            }
+        }
+
+        s.block_scanner.set_cursor(token.id, next_block_idx);
+    });
+
+    Ok(())
+}
+
+async fn scan_icp_blocks(token: &Token) -> Result<(), String> {
+    let client = IcpLedgerCanisterClient::new(token.id);
+    let cursor_opt = STATE.with_borrow(|s| s.block_scanner.get_cursor(&token.id));
+
+    let (blocks, chain_length) = client
+        .get_blocks(cursor_opt.unwrap_or_default(), MAX_SCANNED_BLOCKS_PER_TOKEN)
+        .await?;
+
+    let mut next_block_idx = match cursor_opt {
+        Some(it) => it,
+        None => {
+            STATE.with_borrow_mut(|s| s.block_scanner.set_cursor(token.id, chain_length));
+            return Ok(());
+        }
+    };
+
+    STATE.with_borrow_mut(|s| {
+        for (block_idx, block) in blocks {
+            if block_idx != next_block_idx {
+                break;
+            }
+            next_block_idx += 1;
+
+            let to = match block.transaction.operation {
+                Some(Operation::Transfer { to, .. }) => to,
+                _ => continue,
+            };
+
+            // ICRC-1 transfers to account identifiers keep their full memo, legacy ones only have a u64 memo
+            let invoice_id_opt = s.invoices.get_active_by_deposit_address(&to.0).or_else(|| {
+                match block.transaction.icrc1_memo {
+                    Some(ref memo) => <[u8; 32]>::try_from(memo.as_slice())
+                        .ok()
+                        .and_then(|it| s.invoices.get_active_by_memo(&it)),
+                    None => s.invoices.get_active_by_legacy_memo(block.transaction.memo),
+                }
+            });
+
+            if let Some(invoice_id) = invoice_id_opt {
+                settle_scanned_payment(s, &invoice_id, token, Nat::from(block_idx), |invoice| {
+                    icp_block_to_transfer_txn(&block, token.id, token.fee.decimals, invoice)
+                });
+            }
+        }
+
+        s.block_scanner.set_cursor(token.id, next_block_idx);
+    });
+
+    Ok(())
+}
+
+fn settle_scanned_payment(
+    s: &mut State,
+    invoice_id: &InvoiceId,
+    token: &Token,
+    block_idx: Nat,
+    make_txn: impl FnOnce(&Invoice) -> Result<TransferTxn, String>,
+) {
+    let invoice = match s.invoices.get(invoice_id) {
+        Some(it) => it,
+        None => return,
+    };
+
+    // invoices in VerifyPayment status are being verified by their creators right now
+    let prev_status = match invoice.status {
+        InvoiceStatus::Created { .. } | InvoiceStatus::PartiallyPaid => invoice.status.clone(),
+        _ => return,
+    };
+
+    let txn = match make_txn(&invoice) {
+        Ok(it) => it,
+        Err(_) => return,
+    };
+
+    s.invoices
+        .set_status(invoice_id, InvoiceStatus::VerifyPayment);
+
+    match s.settle_invoice_payment(
+        invoice_id,
+        txn,
+        block_idx.clone(),
+        prev_status,
+        id(),
+        time(),
+    ) {
+        Ok(_) => ic_cdk::println!(
+            "Block {} of {} is applied to invoice {:?}",
+            block_idx,
+            token.id,
+            invoice_id
+        ),
+        Err(e) => ic_cdk::println!(
+            "Block {} of {} can't be applied to invoice {:?}: {}",
+            block_idx,
+            token.id,
+            invoice_id,
+            e
+        ),
+    }
+}
+
+/**
+ * Moves the funds of invoice payments from their deposit subaccounts into the shop subaccounts. The whole balance of a
+ * deposit subaccount is swept each time, so repeating a sweep which already went through is harmless.
+ */
+pub async fn sweep_deposit_subaccounts() {
+    let sweeps = STATE.with_borrow(|s| s.invoices.pending_deposit_sweeps());
+
+    for (invoice_id, sweep) in sweeps {
+        if let Err(e) = sweep_deposit_subaccount(&invoice_id, &sweep).await {
+            ic_cdk::println!(
+                "Unable to sweep the deposit subaccount of invoice {:?}: {}",
+                invoice_id,
+                e
+            );
+        }
+    }
+}
+
+async fn sweep_deposit_subaccount(
+    invoice_id: &InvoiceId,
+    sweep: &DepositSweep,
+) -> Result<(), String> {
+    let fee = STATE
+        .with_borrow(|s| {
+            s.supported_tokens
+                .get_by_id(&sweep.token_id)
+                .map(|it| it.fee.clone())
+        })
+        .ok_or(format!("Token {} is not supported", sweep.token_id))?;
+
+    let token = ICRC1CanisterClient::new(sweep.token_id);
+    let deposit_account = Account {
+        owner: id(),
+        subaccount: Some(sweep.deposit_subaccount),
+    };
+
+    let (balance,) = token
+        .icrc1_balance_of(deposit_account)
+        .await
+        .map_err(|(code, msg)| format!("Unable to fetch the balance: [{:?}] {}", code, msg))?;
+    let balance = EDs::new(balance.0, fee.decimals);
+
+    if balance > fee {
+        let (result,) = token
+            .icrc1_transfer(TransferArg {
+                from_subaccount: Some(sweep.deposit_subaccount),
+                to: shop_account(sweep.shop_id),
+                fee: Some((&fee).into()),
+                memo: Some(Memo::from(
+                    InvoicesState::make_invoice_memo(invoice_id).to_vec(),
+                )),
+                created_at_time: None,
+                amount: (&balance - &fee).into(),
+            })
+            .await
+            .map_err(|(code, msg)| {
+                format!(
+                    "Unable to make an ICRC-1 transfer call: [{:?}] {}",
+                    code, msg
+                )
+            })?;
+
+        result.map_err(|e| e.to_string())?;
+    }
+
+    STATE.with_borrow_mut(|s| s.complete_deposit_sweep(invoice_id, sweep, &balance, &fee));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use candid::{Nat, Principal};
+    use ic_e8s::{c::E8s, d::EDs};
+    use ic_stable_structures::{memory_manager::MemoryManager, DefaultMemoryImpl};
+    use icrc_ledger_types::{icrc::generic_value::ICRC3Value, icrc3::blocks::BlockWithId};
+    use msq_pay_types::{InvoiceStatus, RateLockPolicy, TokenPrice, UnderpaymentTolerance};
+    use shared::{
+        exchange_rates::types::Ticker, invoices::types::NewInvoice, payment_hub::state::State,
+        supported_tokens::types::Token,
+    };
+
+    use super::icrc3_block_to_transfer_txn;
+
+    fn account_value(owner: Principal, subaccount: [u8; 32]) -> ICRC3Value {
+        ICRC3Value::Array(vec![
+            ICRC3Value::Blob(owner.as_slice().to_vec().into()),
+            ICRC3Value::Blob(subaccount.to_vec().into()),
+        ])
+    }
+
+    #[test]
+    fn memoless_transfer_pays_a_deposit_invoice() {
+        let mut state = State::init(&MemoryManager::init(DefaultMemoryImpl::default()));
+
+        let this_canister_id = Principal::management_canister();
+        let owner = Principal::from_slice(&[1]);
+        let payer = Principal::from_slice(&[2]);
+        let token_id = Principal::from_slice(&[3]);
+
+        state.supported_tokens.add_token(Token {
+            id: token_id,
+            ticker: Ticker::from("ckBTC"),
+            xrc_ticker: Ticker::from("BTC"),
+            fee: EDs::new(10u64.into(), 8),
+            logo_src: String::new(),
+        });
+
+        let shop_id = state.shops.create_shop(
+            BTreeSet::from([owner]),
+            String::from("Shop"),
+            String::new(),
+            String::new(),
+            None,
+            owner,
+        );
+
+        let invoice_id = state
+            .invoices
+            .create(
+                NewInvoice {
+                    shop_id,
+                    creator: owner,
+                    qty_usd: E8s::from(1_0000_0000u64),
+                    token_price: Some(TokenPrice {
+                        token_id,
+                        qty: EDs::new(1_0000u64.into(), 8),
+                    }),
+                    fiat_price: None,
+                    exchange_rates_timestamp: 0,
+                    expires_at: 100,
+                    rate_lock_policy: RateLockPolicy::LockAtCreation,
+                    underpayment_tolerance: UnderpaymentTolerance::default(),
+                    metadata: None,
+                    idempotency_key: None,
+                    deposit_account_owner: Some(this_canister_id),
+                },
+                0,
+            )
+            .unwrap();
+        let invoice = state.invoices.get(&invoice_id).unwrap();
+        let deposit_subaccount = invoice.deposit_subaccount.unwrap();
+
+        // a plain ICRC-1 transfer from a wallet, with no memo at all
+        let block = BlockWithId {
+            id: Nat::from(7u64),
+            block: ICRC3Value::Map(BTreeMap::from([
+                (
+                    String::from("btype"),
+                    ICRC3Value::Text(String::from("1xfer")),
+                ),
+                (
+                    String::from("tx"),
+                    ICRC3Value::Map(BTreeMap::from([
+                        (String::from("amt"), ICRC3Value::Nat(Nat::from(1_0000u64))),
+                        (
+                            String::from("to"),
+                            account_value(this_canister_id, deposit_subaccount),
+                        ),
+                        (String::from("from"), account_value(payer, [0u8; 32])),
+                    ])),
+                ),
+            ])),
+        };
+
+        let txn = icrc3_block_to_transfer_txn(&block, token_id, 8).unwrap();
+        assert_eq!(txn.memo, None);
+        assert_eq!(
+            state
+                .invoices
+                .get_active_by_deposit_address(&txn.to.subaccount.unwrap()),
+            Some(invoice_id)
+        );
+
+        state
+            .invoices
+            .set_status(&invoice_id, InvoiceStatus::VerifyPayment);
+
+        let invoice = state
+            .settle_invoice_payment(
+                &invoice_id,
+                txn,
+                block.id,
+                invoice.status,
+                this_canister_id,
+                50,
+            )
+            .unwrap();
+
+        assert!(matches!(invoice.status, InvoiceStatus::Paid { .. }));
+    }
+}
@@ -1,24 +1,200 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
 use candid::{CandidType, Nat, Principal};
-use ic_cdk::call;
+use ic_cdk::{
+    api::call::{CallResult, RejectionCode},
+    call,
+};
 use ic_e8s::{c::E8s, d::EDs};
+use icrc_ledger_types::icrc1::account::Account;
 use serde::Deserialize;
 
 pub type InvoiceId = [u8; 32];
 
+pub const DEFAULT_UNDERPAYMENT_TOLERANCE_BPS: u16 = 100;
+
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub enum InvoiceStatus {
+    // `ttl` is the invoice lifetime in minutes, capped at 255 - see `Invoice::expires_at` for the exact expiry
     Created {
         ttl: u8,
     },
     VerifyPayment,
+    // some payments are applied, but they don't cover the invoice yet
+    PartiallyPaid,
+    // the payment fields are of the payment, which completed the invoice - see `Invoice::payments` for all of them
     Paid {
         timestamp: u64,
         token_id: Principal,
         qty: EDs,
         exchange_rate: EDs,
+        // not known for invoices paid before these were tracked
+        block_idx: Option<Nat>,
+        from: Option<Account>,
+    },
+    // the shop refunded some of the payments - see `Invoice::refunds`
+    PartiallyRefunded {
+        timestamp: u64,
+    },
+    // the shop refunded all of the payments
+    Refunded {
+        timestamp: u64,
+    },
+    // nothing was paid before the invoice expired - see `Invoice::late_payments` for transfers made afterwards
+    Expired {
+        timestamp: u64,
+    },
+    // the creator cancelled the invoice before anything was paid
+    Cancelled {
+        timestamp: u64,
     },
 }
 
+/**
+ * A single transfer applied to an invoice
+ */
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InvoicePayment {
+    pub token_id: Principal,
+    pub block_idx: Nat,
+    // not known for legacy ICP transfers, which only refer account identifiers
+    pub from: Option<Account>,
+    pub qty: EDs,
+    pub qty_usd: E8s,
+    pub exchange_rate: EDs,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum OverpaymentStatus {
+    // the shop keeps the excess as a tip
+    Kept,
+    // the refund is sent with this withdrawal of the shop
+    RefundPending { withdrawal_id: u64 },
+    Refunded { block_idx: Nat },
+    // the shop keeps the excess, since it couldn't be refunded
+    RefundFailed { reason: String },
+}
+
+/**
+ * The part of the payment, which completed the invoice, that exceeds the invoice amount
+ */
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Overpayment {
+    pub token_id: Principal,
+    pub qty: EDs,
+    pub qty_usd: E8s,
+    pub status: OverpaymentStatus,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum InvoiceRefundStatus {
+    Pending,
+    Completed { block_idx: Nat },
+    // the refunded funds are back in the shop's balance
+    Failed { reason: String },
+}
+
+/**
+ * A refund of (a part of) a payment back to its payer, sent with a withdrawal of the shop
+ */
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InvoiceRefund {
+    pub withdrawal_id: u64,
+    pub token_id: Principal,
+    pub payment_block_idx: Nat,
+    pub qty: EDs,
+    pub qty_usd: E8s,
+    pub timestamp: u64,
+    pub status: InvoiceRefundStatus,
+}
+
+/**
+ * A fixed price in a specific token - such invoices are paid with exactly this token, without any exchange rate conversion
+ */
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TokenPrice {
+    pub token_id: Principal,
+    pub qty: EDs,
+}
+
+/**
+ * A price in a non-USD fiat currency, locked to its USD exchange rate at the moment the invoice was created
+ */
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FiatPrice {
+    pub currency: String,
+    pub qty: E8s,
+    pub usd_rate: E8s,
+}
+
+/**
+ * Which exchange rate converts the payments of a USD-priced invoice
+ */
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RateLockPolicy {
+    // the rate, which was actual when the invoice was created
+    #[default]
+    LockAtCreation,
+    // the latest rate at the moment of each payment
+    RequoteAtPayment,
+}
+
+/**
+ * How much less than the invoice amount, in basis points of it, is still accepted as a full payment.
+ * Token-priced invoices are always paid in full.
+ */
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct UnderpaymentTolerance {
+    pub bps: u16,
+    // overrides `bps` for payments in these tokens
+    pub by_token: Option<BTreeMap<Principal, u16>>,
+}
+
+impl Default for UnderpaymentTolerance {
+    fn default() -> Self {
+        Self {
+            bps: DEFAULT_UNDERPAYMENT_TOLERANCE_BPS,
+            by_token: None,
+        }
+    }
+}
+
+impl UnderpaymentTolerance {
+    pub fn bps_for(&self, token_id: &Principal) -> u16 {
+        self.by_token
+            .as_ref()
+            .and_then(|it| it.get(token_id))
+            .copied()
+            .unwrap_or(self.bps)
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InvoiceLineItem {
+    pub sku: Option<String>,
+    pub name: String,
+    pub qty: u64,
+    // in the currency the invoice is priced in
+    pub unit_price: E8s,
+}
+
+/**
+ * What the merchant links the invoice to its order with - and what the payer is shown
+ */
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct InvoiceMetadata {
+    // unique per shop
+    pub external_order_id: Option<String>,
+    pub description: Option<String>,
+    pub line_items: Option<Vec<InvoiceLineItem>>,
+    pub extra: Option<BTreeMap<String, String>>,
+}
+
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct Invoice {
     pub id: InvoiceId,
@@ -28,6 +204,25 @@ pub struct Invoice {
     pub created_at: u64,
     pub exchange_rates_timestamp: u64,
     pub shop_id: u64,
+    // if set, the invoice is paid into this subaccount of the payment hub instead of the shop one, and needs no memo
+    pub deposit_subaccount: Option<[u8; 32]>,
+    // if set, the invoice is priced in a token and `qty_usd` is only an estimate made at creation
+    pub token_price: Option<TokenPrice>,
+    // if set, the invoice is priced in this fiat currency and `qty_usd` is converted from it
+    pub fiat_price: Option<FiatPrice>,
+    // payments applied so far and their running total - not set until the first payment
+    pub payments: Option<Vec<InvoicePayment>>,
+    pub paid_qty_usd: Option<E8s>,
+    pub overpayment: Option<Overpayment>,
+    pub refunds: Option<Vec<InvoiceRefund>>,
+    // transfers made after the invoice expired or was cancelled - they pay for nothing and should be refunded
+    pub late_payments: Option<Vec<InvoicePayment>>,
+    // an invoice, which is not paid at all by this time, expires - not set for invoices archived before it was introduced
+    pub expires_at: Option<u64>,
+    // the terms of the shop at the moment the invoice was created - the defaults, if not set
+    pub rate_lock_policy: Option<RateLockPolicy>,
+    pub underpayment_tolerance: Option<UnderpaymentTolerance>,
+    pub metadata: Option<InvoiceMetadata>,
 }
 
 #[derive(CandidType, Deserialize)]
@@ -40,10 +235,35 @@ pub struct GetInvoiceResponse {
     pub invoice_opt: Option<Invoice>,
 }
 
+#[derive(CandidType, Deserialize)]
+pub struct GetInvoiceByExternalOrderIdRequest {
+    pub shop_id: u64,
+    pub external_order_id: String,
+}
+
+/**
+ * The invoice itself is not set, if it is archived already - look it up in the invoice archives by its id then
+ */
+#[derive(CandidType, Deserialize)]
+pub struct GetInvoiceByExternalOrderIdResponse {
+    pub invoice_id: Option<InvoiceId>,
+    pub invoice_opt: Option<Invoice>,
+}
+
 #[derive(CandidType, Deserialize)]
 pub struct CreateInvoiceRequest {
     pub shop_id: u64,
+    // in the pricing currency of the shop, which is USD by default
     pub qty_usd: E8s,
+    // for payers who can't set a memo - the invoice gets its own deposit subaccount
+    pub use_deposit_subaccount: Option<bool>,
+    // prices the invoice in a token instead of USD - `qty_usd` is ignored then
+    pub token_price: Option<TokenPrice>,
+    // the lifetime of the invoice in nanoseconds, within the bounds set by the shop - the shop's default, if not set
+    pub ttl: Option<u64>,
+    pub metadata: Option<InvoiceMetadata>,
+    // retries with the same key (by the same caller, for the same shop) return the invoice created by the first request
+    pub idempotency_key: Option<String>,
 }
 
 #[derive(CandidType, Deserialize)]
@@ -60,10 +280,70 @@ pub struct VerifyPaymentRequest {
 
 pub type VerifyPaymentResponse = Result<Invoice, String>;
 
+/**
+ * The caller should approve (ICRC-2) the payment hub to spend the invoice amount plus the transfer fee
+ * from `from_subaccount` beforehand
+ */
+#[derive(CandidType, Deserialize)]
+pub struct PayInvoiceRequest {
+    pub invoice_id: InvoiceId,
+    pub asset_id: Principal,
+    pub from_subaccount: Option<[u8; 32]>,
+    // reuse it, when retrying a call with an unknown outcome, so the ledger deduplicates the transfer - the current time, if not set
+    pub created_at_time: Option<u64>,
+}
+
+pub type PayInvoiceResponse = Result<Invoice, String>;
+
+/**
+ * Only invoices, which are not paid (even partially) yet, can be cancelled
+ */
+#[derive(CandidType, Deserialize)]
+pub struct CancelInvoiceRequest {
+    pub invoice_id: InvoiceId,
+}
+
+pub type CancelInvoiceResponse = Result<Invoice, String>;
+
 pub const MSQ_PAY_CANISTER_ID: &str = "dqerg-34aaa-aaaaa-qaapq-cai";
 pub const CREATE_INVOICE_METHOD: &str = "create_invoice";
 pub const GET_INVOICE_METHOD: &str = "get_invoice";
+pub const GET_INVOICE_BY_EXTERNAL_ORDER_ID_METHOD: &str = "get_invoice_by_external_order_id";
 pub const VERIFY_PAYMENT_METHOD: &str = "verify_payment";
+pub const PAY_INVOICE_METHOD: &str = "pay_invoice";
+pub const CANCEL_INVOICE_METHOD: &str = "cancel_invoice";
+
+pub const CREATE_INVOICE_MAX_ATTEMPTS: u32 = 3;
+
+static IDEMPOTENCY_KEY_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/**
+ * A call could be retried, if it either surely didn't reach the canister (`SysTransient`) or its outcome is unknown
+ * (`Unknown`, e.g. a timeout) - the latter is only safe for idempotent calls
+ */
+pub fn is_retryable(code: &RejectionCode) -> bool {
+    matches!(code, RejectionCode::SysTransient | RejectionCode::Unknown)
+}
+
+/**
+ * Makes the call again, while it fails with a retryable code, up to `max_attempts` times in total
+ */
+pub async fn call_with_retries<T, F, Fut>(max_attempts: u32, mut make_call: F) -> CallResult<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = CallResult<T>>,
+{
+    let mut attempt = 1;
+
+    loop {
+        match make_call().await {
+            Err((code, _)) if is_retryable(&code) && attempt < max_attempts => {
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
 
 pub struct InterCanisterClient(pub Principal);
 
@@ -76,21 +356,52 @@ impl InterCanisterClient {
         }
     }
 
+    /**
+     * Retries transient failures and calls with an unknown outcome - the request carries a fresh idempotency key,
+     * so a retry never creates a second invoice
+     */
     pub async fn create_invoice(
         &self,
         shop_id: u64,
         qty_usd_e8s: Nat,
     ) -> Result<InvoiceId, String> {
-        let arg = CreateInvoiceRequest {
-            shop_id,
-            qty_usd: E8s::new(qty_usd_e8s.0),
-        };
+        let idempotency_key = format!(
+            "{}-{}",
+            ic_cdk::api::time(),
+            IDEMPOTENCY_KEY_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
 
-        let (resp,) = call::<(CreateInvoiceRequest,), (CreateInvoiceResponse,)>(
-            self.0,
-            CREATE_INVOICE_METHOD,
-            (arg,),
-        )
+        self.create_invoice_with_idempotency_key(shop_id, qty_usd_e8s, idempotency_key)
+            .await
+    }
+
+    /**
+     * Same as `create_invoice`, but with an idempotency key chosen by the caller - e.g. their order id,
+     * so the invoice could be recovered even after the calling canister itself gave up
+     */
+    pub async fn create_invoice_with_idempotency_key(
+        &self,
+        shop_id: u64,
+        qty_usd_e8s: Nat,
+        idempotency_key: String,
+    ) -> Result<InvoiceId, String> {
+        let (resp,) = call_with_retries(CREATE_INVOICE_MAX_ATTEMPTS, || {
+            let arg = CreateInvoiceRequest {
+                shop_id,
+                qty_usd: E8s::new(qty_usd_e8s.0.clone()),
+                use_deposit_subaccount: None,
+                token_price: None,
+                ttl: None,
+                metadata: None,
+                idempotency_key: Some(idempotency_key.clone()),
+            };
+
+            call::<(CreateInvoiceRequest,), (CreateInvoiceResponse,)>(
+                self.0,
+                CREATE_INVOICE_METHOD,
+                (arg,),
+            )
+        })
         .await
         .map_err(|(code, msg)| format!("Unable to call MSQ Pay canister: [{:?}] {}", code, msg))?;
 
@@ -110,6 +421,26 @@ impl InterCanisterClient {
         Ok(resp.invoice_opt)
     }
 
+    pub async fn get_invoice_by_external_order_id(
+        &self,
+        shop_id: u64,
+        external_order_id: String,
+    ) -> Result<GetInvoiceByExternalOrderIdResponse, String> {
+        let arg = GetInvoiceByExternalOrderIdRequest {
+            shop_id,
+            external_order_id,
+        };
+
+        let (resp,) = call::<
+            (GetInvoiceByExternalOrderIdRequest,),
+            (GetInvoiceByExternalOrderIdResponse,),
+        >(self.0, GET_INVOICE_BY_EXTERNAL_ORDER_ID_METHOD, (arg,))
+        .await
+        .map_err(|(code, msg)| format!("Unable to call MSQ Pay canister: [{:?}] {}", code, msg))?;
+
+        Ok(resp)
+    }
+
     pub async fn verify_payment(
         &self,
         invoice_id: InvoiceId,
@@ -132,4 +463,114 @@ impl InterCanisterClient {
 
         resp
     }
+
+    pub async fn pay_invoice(
+        &self,
+        invoice_id: InvoiceId,
+        token_id: Principal,
+        from_subaccount: Option<[u8; 32]>,
+    ) -> Result<Invoice, String> {
+        let arg = PayInvoiceRequest {
+            invoice_id,
+            asset_id: token_id,
+            from_subaccount,
+            created_at_time: None,
+        };
+
+        let (resp,) =
+            call::<(PayInvoiceRequest,), (PayInvoiceResponse,)>(self.0, PAY_INVOICE_METHOD, (arg,))
+                .await
+                .map_err(|(code, msg)| {
+                    format!("Unable to call MSQ Pay canister: [{:?}] {}", code, msg)
+                })?;
+
+        resp
+    }
+
+    pub async fn cancel_invoice(&self, invoice_id: InvoiceId) -> Result<Invoice, String> {
+        let arg = CancelInvoiceRequest { invoice_id };
+
+        let (resp,) = call::<(CancelInvoiceRequest,), (CancelInvoiceResponse,)>(
+            self.0,
+            CANCEL_INVOICE_METHOD,
+            (arg,),
+        )
+        .await
+        .map_err(|(code, msg)| format!("Unable to call MSQ Pay canister: [{:?}] {}", code, msg))?;
+
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use ic_cdk::api::call::{CallResult, RejectionCode};
+
+    use super::{call_with_retries, CREATE_INVOICE_MAX_ATTEMPTS};
+
+    fn block_on<T>(fut: impl Future<Output = T>) -> T {
+        let mut fut = pin!(fut);
+        let mut cx = Context::from_waker(Waker::noop());
+
+        loop {
+            if let Poll::Ready(res) = fut.as_mut().poll(&mut cx) {
+                return res;
+            }
+        }
+    }
+
+    // replays the given results and passes the same key to each attempt, like `create_invoice_with_idempotency_key` does
+    fn run(results: Vec<CallResult<u64>>) -> (CallResult<u64>, Vec<String>) {
+        let key = "order-1".to_string();
+        let mut results = results.into_iter();
+        let mut keys_sent = Vec::new();
+
+        let result = block_on(call_with_retries(CREATE_INVOICE_MAX_ATTEMPTS, || {
+            keys_sent.push(key.clone());
+            let result = results.next().unwrap();
+
+            async move { result }
+        }));
+
+        (result, keys_sent)
+    }
+
+    #[test]
+    fn unknown_outcome_is_retried_with_the_same_key() {
+        let (result, keys_sent) = run(vec![
+            Err((RejectionCode::Unknown, "timeout".to_string())),
+            Err((RejectionCode::SysTransient, "queue full".to_string())),
+            Ok(42),
+        ]);
+
+        assert_eq!(result, Ok(42));
+        assert_eq!(keys_sent, vec!["order-1"; 3]);
+    }
+
+    #[test]
+    fn retries_stop_after_max_attempts_or_on_a_definite_failure() {
+        let (result, keys_sent) = run(vec![
+            Err((RejectionCode::Unknown, "timeout".to_string())),
+            Err((RejectionCode::Unknown, "timeout".to_string())),
+            Err((RejectionCode::Unknown, "timeout".to_string())),
+            Ok(42),
+        ]);
+
+        assert!(matches!(result, Err((RejectionCode::Unknown, _))));
+        assert_eq!(keys_sent.len(), CREATE_INVOICE_MAX_ATTEMPTS as usize);
+
+        let (result, keys_sent) = run(vec![
+            Err((RejectionCode::CanisterReject, "bad request".to_string())),
+            Ok(42),
+        ]);
+
+        assert!(matches!(result, Err((RejectionCode::CanisterReject, _))));
+        assert_eq!(keys_sent.len(), 1);
+    }
 }
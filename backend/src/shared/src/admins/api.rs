@@ -0,0 +1,26 @@
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+
+#[derive(CandidType, Deserialize)]
+pub struct GetAdminsRequest {}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetAdminsResponse {
+    pub admins: Vec<Principal>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GrantAdminRequest {
+    pub principal: Principal,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GrantAdminResponse {}
+
+#[derive(CandidType, Deserialize)]
+pub struct RevokeAdminRequest {
+    pub principal: Principal,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RevokeAdminResponse {}
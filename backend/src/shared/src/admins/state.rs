@@ -0,0 +1,38 @@
+use candid::Principal;
+use ic_stable_structures::StableBTreeMap;
+
+use crate::utils::Memory;
+
+/**
+ * Principals allowed to manage supported tokens and platform settings.
+ * Controllers of the canister are always treated as admins, so they can grant the first ones.
+ */
+pub struct AdminsState {
+    pub admins: StableBTreeMap<Principal, (), Memory>,
+}
+
+impl AdminsState {
+    pub fn init(admins_memory: Memory) -> Self {
+        Self {
+            admins: StableBTreeMap::init(admins_memory),
+        }
+    }
+
+    pub fn is_admin(&self, principal: &Principal) -> bool {
+        self.admins.contains_key(principal)
+    }
+
+    pub fn grant(&mut self, principal: Principal) {
+        self.admins.insert(principal, ());
+    }
+
+    pub fn revoke(&mut self, principal: &Principal) -> Result<(), String> {
+        self.admins
+            .remove(principal)
+            .ok_or(String::from("Not an admin"))
+    }
+
+    pub fn get(&self) -> Vec<Principal> {
+        self.admins.iter().map(|(it, _)| it).collect()
+    }
+}
@@ -0,0 +1,24 @@
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::utils::ShopId;
+
+use super::types::{BalanceDiscrepancy, ShopBalance};
+
+#[derive(CandidType, Deserialize)]
+pub struct GetShopBalancesRequest {
+    pub shop_id: ShopId,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetShopBalancesResponse {
+    pub balances: Vec<ShopBalance>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetBalanceDiscrepanciesRequest {}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetBalanceDiscrepanciesResponse {
+    pub discrepancies: Vec<BalanceDiscrepancy>,
+}
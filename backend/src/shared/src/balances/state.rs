@@ -0,0 +1,71 @@
+use ic_e8s::d::EDs;
+use ic_stable_structures::StableBTreeMap;
+
+use crate::utils::{Candid, Memory, ShopId, TokenId};
+
+use super::types::{BalanceDiscrepancy, ShopBalance};
+
+/**
+ * Internal ledger of what each shop is able to withdraw, per token. Credited when an invoice
+ * is paid, debited when a withdrawal is journaled (and credited back for legs which failed).
+ */
+pub struct BalancesState {
+    pub balances: StableBTreeMap<(ShopId, TokenId), Candid<EDs>, Memory>,
+    pub discrepancies: StableBTreeMap<(ShopId, TokenId), BalanceDiscrepancy, Memory>,
+}
+
+impl BalancesState {
+    pub fn init(balances_memory: Memory, discrepancies_memory: Memory) -> Self {
+        Self {
+            balances: StableBTreeMap::init(balances_memory),
+            discrepancies: StableBTreeMap::init(discrepancies_memory),
+        }
+    }
+
+    pub fn get(&self, shop_id: ShopId, token_id: TokenId, decimals: u8) -> EDs {
+        self.balances
+            .get(&(shop_id, token_id))
+            .map(|it| it.0)
+            .unwrap_or_else(|| EDs::from((0u64, decimals)))
+    }
+
+    pub fn get_by_shop(&self, shop_id: ShopId) -> Vec<ShopBalance> {
+        self.balances
+            .range((shop_id, TokenId::management_canister())..)
+            .take_while(|((id, _), _)| *id == shop_id)
+            .map(|((_, token_id), Candid(balance))| ShopBalance { token_id, balance })
+            .collect()
+    }
+
+    pub fn credit(&mut self, shop_id: ShopId, token_id: TokenId, qty: &EDs) {
+        let balance = self.get(shop_id, token_id, qty.decimals) + qty;
+
+        self.balances.insert((shop_id, token_id), Candid(balance));
+    }
+
+    // funds might have been received before the internal ledger was introduced, so the balance can't go below zero
+    pub fn debit(&mut self, shop_id: ShopId, token_id: TokenId, qty: &EDs) {
+        let balance = self.get(shop_id, token_id, qty.decimals);
+
+        let balance = if &balance > qty {
+            balance - qty
+        } else {
+            EDs::from((0u64, qty.decimals))
+        };
+
+        self.balances.insert((shop_id, token_id), Candid(balance));
+    }
+
+    pub fn report_discrepancy(&mut self, discrepancy: BalanceDiscrepancy) {
+        self.discrepancies
+            .insert((discrepancy.shop_id, discrepancy.token_id), discrepancy);
+    }
+
+    pub fn clear_discrepancy(&mut self, shop_id: ShopId, token_id: TokenId) {
+        self.discrepancies.remove(&(shop_id, token_id));
+    }
+
+    pub fn get_discrepancies(&self) -> Vec<BalanceDiscrepancy> {
+        self.discrepancies.iter().map(|(_, it)| it).collect()
+    }
+}
@@ -0,0 +1,29 @@
+use candid::CandidType;
+use ic_e8s::d::EDs;
+use serde::Deserialize;
+
+use crate::{
+    impl_candid_storable,
+    utils::{ShopId, Timestamp, TokenId},
+};
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ShopBalance {
+    pub token_id: TokenId,
+    pub balance: EDs,
+}
+
+/**
+ * A mismatch between the internal balance of a shop and the actual balance of its subaccount,
+ * found by the last reconciliation. Withdrawals with pending legs may cause temporary ones.
+ */
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BalanceDiscrepancy {
+    pub shop_id: ShopId,
+    pub token_id: TokenId,
+    pub internal_balance: EDs,
+    pub actual_balance: EDs,
+    pub checked_at: Timestamp,
+}
+
+impl_candid_storable!(BalanceDiscrepancy);
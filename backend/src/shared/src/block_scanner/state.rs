@@ -0,0 +1,27 @@
+use ic_stable_structures::StableBTreeMap;
+
+use crate::utils::{Memory, TokenId};
+
+pub struct BlockScannerState {
+    // index of the next block to scan, per supported token
+    pub cursors: StableBTreeMap<TokenId, u64, Memory>,
+    // set while a scan is in flight, so the next timer tick doesn't start an overlapping one
+    pub is_scanning: bool,
+}
+
+impl BlockScannerState {
+    pub fn init(cursors_memory: Memory) -> Self {
+        Self {
+            cursors: StableBTreeMap::init(cursors_memory),
+            is_scanning: false,
+        }
+    }
+
+    pub fn get_cursor(&self, token_id: &TokenId) -> Option<u64> {
+        self.cursors.get(token_id)
+    }
+
+    pub fn set_cursor(&mut self, token_id: TokenId, next_block_idx: u64) {
+        self.cursors.insert(token_id, next_block_idx);
+    }
+}
@@ -15,3 +15,11 @@ pub struct GetExchangeRatesRequest {
 pub struct GetExchangeRatesResponse {
     pub rates: Option<Vec<(Ticker, E8s)>>,
 }
+
+#[derive(CandidType, Deserialize)]
+pub struct SetShouldMockExchangeRatesRequest {
+    pub should_mock: bool,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SetShouldMockExchangeRatesResponse {}
@@ -1,66 +1,103 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::BTreeMap;
 
 use candid::CandidType;
 use ic_e8s::c::E8s;
+use ic_stable_structures::{StableBTreeMap, StableCell};
 use serde::Deserialize;
 
-use crate::utils::Timestamp;
+use crate::{
+    impl_candid_storable,
+    utils::{Candid, Memory, Timestamp},
+};
 
 use super::types::Ticker;
 
-#[derive(CandidType, Deserialize, Default, Clone)]
-pub struct ExchangeRatesState {
+#[derive(CandidType, Deserialize, Default, Clone, Copy)]
+pub struct ExchangeRatesMeta {
     pub mock: bool,
     pub last_updated_at: Timestamp,
-    pub rates: HashMap<Timestamp, BTreeMap<Ticker, E8s>>,
+}
+
+impl_candid_storable!(ExchangeRatesMeta);
+
+pub struct ExchangeRatesState {
+    pub meta: StableCell<ExchangeRatesMeta, Memory>,
+    pub rates: StableBTreeMap<Timestamp, Candid<BTreeMap<Ticker, E8s>>, Memory>,
 }
 
 impl ExchangeRatesState {
+    pub fn init(meta_memory: Memory, rates_memory: Memory) -> Self {
+        Self {
+            meta: StableCell::init(meta_memory, ExchangeRatesMeta::default())
+                .expect("Unable to init exchange rates meta"),
+            rates: StableBTreeMap::init(rates_memory),
+        }
+    }
+
     pub fn should_mock(&self) -> bool {
-        self.mock
+        self.meta.get().mock
     }
 
     pub fn set_should_mock(&mut self, mock: bool) {
-        self.mock = mock;
+        let mut meta = *self.meta.get();
+        meta.mock = mock;
+
+        self.meta.set(meta).expect("Unable to set mock flag");
     }
 
-    pub fn get_exchange_rate(&self, updated_at: &Timestamp, ticker: &Ticker) -> &E8s {
-        self.rates.get(updated_at).unwrap().get(ticker).unwrap()
+    pub fn last_updated_at(&self) -> Timestamp {
+        self.meta.get().last_updated_at
     }
 
-    pub fn get_current_rates(&self) -> &BTreeMap<Ticker, E8s> {
+    pub fn set_last_updated_at(&mut self, timestamp: Timestamp) {
+        let mut meta = *self.meta.get();
+        meta.last_updated_at = timestamp;
+
+        self.meta
+            .set(meta)
+            .expect("Unable to set exchange rates timestamp");
+    }
+
+    pub fn get_exchange_rate(&self, updated_at: &Timestamp, ticker: &Ticker) -> E8s {
+        self.rates
+            .get(updated_at)
+            .unwrap()
+            .0
+            .remove(ticker)
+            .unwrap()
+    }
+
+    pub fn find_exchange_rate(&self, updated_at: &Timestamp, ticker: &Ticker) -> Option<E8s> {
+        self.rates.get(updated_at)?.0.remove(ticker)
+    }
+
+    pub fn get_current_rates(&self) -> BTreeMap<Ticker, E8s> {
         self.rates
-            .get(&self.last_updated_at)
+            .get(&self.last_updated_at())
             .expect("Current rates are not ready yet, try again later...")
+            .0
     }
 
-    pub fn get_rates(&self, timestamp: Timestamp) -> Option<&BTreeMap<Ticker, E8s>> {
-        let mut keys = Vec::new();
+    pub fn get_rates(&self, timestamp: Timestamp) -> Option<BTreeMap<Ticker, E8s>> {
+        // the latest rates, which were actual at the moment
+        self.rates
+            .range(..=timestamp)
+            .next_back()
+            .map(|(_, rates)| rates.0)
+    }
 
-        for key in self.rates.keys() {
-            match keys.binary_search(key) {
-                Err(idx) => keys.insert(idx, *key),
-                _ => {}
-            }
-        }
+    pub fn set_rates(&mut self, timestamp: Timestamp, ticker: Ticker, rate: E8s) {
+        let mut rates = self.rates.get(&timestamp).unwrap_or_default();
+        rates.0.insert(ticker, rate);
 
-        match keys.binary_search(&timestamp) {
-            Ok(idx) => self.rates.get(&keys[idx]),
-            Err(idx) => {
-                if idx == 0 {
-                    None
-                } else {
-                    self.rates.get(&keys[idx - 1])
-                }
-            }
-        }
+        self.rates.insert(timestamp, rates);
     }
 
     pub fn delete_outdated(&mut self, timestamp: &Timestamp) {
-        if *timestamp == self.last_updated_at {
+        if *timestamp == self.last_updated_at() {
             return;
         }
 
-        self.rates.remove(&timestamp);
+        self.rates.remove(timestamp);
     }
 }
@@ -4,9 +4,30 @@ use candid::CandidType;
 use serde::Deserialize;
 use tinystr::TinyStr16;
 
+// all exchange rates are quoted in this currency
+pub const BASE_CURRENCY: &str = "USD";
+
+// fiat currencies shops can price their invoices in
+pub const SUPPORTED_FIAT_CURRENCIES: &[&str] = &[
+    "USD", "EUR", "GBP", "JPY", "CHF", "CAD", "AUD", "CNY", "INR", "KRW", "SGD", "HKD",
+];
+
+pub fn is_supported_fiat_currency(currency: &str) -> bool {
+    SUPPORTED_FIAT_CURRENCIES.contains(&currency)
+}
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub struct Ticker(pub TinyStr16);
 
+impl Ticker {
+    /**
+     * Fiat rates are stored next to the token ones, under keys like "EUR/USD", which can't clash with token tickers
+     */
+    pub fn fiat(currency: &str) -> Self {
+        Self::from(format!("{}/{}", currency, BASE_CURRENCY))
+    }
+}
+
 impl Borrow<str> for Ticker {
     fn borrow(&self) -> &str {
         self.0.borrow()
@@ -0,0 +1,34 @@
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::utils::ShopId;
+
+use super::types::FeeSchedule;
+
+#[derive(CandidType, Deserialize)]
+pub struct GetFeeScheduleRequest {
+    pub shop_id: Option<ShopId>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetFeeScheduleResponse {
+    pub schedule: FeeSchedule,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SetFeeScheduleRequest {
+    pub schedule: FeeSchedule,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SetFeeScheduleResponse {}
+
+#[derive(CandidType, Deserialize)]
+pub struct SetShopFeeScheduleRequest {
+    pub shop_id: ShopId,
+    // removes the override, if empty
+    pub schedule: Option<FeeSchedule>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SetShopFeeScheduleResponse {}
@@ -0,0 +1,98 @@
+use chrono::{Datelike, TimeZone, Utc};
+use ic_e8s::c::E8s;
+use ic_stable_structures::{StableBTreeMap, StableCell};
+
+use crate::utils::{Candid, Memory, ShopId, Timestamp};
+
+use super::types::FeeSchedule;
+
+pub struct FeesState {
+    pub default_schedule: StableCell<FeeSchedule, Memory>,
+    pub shop_schedules: StableBTreeMap<ShopId, FeeSchedule, Memory>,
+    // USD volume of paid invoices, segregated by shop and calendar month
+    pub monthly_volumes: StableBTreeMap<(ShopId, u32), Candid<E8s>, Memory>,
+}
+
+impl FeesState {
+    pub fn init(
+        default_schedule_memory: Memory,
+        shop_schedules_memory: Memory,
+        monthly_volumes_memory: Memory,
+    ) -> Self {
+        Self {
+            default_schedule: StableCell::init(default_schedule_memory, FeeSchedule::default())
+                .expect("Unable to init fee schedule"),
+            shop_schedules: StableBTreeMap::init(shop_schedules_memory),
+            monthly_volumes: StableBTreeMap::init(monthly_volumes_memory),
+        }
+    }
+
+    pub fn schedule_for(&self, shop_id: &ShopId) -> FeeSchedule {
+        self.shop_schedules
+            .get(shop_id)
+            .unwrap_or_else(|| self.default_schedule.get().clone())
+    }
+
+    pub fn set_default_schedule(&mut self, schedule: FeeSchedule) -> Result<(), String> {
+        schedule.validate()?;
+
+        self.default_schedule
+            .set(schedule)
+            .expect("Unable to set fee schedule");
+
+        Ok(())
+    }
+
+    pub fn set_shop_schedule(
+        &mut self,
+        shop_id: ShopId,
+        schedule_opt: Option<FeeSchedule>,
+    ) -> Result<(), String> {
+        match schedule_opt {
+            Some(schedule) => {
+                schedule.validate()?;
+                self.shop_schedules.insert(shop_id, schedule);
+            }
+            None => {
+                self.shop_schedules.remove(&shop_id);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn add_volume(&mut self, shop_id: ShopId, timestamp: Timestamp, qty_usd: &E8s) {
+        let key = (shop_id, Self::month_idx(timestamp));
+        let Candid(mut volume) = self.monthly_volumes.get(&key).unwrap_or_default();
+
+        volume += qty_usd;
+
+        self.monthly_volumes.insert(key, Candid(volume));
+    }
+
+    pub fn sub_volume(&mut self, shop_id: ShopId, timestamp: Timestamp, qty_usd: &E8s) {
+        let key = (shop_id, Self::month_idx(timestamp));
+        let Candid(volume) = self.monthly_volumes.get(&key).unwrap_or_default();
+
+        let volume = if volume > *qty_usd {
+            volume - qty_usd
+        } else {
+            E8s::zero()
+        };
+
+        self.monthly_volumes.insert(key, Candid(volume));
+    }
+
+    pub fn monthly_volume(&self, shop_id: ShopId, timestamp: Timestamp) -> E8s {
+        self.monthly_volumes
+            .get(&(shop_id, Self::month_idx(timestamp)))
+            .map(|it| it.0)
+            .unwrap_or_default()
+    }
+
+    fn month_idx(timestamp: Timestamp) -> u32 {
+        let date = Utc.timestamp_nanos(timestamp as i64);
+
+        date.year() as u32 * 12 + date.month0()
+    }
+}
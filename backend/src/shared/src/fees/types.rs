@@ -0,0 +1,118 @@
+use candid::CandidType;
+use ic_e8s::{c::E8s, d::EDs};
+use serde::Deserialize;
+
+use crate::impl_candid_storable;
+
+pub const BPS_BASE: u16 = 10_000;
+pub const DEFAULT_PLATFORM_FEE_BPS: u16 = 300;
+pub const DEFAULT_REFERRAL_SHARE_BPS: u16 = 2_000;
+pub const DEFAULT_MIN_WITHDRAW_FEE_MULTIPLIER: u64 = 6;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FeeTier {
+    pub min_monthly_volume_usd: E8s,
+    pub platform_fee_bps: u16,
+}
+
+/**
+ * The platform takes `platform_fee_bps` of each withdrawal (or the fee of the highest tier the shop's
+ * volume for the current month reached) and passes `referral_share_bps` of it to the shop's referral.
+ * Withdrawals should be at least `min_withdraw_fee_multiplier` times bigger than the token's transfer fee.
+ */
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FeeSchedule {
+    pub platform_fee_bps: u16,
+    pub referral_share_bps: u16,
+    pub min_withdraw_fee_multiplier: u64,
+    pub tiers: Vec<FeeTier>,
+}
+
+impl_candid_storable!(FeeSchedule);
+
+impl Default for FeeSchedule {
+    fn default() -> Self {
+        Self {
+            platform_fee_bps: DEFAULT_PLATFORM_FEE_BPS,
+            referral_share_bps: DEFAULT_REFERRAL_SHARE_BPS,
+            min_withdraw_fee_multiplier: DEFAULT_MIN_WITHDRAW_FEE_MULTIPLIER,
+            tiers: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WithdrawSplit {
+    pub withdraw_qty: EDs,
+    pub fmj_fee: EDs,
+    pub referral_fee: EDs,
+}
+
+impl FeeSchedule {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.platform_fee_bps > BPS_BASE {
+            return Err(format!("Platform fee can't exceed {} bps", BPS_BASE));
+        }
+
+        if self.referral_share_bps > BPS_BASE {
+            return Err(format!("Referral share can't exceed {} bps", BPS_BASE));
+        }
+
+        if self.tiers.iter().any(|it| it.platform_fee_bps > BPS_BASE) {
+            return Err(format!("Tier platform fee can't exceed {} bps", BPS_BASE));
+        }
+
+        Ok(())
+    }
+
+    pub fn platform_fee_bps(&self, monthly_volume_usd: &E8s) -> u16 {
+        self.tiers
+            .iter()
+            .filter(|it| &it.min_monthly_volume_usd <= monthly_volume_usd)
+            .max_by(|a, b| a.min_monthly_volume_usd.cmp(&b.min_monthly_volume_usd))
+            .map(|it| it.platform_fee_bps)
+            .unwrap_or(self.platform_fee_bps)
+    }
+
+    pub fn split(
+        &self,
+        qty: EDs,
+        monthly_volume_usd: &E8s,
+        has_fee_collector: bool,
+        has_referral: bool,
+    ) -> WithdrawSplit {
+        let zero = EDs::from((0u64, qty.decimals));
+
+        if !has_fee_collector && !has_referral {
+            return WithdrawSplit {
+                withdraw_qty: qty,
+                fmj_fee: zero.clone(),
+                referral_fee: zero,
+            };
+        }
+
+        let fee_bps = self.platform_fee_bps(monthly_volume_usd) as u64;
+
+        let withdraw_qty = &qty * (BPS_BASE as u64 - fee_bps) / BPS_BASE as u64;
+        let platform_fee = &qty - &withdraw_qty;
+
+        // if only one of the fee receivers is present, it gets the whole platform fee
+        let (fmj_fee, referral_fee) = match (has_fee_collector, has_referral) {
+            (true, true) => {
+                let fmj_fee =
+                    &platform_fee * (BPS_BASE - self.referral_share_bps) as u64 / BPS_BASE as u64;
+                let referral_fee = &platform_fee - &fmj_fee;
+
+                (fmj_fee, referral_fee)
+            }
+            (false, true) => (zero, platform_fee),
+            _ => (platform_fee, zero),
+        };
+
+        WithdrawSplit {
+            withdraw_qty,
+            fmj_fee,
+            referral_fee,
+        }
+    }
+}
@@ -0,0 +1,15 @@
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::utils::ShopId;
+
+#[derive(CandidType, Deserialize)]
+pub struct GetShopAccountIdentifierRequest {
+    pub shop_id: ShopId,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetShopAccountIdentifierResponse {
+    // hex-encoded, as ICP wallets expect it
+    pub account_identifier: String,
+}
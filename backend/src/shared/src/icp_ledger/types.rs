@@ -0,0 +1,169 @@
+use candid::{CandidType, Principal};
+use icrc_ledger_types::{
+    icrc1::account::{Account, Subaccount},
+    icrc3::archive::QueryArchiveFn,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha224};
+
+const ACCOUNT_ID_DOMAIN: &[u8] = b"\x0Aaccount-id";
+
+/**
+ * Legacy ICP ledger address - a checksummed hash of an account. Many ICP wallets can only send to these.
+ */
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccountIdentifier(pub [u8; 32]);
+
+impl AccountIdentifier {
+    pub fn new(owner: &Principal, subaccount: &Subaccount) -> Self {
+        let mut hasher = Sha224::new();
+
+        hasher.update(ACCOUNT_ID_DOMAIN);
+        hasher.update(owner.as_slice());
+        hasher.update(subaccount);
+
+        let hash: [u8; 28] = hasher.finalize().into();
+        let checksum = crc32fast::hash(&hash).to_be_bytes();
+
+        let mut result = [0u8; 32];
+        result[..4].copy_from_slice(&checksum);
+        result[4..].copy_from_slice(&hash);
+
+        Self(result)
+    }
+
+    pub fn from_hex(hex_str: &str) -> Result<Self, String> {
+        let bytes: [u8; 32] = hex::decode(hex_str)
+            .map_err(|e| format!("Invalid account identifier: {}", e))?
+            .try_into()
+            .map_err(|_| "Invalid account identifier: expected 32 bytes".to_string())?;
+
+        if crc32fast::hash(&bytes[4..]).to_be_bytes() != bytes[..4] {
+            return Err("Invalid account identifier: checksum mismatch".to_string());
+        }
+
+        Ok(Self(bytes))
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+}
+
+impl From<&Account> for AccountIdentifier {
+    fn from(account: &Account) -> Self {
+        Self::new(&account.owner, &account.subaccount.unwrap_or([0u8; 32]))
+    }
+}
+
+// ------------------------ LEGACY LEDGER INTERFACE -------------------------
+// only the parts of the ICP ledger interface, which are used by the payment hub
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Tokens {
+    pub e8s: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub struct TimeStamp {
+    pub timestamp_nanos: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksArgs {
+    pub start: u64,
+    pub length: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Operation {
+    Mint {
+        to: AccountIdentifier,
+        amount: Tokens,
+    },
+    Burn {
+        from: AccountIdentifier,
+        amount: Tokens,
+    },
+    Transfer {
+        from: AccountIdentifier,
+        to: AccountIdentifier,
+        amount: Tokens,
+        fee: Tokens,
+    },
+    Approve {
+        from: AccountIdentifier,
+        fee: Tokens,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Transaction {
+    pub memo: u64,
+    pub icrc1_memo: Option<Vec<u8>>,
+    pub operation: Option<Operation>,
+    pub created_at_time: TimeStamp,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct IcpBlock {
+    pub parent_hash: Option<Vec<u8>>,
+    pub transaction: Transaction,
+    pub timestamp: TimeStamp,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BlockRange {
+    pub blocks: Vec<IcpBlock>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum GetBlocksError {
+    BadFirstBlockIndex {
+        requested_index: u64,
+        first_valid_index: u64,
+    },
+    Other {
+        error_code: u64,
+        error_message: String,
+    },
+}
+
+pub type QueryArchiveResult = Result<BlockRange, GetBlocksError>;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchivedBlocksRange {
+    pub start: u64,
+    pub length: u64,
+    pub callback: QueryArchiveFn<GetBlocksArgs, QueryArchiveResult>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct QueryBlocksResponse {
+    pub chain_length: u64,
+    pub certificate: Option<Vec<u8>>,
+    pub blocks: Vec<IcpBlock>,
+    pub first_block_index: u64,
+    pub archived_blocks: Vec<ArchivedBlocksRange>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct IcpTransferArgs {
+    pub memo: u64,
+    pub amount: Tokens,
+    pub fee: Tokens,
+    pub from_subaccount: Option<Subaccount>,
+    pub to: AccountIdentifier,
+    pub created_at_time: Option<TimeStamp>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum IcpTransferError {
+    BadFee { expected_fee: Tokens },
+    InsufficientFunds { balance: Tokens },
+    TxTooOld { allowed_window_nanos: u64 },
+    TxCreatedInFuture,
+    TxDuplicate { duplicate_of: u64 },
+}
+
+pub type IcpTransferResult = Result<u64, IcpTransferError>;
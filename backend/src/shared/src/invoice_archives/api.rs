@@ -0,0 +1,54 @@
+use candid::{CandidType, Principal};
+use msq_pay_types::InvoiceId;
+use serde::Deserialize;
+
+use crate::invoice_history::types::ArchivedInvoice;
+
+use super::types::{ArchiveInfo, ArchivingConfig};
+
+#[derive(CandidType, Deserialize)]
+pub struct GetInvoiceArchivesRequest {}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetInvoiceArchivesResponse {
+    pub archives: Vec<ArchiveInfo>,
+    pub config: ArchivingConfig,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct FindInvoiceArchiveRequest {
+    pub idx: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct FindInvoiceArchiveResponse {
+    pub archive_opt: Option<ArchiveInfo>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetArchivedInvoiceRequest {
+    pub invoice_id: InvoiceId,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetArchivedInvoiceResponse {
+    pub invoice_opt: Option<ArchivedInvoice>,
+    // the archive the invoice was found in
+    pub archive_opt: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SetInvoiceArchiveWasmRequest {
+    pub wasm: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SetInvoiceArchiveWasmResponse {}
+
+#[derive(CandidType, Deserialize)]
+pub struct UpdateArchivingConfigRequest {
+    pub config: ArchivingConfig,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct UpdateArchivingConfigResponse {}
@@ -0,0 +1,84 @@
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+
+use super::types::{ArchiveInfo, ArchivingConfig};
+
+#[derive(CandidType, Deserialize, Default, Clone)]
+pub struct InvoiceArchivesState {
+    pub archives: Vec<ArchiveInfo>,
+    pub config: ArchivingConfig,
+    pub archive_wasm: Option<Vec<u8>>,
+    pub is_spawning: bool,
+}
+
+impl InvoiceArchivesState {
+    pub fn init_root(&mut self, root_canister_id: Principal) {
+        if self.archives.is_empty() {
+            self.archives.push(ArchiveInfo::new(root_canister_id, 0));
+        }
+    }
+
+    pub fn current(&self) -> ArchiveInfo {
+        *self.archives.last().expect("No archives registered")
+    }
+
+    pub fn update_stats(&mut self, canister_id: Principal, len: u64, memory_size_bytes: u64) {
+        if let Some(archive) = self
+            .archives
+            .iter_mut()
+            .find(|it| it.canister_id == canister_id)
+        {
+            archive.len = len;
+            archive.memory_size_bytes = memory_size_bytes;
+        }
+    }
+
+    /**
+     * The archive, which holds the invoice with this index - the current one for indices it hasn't reached yet
+     */
+    pub fn find_by_idx(&self, idx: u64) -> Option<ArchiveInfo> {
+        let pos = self.archives.partition_point(|it| it.start_idx <= idx);
+
+        pos.checked_sub(1).map(|it| self.archives[it])
+    }
+
+    pub fn should_spawn_next(&self) -> bool {
+        !self.is_spawning && self.current().is_full(&self.config)
+    }
+
+    pub fn register_next(&mut self, canister_id: Principal) {
+        let start_idx = self.current().end_idx();
+
+        self.archives.push(ArchiveInfo::new(canister_id, start_idx));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+
+    use super::InvoiceArchivesState;
+
+    #[test]
+    fn any_index_resolves_to_its_archive() {
+        let mut state = InvoiceArchivesState::default();
+        let root = Principal::from_slice(&[1]);
+        let second = Principal::from_slice(&[2]);
+        let third = Principal::from_slice(&[3]);
+
+        state.init_root(root);
+        state.update_stats(root, 10, 0);
+        state.register_next(second);
+        state.update_stats(second, 5, 0);
+        state.register_next(third);
+
+        let find = |idx| state.find_by_idx(idx).unwrap().canister_id;
+
+        assert_eq!(find(0), root);
+        assert_eq!(find(9), root);
+        assert_eq!(find(10), second);
+        assert_eq!(find(14), second);
+        assert_eq!(find(15), third);
+        assert_eq!(find(1_000), third);
+    }
+}
@@ -0,0 +1,61 @@
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+
+pub const DEFAULT_MAX_ENTRIES_PER_ARCHIVE: u64 = 1_000_000;
+pub const DEFAULT_MAX_MEMORY_PER_ARCHIVE_BYTES: u64 = 32 * 1024 * 1024 * 1024;
+pub const DEFAULT_CYCLES_PER_ARCHIVE: u128 = 2_000_000_000_000;
+pub const DEFAULT_REFUND_WINDOW_NS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub struct ArchiveInfo {
+    pub canister_id: Principal,
+    pub start_idx: u64,
+    pub len: u64,
+    pub memory_size_bytes: u64,
+}
+
+impl ArchiveInfo {
+    pub fn new(canister_id: Principal, start_idx: u64) -> Self {
+        Self {
+            canister_id,
+            start_idx,
+            len: 0,
+            memory_size_bytes: 0,
+        }
+    }
+
+    pub fn end_idx(&self) -> u64 {
+        self.start_idx + self.len
+    }
+
+    pub fn is_full(&self, config: &ArchivingConfig) -> bool {
+        self.len >= config.max_entries_per_archive
+            || self.memory_size_bytes >= config.max_memory_per_archive_bytes
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub struct ArchivingConfig {
+    pub max_entries_per_archive: u64,
+    pub max_memory_per_archive_bytes: u64,
+    pub cycles_per_archive: u128,
+    // for how long closed invoices stay in the payment hub, so they could still be refunded - the default, if not set
+    pub refund_window_ns: Option<u64>,
+}
+
+impl Default for ArchivingConfig {
+    fn default() -> Self {
+        Self {
+            max_entries_per_archive: DEFAULT_MAX_ENTRIES_PER_ARCHIVE,
+            max_memory_per_archive_bytes: DEFAULT_MAX_MEMORY_PER_ARCHIVE_BYTES,
+            cycles_per_archive: DEFAULT_CYCLES_PER_ARCHIVE,
+            refund_window_ns: None,
+        }
+    }
+}
+
+impl ArchivingConfig {
+    pub fn refund_window_ns(&self) -> u64 {
+        self.refund_window_ns.unwrap_or(DEFAULT_REFUND_WINDOW_NS)
+    }
+}
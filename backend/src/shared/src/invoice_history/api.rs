@@ -1,7 +1,15 @@
 use candid::{CandidType, Principal};
+use msq_pay_types::InvoiceId;
 use serde::Deserialize;
 
-use super::types::ArchivedInvoice;
+use crate::utils::{ShopId, Timestamp, TokenId};
+
+use super::types::{ArchivedInvoice, GetInvoiceError, GetShopInvoicesError, ShopInvoicesCursor};
+
+#[derive(CandidType, Deserialize)]
+pub struct InitArgs {
+    pub start_idx: u64,
+}
 
 #[derive(CandidType, Deserialize)]
 pub struct PushBatchRequest {
@@ -9,18 +17,48 @@ pub struct PushBatchRequest {
 }
 
 #[derive(CandidType, Deserialize)]
-pub struct PushBatchResponse {}
+pub struct PushBatchResponse {
+    pub len: u64,
+    pub memory_size_bytes: u64,
+}
 
 #[derive(CandidType, Deserialize)]
 pub struct GetInvoiceRequest {
     pub idx: u64,
 }
 
-pub type GetInvoiceResponse = Result<ArchivedInvoice, Principal>;
+pub type GetInvoiceResponse = Result<ArchivedInvoice, GetInvoiceError>;
+
+#[derive(CandidType, Deserialize)]
+pub struct GetInvoiceByIdRequest {
+    pub id: InvoiceId,
+}
+
+pub type GetInvoiceByIdResponse = Result<ArchivedInvoice, GetInvoiceError>;
+
+#[derive(CandidType, Deserialize)]
+pub struct GetShopInvoicesRequest {
+    pub shop_id: ShopId,
+    // unpaid invoices are filtered by the time they expired or were cancelled
+    pub paid_from: Option<Timestamp>,
+    pub paid_to: Option<Timestamp>,
+    pub token_id: Option<TokenId>,
+    pub start_after: Option<ShopInvoicesCursor>,
+    pub limit: u32,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ShopInvoicesPage {
+    pub invoices: Vec<ArchivedInvoice>,
+    pub next_cursor: Option<ShopInvoicesCursor>,
+    pub next_archive: Option<Principal>,
+}
+
+pub type GetShopInvoicesResponse = Result<ShopInvoicesPage, GetShopInvoicesError>;
 
 #[derive(CandidType, Deserialize)]
 pub struct SetNextRequest {
-    next: Principal,
+    pub next: Principal,
 }
 
 #[derive(CandidType, Deserialize)]
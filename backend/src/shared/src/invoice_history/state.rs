@@ -1,10 +1,244 @@
 use candid::{CandidType, Principal};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager},
+    DefaultMemoryImpl, StableBTreeMap, StableCell, StableLog,
+};
+use msq_pay_types::InvoiceId;
 use serde::Deserialize;
 
-use super::types::ArchivedInvoice;
+use crate::utils::{Memory, ShopId, Timestamp};
 
-#[derive(CandidType, Deserialize, Default)]
+use super::{
+    api::{GetShopInvoicesRequest, ShopInvoicesPage},
+    types::{
+        ArchiveMeta, ArchivedInvoice, GetInvoiceError, GetShopInvoicesError, ShopInvoicesCursor,
+    },
+};
+
+pub const MAX_SHOP_INVOICES_PAGE_SIZE: u32 = 100;
+pub const MAX_SHOP_INVOICES_SCANNED: usize = 10_000;
+
+const META_MEMORY_ID: MemoryId = MemoryId::new(0);
+const LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(1);
+const LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(2);
+const IDX_BY_INVOICE_ID_MEMORY_ID: MemoryId = MemoryId::new(3);
+const IDX_BY_SHOP_MEMORY_ID: MemoryId = MemoryId::new(4);
+
+/**
+ * The whole state lives in stable memory, so upgrades don't need to (de)serialize anything.
+ * The log is append-only, the indexes point to global (chain-wide) positions of entries in it.
+ */
 pub struct State {
+    pub meta: StableCell<ArchiveMeta, Memory>,
+    pub log: StableLog<ArchivedInvoice, Memory, Memory>,
+
+    pub idx_by_invoice_id: StableBTreeMap<InvoiceId, u64, Memory>,
+    pub idx_by_shop: StableBTreeMap<(ShopId, Timestamp, u64), (), Memory>,
+}
+
+impl State {
+    pub fn init(memory_manager: &MemoryManager<DefaultMemoryImpl>) -> Self {
+        Self {
+            meta: StableCell::init(memory_manager.get(META_MEMORY_ID), ArchiveMeta::default())
+                .expect("Unable to init archive meta"),
+            log: StableLog::init(
+                memory_manager.get(LOG_INDEX_MEMORY_ID),
+                memory_manager.get(LOG_DATA_MEMORY_ID),
+            )
+            .expect("Unable to init archive log"),
+            idx_by_invoice_id: StableBTreeMap::init(
+                memory_manager.get(IDX_BY_INVOICE_ID_MEMORY_ID),
+            ),
+            idx_by_shop: StableBTreeMap::init(memory_manager.get(IDX_BY_SHOP_MEMORY_ID)),
+        }
+    }
+
+    pub fn next(&self) -> Option<Principal> {
+        self.meta.get().next
+    }
+
+    pub fn set_next(&mut self, next: Principal) {
+        let mut meta = *self.meta.get();
+        meta.next = Some(next);
+
+        self.meta.set(meta).expect("Unable to set next archive");
+    }
+
+    pub fn start_idx(&self) -> u64 {
+        self.meta.get().start_idx
+    }
+
+    pub fn set_start_idx(&mut self, start_idx: u64) {
+        let mut meta = *self.meta.get();
+        meta.start_idx = start_idx;
+
+        self.meta.set(meta).expect("Unable to set start idx");
+    }
+
+    pub fn len(&self) -> u64 {
+        self.log.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.log.is_empty()
+    }
+
+    pub fn end_idx(&self) -> u64 {
+        self.start_idx() + self.len()
+    }
+
+    pub fn push(&mut self, invoice: ArchivedInvoice) {
+        let idx = self.end_idx();
+
+        self.log
+            .append(&invoice)
+            .expect("Unable to append to the archive log");
+
+        self.idx_by_invoice_id.insert(*invoice.id(), idx);
+        self.idx_by_shop
+            .insert((invoice.shop_id(), invoice.closed_at(), idx), ());
+    }
+
+    pub fn get(&self, idx: u64) -> Result<ArchivedInvoice, GetInvoiceError> {
+        // invoices past this archive's range live in the next one
+        if idx >= self.end_idx() {
+            return Err(self.not_found());
+        }
+
+        let start_idx = self.start_idx();
+        if idx < start_idx {
+            return Err(GetInvoiceError::NotFound);
+        }
+
+        self.log
+            .get(idx - start_idx)
+            .map(ArchivedInvoice::into_latest)
+            .ok_or(GetInvoiceError::NotFound)
+    }
+
+    pub fn get_by_id(&self, id: &InvoiceId) -> Result<ArchivedInvoice, GetInvoiceError> {
+        let idx = self.idx_by_invoice_id.get(id).ok_or(self.not_found())?;
+
+        self.get(idx)
+    }
+
+    pub fn get_shop_invoices(
+        &self,
+        req: &GetShopInvoicesRequest,
+    ) -> Result<ShopInvoicesPage, GetShopInvoicesError> {
+        if req.limit == 0 || req.limit > MAX_SHOP_INVOICES_PAGE_SIZE {
+            return Err(GetShopInvoicesError::InvalidLimit {
+                max: MAX_SHOP_INVOICES_PAGE_SIZE,
+            });
+        }
+
+        let paid_from = req.paid_from.unwrap_or(Timestamp::MIN);
+        let paid_to = req.paid_to.unwrap_or(Timestamp::MAX);
+
+        if paid_from > paid_to {
+            return Err(GetShopInvoicesError::InvalidTimeRange);
+        }
+
+        let from = match req.start_after {
+            Some(cursor) => (req.shop_id, cursor.closed_at, cursor.idx.saturating_add(1)),
+            None => (req.shop_id, paid_from, 0),
+        };
+        let to = (req.shop_id, paid_to, u64::MAX);
+
+        let mut invoices = Vec::new();
+        let mut last_scanned = None;
+        let mut next_cursor = None;
+
+        if from <= to {
+            for (i, ((_, closed_at, idx), _)) in self.idx_by_shop.range(from..=to).enumerate() {
+                // stop early, so a sparse token filter can't exhaust the instruction limit
+                if invoices.len() == req.limit as usize || i == MAX_SHOP_INVOICES_SCANNED {
+                    next_cursor = last_scanned;
+                    break;
+                }
+
+                last_scanned = Some(ShopInvoicesCursor { closed_at, idx });
+
+                let invoice = self.get(idx).expect("Inconsistent shop index");
+
+                if let Some(token_id) = req.token_id {
+                    if invoice.token_id() != Some(token_id) {
+                        continue;
+                    }
+                }
+
+                invoices.push(invoice);
+            }
+        }
+
+        Ok(ShopInvoicesPage {
+            invoices,
+            next_cursor,
+            next_archive: self.next(),
+        })
+    }
+
+    fn not_found(&self) -> GetInvoiceError {
+        match self.next() {
+            Some(next) => GetInvoiceError::CheckNextArchive(next),
+            None => GetInvoiceError::NotFound,
+        }
+    }
+}
+
+/**
+ * The state layout used before the stable memory one, only needed to migrate old archives
+ */
+#[derive(CandidType, Deserialize)]
+pub struct LegacyState {
     pub next: Option<Principal>,
+    pub start_idx: Option<u64>,
     pub log: Vec<ArchivedInvoice>,
 }
+
+#[cfg(test)]
+mod tests {
+    use candid::{Nat, Principal};
+    use ic_e8s::{c::E8s, d::EDs};
+    use ic_stable_structures::{memory_manager::MemoryManager, DefaultMemoryImpl};
+    use msq_pay_types::InvoiceStatus;
+
+    use crate::invoice_history::types::{ArchivedInvoice, ArchivedInvoiceV0001};
+
+    use super::State;
+
+    #[test]
+    fn older_versions_are_read_as_the_latest_one() {
+        let mut state = State::init(&MemoryManager::init(DefaultMemoryImpl::default()));
+
+        state.push(ArchivedInvoice::V0001(ArchivedInvoiceV0001 {
+            id: [1u8; 32],
+            shop_id: 0,
+            creator: Principal::anonymous(),
+            qty_usd: E8s::from(10_0000_0000u64),
+            token_id: Principal::management_canister(),
+            token_qty: EDs::new(1_0000_0000u64.into(), 8),
+            exchange_rate: EDs::new(10_0000_0000u64.into(), 8),
+            exchange_rates_timestamp: 0,
+            created_at: 10,
+            paid_at: 20,
+            block_idx: Some(Nat::from(5u64)),
+            from: None,
+            refunded_qty_usd: Some(E8s::from(1_0000_0000u64)),
+            metadata: None,
+        }));
+
+        let ArchivedInvoice::V0003(it) = state.get_by_id(&[1u8; 32]).unwrap() else {
+            panic!("The invoice is not upgraded");
+        };
+
+        assert!(matches!(
+            it.status,
+            InvoiceStatus::Paid { timestamp: 20, .. }
+        ));
+        assert_eq!(it.closed_at, 20);
+        assert_eq!(it.payments.len(), 1);
+        assert_eq!(it.payments[0].qty_usd, E8s::from(10_0000_0000u64));
+        assert_eq!(it.refunded_qty_usd, Some(E8s::from(1_0000_0000u64)));
+    }
+}
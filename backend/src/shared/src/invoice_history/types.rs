@@ -1,10 +1,311 @@
-use candid::CandidType;
+use candid::{CandidType, Nat, Principal};
+use ic_e8s::{c::E8s, d::EDs};
+use icrc_ledger_types::icrc1::account::Account;
+use msq_pay_types::{
+    FiatPrice, Invoice, InvoiceId, InvoiceMetadata, InvoicePayment, InvoiceRefund, InvoiceStatus,
+    Overpayment, RateLockPolicy, TokenPrice, UnderpaymentTolerance,
+};
 use serde::Deserialize;
 
+use crate::{
+    impl_candid_storable,
+    invoices::state::InvoicesState,
+    utils::{ShopId, Timestamp, TokenId},
+};
+
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub enum ArchivedInvoice {
     V0001(ArchivedInvoiceV0001),
+    V0002(ArchivedInvoiceV0002),
+    V0003(Box<ArchivedInvoiceV0003>),
+}
+
+impl_candid_storable!(ArchivedInvoice);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchivedInvoiceV0001 {
+    pub id: InvoiceId,
+    pub shop_id: ShopId,
+    pub creator: Principal,
+    pub qty_usd: E8s,
+    pub token_id: TokenId,
+    pub token_qty: EDs,
+    pub exchange_rate: EDs,
+    pub exchange_rates_timestamp: Timestamp,
+    pub created_at: Timestamp,
+    pub paid_at: Timestamp,
+    // the block, which completed the invoice, and its sender - not known for invoices paid before these were tracked
+    pub block_idx: Option<Nat>,
+    pub from: Option<Account>,
+    // the USD value of the refunds, made by the shop before the invoice was archived
+    pub refunded_qty_usd: Option<E8s>,
+    pub metadata: Option<InvoiceMetadata>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchivedInvoiceCloseReason {
+    Expired,
+    Cancelled,
+}
+
+/**
+ * An invoice, which was closed without being paid
+ */
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchivedInvoiceV0002 {
+    pub id: InvoiceId,
+    pub shop_id: ShopId,
+    pub creator: Principal,
+    pub qty_usd: E8s,
+    pub exchange_rates_timestamp: Timestamp,
+    pub created_at: Timestamp,
+    pub closed_at: Timestamp,
+    pub close_reason: ArchivedInvoiceCloseReason,
+    // transfers made after the invoice was closed - see `Invoice::late_payments`
+    pub late_payments: Vec<InvoicePayment>,
+    pub metadata: Option<InvoiceMetadata>,
 }
 
+/**
+ * A closed invoice (paid or not) with its whole history - all of its payments, refunds and the terms it was created with.
+ * Older versions are upgraded to it, when read from the archive.
+ */
 #[derive(CandidType, Deserialize, Clone, Debug)]
-pub struct ArchivedInvoiceV0001 {}
+pub struct ArchivedInvoiceV0003 {
+    pub id: InvoiceId,
+    pub shop_id: ShopId,
+    pub creator: Principal,
+    pub status: InvoiceStatus,
+    pub qty_usd: E8s,
+    pub token_price: Option<TokenPrice>,
+    pub fiat_price: Option<FiatPrice>,
+    pub exchange_rates_timestamp: Timestamp,
+    pub created_at: Timestamp,
+    pub closed_at: Timestamp,
+    pub deposit_subaccount: Option<[u8; 32]>,
+    pub payments: Vec<InvoicePayment>,
+    pub overpayment: Option<Overpayment>,
+    pub refunds: Vec<InvoiceRefund>,
+    pub late_payments: Vec<InvoicePayment>,
+    // V0001 invoices only kept the USD value of their refunds, not the refunds themselves
+    pub refunded_qty_usd: Option<E8s>,
+    pub rate_lock_policy: Option<RateLockPolicy>,
+    pub underpayment_tolerance: Option<UnderpaymentTolerance>,
+    pub metadata: Option<InvoiceMetadata>,
+}
+
+impl TryFrom<Invoice> for ArchivedInvoice {
+    type Error = String;
+
+    fn try_from(invoice: Invoice) -> Result<Self, Self::Error> {
+        if !matches!(
+            invoice.status,
+            InvoiceStatus::Paid { .. }
+                | InvoiceStatus::PartiallyRefunded { .. }
+                | InvoiceStatus::Refunded { .. }
+                | InvoiceStatus::Expired { .. }
+                | InvoiceStatus::Cancelled { .. }
+        ) {
+            return Err(format!(
+                "Only closed invoices can be archived, invoice {:?} is {:?}",
+                invoice.id, invoice.status
+            ));
+        }
+
+        // refunded invoices are closed by the payment, which completed them
+        if matches!(
+            invoice.status,
+            InvoiceStatus::PartiallyRefunded { .. } | InvoiceStatus::Refunded { .. }
+        ) && invoice.payments.is_none()
+        {
+            return Err(format!("Refunded invoice {:?} has no payments", invoice.id));
+        }
+
+        Ok(ArchivedInvoice::V0003(Box::new(ArchivedInvoiceV0003 {
+            closed_at: InvoicesState::closed_at(&invoice),
+            id: invoice.id,
+            shop_id: invoice.shop_id,
+            creator: invoice.creator,
+            status: invoice.status,
+            qty_usd: invoice.qty_usd,
+            token_price: invoice.token_price,
+            fiat_price: invoice.fiat_price,
+            exchange_rates_timestamp: invoice.exchange_rates_timestamp,
+            created_at: invoice.created_at,
+            deposit_subaccount: invoice.deposit_subaccount,
+            payments: invoice.payments.unwrap_or_default(),
+            overpayment: invoice.overpayment,
+            refunds: invoice.refunds.unwrap_or_default(),
+            late_payments: invoice.late_payments.unwrap_or_default(),
+            refunded_qty_usd: None,
+            rate_lock_policy: invoice.rate_lock_policy,
+            underpayment_tolerance: invoice.underpayment_tolerance,
+            metadata: invoice.metadata,
+        })))
+    }
+}
+
+/**
+ * Only the payment, which completed the invoice, is known - it is the only one for most of them
+ */
+impl From<ArchivedInvoiceV0001> for ArchivedInvoiceV0003 {
+    fn from(it: ArchivedInvoiceV0001) -> Self {
+        let payments = match it.block_idx.clone() {
+            Some(block_idx) => vec![InvoicePayment {
+                token_id: it.token_id,
+                block_idx,
+                from: it.from,
+                qty_usd: (&it.exchange_rate * &it.token_qty)
+                    .to_decimals(8)
+                    .to_const::<8>(),
+                qty: it.token_qty.clone(),
+                exchange_rate: it.exchange_rate.clone(),
+                timestamp: it.paid_at,
+            }],
+            None => Vec::new(),
+        };
+
+        Self {
+            id: it.id,
+            shop_id: it.shop_id,
+            creator: it.creator,
+            status: InvoiceStatus::Paid {
+                timestamp: it.paid_at,
+                token_id: it.token_id,
+                qty: it.token_qty,
+                exchange_rate: it.exchange_rate,
+                block_idx: it.block_idx,
+                from: it.from,
+            },
+            qty_usd: it.qty_usd,
+            token_price: None,
+            fiat_price: None,
+            exchange_rates_timestamp: it.exchange_rates_timestamp,
+            created_at: it.created_at,
+            closed_at: it.paid_at,
+            deposit_subaccount: None,
+            payments,
+            overpayment: None,
+            refunds: Vec::new(),
+            late_payments: Vec::new(),
+            refunded_qty_usd: it.refunded_qty_usd,
+            rate_lock_policy: None,
+            underpayment_tolerance: None,
+            metadata: it.metadata,
+        }
+    }
+}
+
+impl From<ArchivedInvoiceV0002> for ArchivedInvoiceV0003 {
+    fn from(it: ArchivedInvoiceV0002) -> Self {
+        let status = match it.close_reason {
+            ArchivedInvoiceCloseReason::Expired => InvoiceStatus::Expired {
+                timestamp: it.closed_at,
+            },
+            ArchivedInvoiceCloseReason::Cancelled => InvoiceStatus::Cancelled {
+                timestamp: it.closed_at,
+            },
+        };
+
+        Self {
+            id: it.id,
+            shop_id: it.shop_id,
+            creator: it.creator,
+            status,
+            qty_usd: it.qty_usd,
+            token_price: None,
+            fiat_price: None,
+            exchange_rates_timestamp: it.exchange_rates_timestamp,
+            created_at: it.created_at,
+            closed_at: it.closed_at,
+            deposit_subaccount: None,
+            payments: Vec::new(),
+            overpayment: None,
+            refunds: Vec::new(),
+            late_payments: it.late_payments,
+            refunded_qty_usd: None,
+            rate_lock_policy: None,
+            underpayment_tolerance: None,
+            metadata: it.metadata,
+        }
+    }
+}
+
+impl ArchivedInvoice {
+    /**
+     * The log is append-only, so older versions stay there and are upgraded on the way out
+     */
+    pub fn into_latest(self) -> Self {
+        match self {
+            ArchivedInvoice::V0001(it) => ArchivedInvoice::V0003(Box::new(it.into())),
+            ArchivedInvoice::V0002(it) => ArchivedInvoice::V0003(Box::new(it.into())),
+            it => it,
+        }
+    }
+
+    pub fn id(&self) -> &InvoiceId {
+        match self {
+            ArchivedInvoice::V0001(it) => &it.id,
+            ArchivedInvoice::V0002(it) => &it.id,
+            ArchivedInvoice::V0003(it) => &it.id,
+        }
+    }
+
+    pub fn shop_id(&self) -> ShopId {
+        match self {
+            ArchivedInvoice::V0001(it) => it.shop_id,
+            ArchivedInvoice::V0002(it) => it.shop_id,
+            ArchivedInvoice::V0003(it) => it.shop_id,
+        }
+    }
+
+    // unpaid invoices are not paid with any token
+    pub fn token_id(&self) -> Option<TokenId> {
+        match self {
+            ArchivedInvoice::V0001(it) => Some(it.token_id),
+            ArchivedInvoice::V0002(_) => None,
+            ArchivedInvoice::V0003(it) => match &it.status {
+                InvoiceStatus::Expired { .. } | InvoiceStatus::Cancelled { .. } => None,
+                InvoiceStatus::Paid { token_id, .. } => Some(*token_id),
+                _ => it.payments.last().map(|payment| payment.token_id),
+            },
+        }
+    }
+
+    /**
+     * Paid invoices are closed by their payment - this is what shop invoices are ordered by
+     */
+    pub fn closed_at(&self) -> Timestamp {
+        match self {
+            ArchivedInvoice::V0001(it) => it.paid_at,
+            ArchivedInvoice::V0002(it) => it.closed_at,
+            ArchivedInvoice::V0003(it) => it.closed_at,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default)]
+pub struct ArchiveMeta {
+    pub next: Option<Principal>,
+    pub start_idx: u64,
+}
+
+impl_candid_storable!(ArchiveMeta);
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ShopInvoicesCursor {
+    pub closed_at: Timestamp,
+    pub idx: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum GetInvoiceError {
+    NotFound,
+    CheckNextArchive(Principal),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum GetShopInvoicesError {
+    InvalidLimit { max: u32 },
+    InvalidTimeRange,
+}
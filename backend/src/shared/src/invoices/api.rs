@@ -0,0 +1,24 @@
+use candid::{CandidType, Nat};
+use ic_e8s::d::EDs;
+use msq_pay_types::InvoiceId;
+use serde::Deserialize;
+
+use crate::{utils::TokenId, withdrawals::types::WithdrawalId};
+
+/**
+ * Refunds (a part of) the payment made with the given block back to its payer
+ */
+#[derive(CandidType, Deserialize)]
+pub struct RefundInvoiceRequest {
+    pub invoice_id: InvoiceId,
+    pub token_id: TokenId,
+    pub payment_block_idx: Nat,
+    // everything, which is not refunded yet, if not set - the payer receives it minus the transfer fee
+    pub qty: Option<EDs>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct RefundInvoiceResponse {
+    pub withdrawal_id: WithdrawalId,
+    pub block_idx: Option<Nat>,
+}
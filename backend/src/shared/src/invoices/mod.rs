@@ -1 +1,3 @@
+pub mod api;
 pub mod state;
+pub mod types;
@@ -1,29 +1,112 @@
-use std::collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap};
-
-use candid::{CandidType, Principal};
+use candid::{Nat, Principal};
 use ic_e8s::{c::E8s, d::EDs};
-use msq_pay_types::{Invoice, InvoiceId, InvoiceStatus};
-use serde::Deserialize;
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use msq_pay_types::{
+    Invoice, InvoiceId, InvoiceMetadata, InvoicePayment, InvoiceRefund, InvoiceRefundStatus,
+    InvoiceStatus, Overpayment, OverpaymentStatus, TokenPrice,
+};
+use num_bigint::BigUint;
 use sha2::Digest;
 
-use crate::utils::{
-    calc_shop_subaccount, ShopId, Timestamp, TransferTxn, DEFAULT_TTL, ID_GENERATION_DOMAIN,
-    MEMO_GENERATION_DOMAIN,
+use crate::{
+    icp_ledger::types::AccountIdentifier,
+    supported_tokens::state::SupportedTokensState,
+    utils::{
+        calc_invoice_deposit_subaccount, calc_shop_subaccount, Candid, Memory, ShopId, Timestamp,
+        TokenId, TransferTxn, IDEMPOTENCY_KEY_DOMAIN, ID_GENERATION_DOMAIN, MEMO_GENERATION_DOMAIN,
+    },
+    withdrawals::types::WithdrawalId,
 };
 
-#[derive(Default, CandidType, Deserialize, Clone, Debug)]
+use super::types::{DepositSweep, NewInvoice, PendingRefund};
+
+const EXTERNAL_ORDER_ID_DOMAIN: &[u8] = b"msq-external-order-id";
+pub const MAX_EXTERNAL_ORDER_ID_LEN: usize = 128;
+pub const MAX_DESCRIPTION_LEN: usize = 1024;
+pub const MAX_SHORT_FIELD_LEN: usize = 128;
+pub const MAX_LINE_ITEMS: usize = 50;
+pub const MAX_EXTRA_ENTRIES: usize = 20;
+pub const MAX_IDEMPOTENCY_KEY_LEN: usize = 64;
+
 pub struct InvoicesState {
+    // re-seeded with fresh randomness after each upgrade, so it is never persisted
     pub invoice_id_generator: InvoiceId,
 
-    pub all_invoices: BTreeMap<InvoiceId, Invoice>,
+    pub all_invoices: StableBTreeMap<InvoiceId, Candid<Invoice>, Memory>,
 
-    pub active_invoices: HashMap<Timestamp, BTreeSet<InvoiceId>>,
-    pub inactive_invoices: BTreeSet<InvoiceId>,
+    // active invoices are segregated by the exchange rates they refer to
+    pub active_invoices: StableBTreeMap<(Timestamp, InvoiceId), (), Memory>,
+    pub inactive_invoices: StableBTreeMap<InvoiceId, (), Memory>,
+    // inactive invoices, which could not be converted for archiving - they stay here, not to block the rest
+    pub unarchivable_invoices: StableBTreeMap<InvoiceId, (), Memory>,
+    // inactive invoices by the time they were closed - held ones leave it until their refunds are settled
+    pub archive_queue: StableBTreeMap<(Timestamp, InvoiceId), (), Memory>,
+    // lets the block scanner find the active invoice an incoming transfer pays for
+    pub active_invoices_by_memo: StableBTreeMap<[u8; 32], InvoiceId, Memory>,
+    // same for invoices with deposit subaccounts - keyed by both the subaccount and the ICP account identifier of it
+    pub active_invoices_by_deposit_address: StableBTreeMap<[u8; 32], InvoiceId, Memory>,
+    pub pending_deposit_sweeps: StableBTreeMap<InvoiceId, DepositSweep, Memory>,
+    // active invoices, which can still expire - the ones with no payments yet
+    pub active_invoices_by_expiry: StableBTreeMap<(Timestamp, InvoiceId), (), Memory>,
+    // every block ever applied to an invoice, with the invoice it paid for
+    pub consumed_blocks: StableBTreeMap<(TokenId, u64), InvoiceId, Memory>,
+    // kept for archived invoices too, so external order ids stay unique
+    pub invoices_by_external_order_id: StableBTreeMap<(ShopId, [u8; 32]), InvoiceId, Memory>,
+    // hashed (shop id, creator, key) -> the invoice created with it and until when the key is remembered
+    pub idempotency_keys: StableBTreeMap<[u8; 32], (InvoiceId, Timestamp), Memory>,
+    pub idempotency_keys_by_expiry: StableBTreeMap<(Timestamp, [u8; 32]), (), Memory>,
+    pub pending_refunds: StableBTreeMap<WithdrawalId, PendingRefund, Memory>,
 
-    pub total_processed_in_usd: E8s,
+    pub total_processed_in_usd: StableCell<Candid<E8s>, Memory>,
 }
 
 impl InvoicesState {
+    #[allow(clippy::too_many_arguments)]
+    pub fn init(
+        all_invoices_memory: Memory,
+        active_invoices_memory: Memory,
+        inactive_invoices_memory: Memory,
+        unarchivable_invoices_memory: Memory,
+        archive_queue_memory: Memory,
+        active_invoices_by_memo_memory: Memory,
+        active_invoices_by_deposit_address_memory: Memory,
+        pending_deposit_sweeps_memory: Memory,
+        active_invoices_by_expiry_memory: Memory,
+        consumed_blocks_memory: Memory,
+        invoices_by_external_order_id_memory: Memory,
+        idempotency_keys_memory: Memory,
+        idempotency_keys_by_expiry_memory: Memory,
+        pending_refunds_memory: Memory,
+        total_processed_in_usd_memory: Memory,
+    ) -> Self {
+        Self {
+            invoice_id_generator: InvoiceId::default(),
+            all_invoices: StableBTreeMap::init(all_invoices_memory),
+            active_invoices: StableBTreeMap::init(active_invoices_memory),
+            inactive_invoices: StableBTreeMap::init(inactive_invoices_memory),
+            unarchivable_invoices: StableBTreeMap::init(unarchivable_invoices_memory),
+            archive_queue: StableBTreeMap::init(archive_queue_memory),
+            active_invoices_by_memo: StableBTreeMap::init(active_invoices_by_memo_memory),
+            active_invoices_by_deposit_address: StableBTreeMap::init(
+                active_invoices_by_deposit_address_memory,
+            ),
+            pending_deposit_sweeps: StableBTreeMap::init(pending_deposit_sweeps_memory),
+            active_invoices_by_expiry: StableBTreeMap::init(active_invoices_by_expiry_memory),
+            consumed_blocks: StableBTreeMap::init(consumed_blocks_memory),
+            invoices_by_external_order_id: StableBTreeMap::init(
+                invoices_by_external_order_id_memory,
+            ),
+            idempotency_keys: StableBTreeMap::init(idempotency_keys_memory),
+            idempotency_keys_by_expiry: StableBTreeMap::init(idempotency_keys_by_expiry_memory),
+            pending_refunds: StableBTreeMap::init(pending_refunds_memory),
+            total_processed_in_usd: StableCell::init(
+                total_processed_in_usd_memory,
+                Candid(E8s::zero()),
+            )
+            .expect("Unable to init total processed"),
+        }
+    }
+
     #[inline]
     pub fn init_id_seed(&mut self, seed: &[u8]) {
         self.invoice_id_generator.copy_from_slice(seed);
@@ -31,58 +114,311 @@ impl InvoicesState {
 
     pub fn create(
         &mut self,
-        qty_usd: E8s,
-        shop_id: ShopId,
+        new_invoice: NewInvoice,
         timestamp: Timestamp,
-        exchange_rates_timestamp: Timestamp,
-        caller: Principal,
-    ) -> InvoiceId {
+    ) -> Result<InvoiceId, String> {
+        let external_order_key = match &new_invoice.metadata {
+            Some(metadata) => {
+                Self::validate_metadata(metadata)?;
+
+                metadata
+                    .external_order_id
+                    .as_deref()
+                    .map(|it| (new_invoice.shop_id, Self::make_external_order_key(it)))
+            }
+            None => None,
+        };
+
+        if let Some(key) = &external_order_key {
+            if self.invoices_by_external_order_id.contains_key(key) {
+                return Err("An invoice with this external order id already exists".to_string());
+            }
+        }
+
+        let idempotency_key = match &new_invoice.idempotency_key {
+            Some((key, expires_at)) => {
+                if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+                    return Err(format!(
+                        "Invalid idempotency key: expected 1..={} bytes",
+                        MAX_IDEMPOTENCY_KEY_LEN
+                    ));
+                }
+
+                Some((
+                    Self::make_idempotency_key(new_invoice.shop_id, new_invoice.creator, key),
+                    *expires_at,
+                ))
+            }
+            None => None,
+        };
+
         let id = self.generate_id(&timestamp.to_le_bytes());
+        let deposit_subaccount = new_invoice
+            .deposit_account_owner
+            .map(|_| calc_invoice_deposit_subaccount(&id));
 
         let inv = Invoice {
             id,
-            creator: caller,
-            status: InvoiceStatus::Created { ttl: DEFAULT_TTL },
-            qty_usd,
-            exchange_rates_timestamp,
+            creator: new_invoice.creator,
+            // the lifetime in minutes, capped at the `ttl` range - `expires_at` is the exact expiry
+            status: InvoiceStatus::Created {
+                ttl: (new_invoice.expires_at.saturating_sub(timestamp) / 60_000_000_000)
+                    .min(u8::MAX as u64) as u8,
+            },
+            qty_usd: new_invoice.qty_usd,
+            exchange_rates_timestamp: new_invoice.exchange_rates_timestamp,
             created_at: timestamp,
-            shop_id,
+            shop_id: new_invoice.shop_id,
+            deposit_subaccount,
+            token_price: new_invoice.token_price,
+            fiat_price: new_invoice.fiat_price,
+            payments: None,
+            paid_qty_usd: None,
+            overpayment: None,
+            refunds: None,
+            late_payments: None,
+            expires_at: Some(new_invoice.expires_at),
+            rate_lock_policy: Some(new_invoice.rate_lock_policy),
+            underpayment_tolerance: Some(new_invoice.underpayment_tolerance),
+            metadata: new_invoice.metadata,
         };
 
-        match self.active_invoices.entry(inv.exchange_rates_timestamp) {
-            Entry::Occupied(mut e) => {
-                e.get_mut().insert(id);
+        if let Some(key) = external_order_key {
+            self.invoices_by_external_order_id.insert(key, id);
+        }
+
+        if let Some((key, expires_at)) = idempotency_key {
+            // the previous use of the key is forgotten already, but may still be waiting for the cleanup
+            if let Some((_, prev_expires_at)) = self.idempotency_keys.insert(key, (id, expires_at))
+            {
+                self.idempotency_keys_by_expiry
+                    .remove(&(prev_expires_at, key));
             }
-            Entry::Vacant(e) => {
-                let mut s = BTreeSet::new();
-                s.insert(id);
 
-                e.insert(s);
+            self.idempotency_keys_by_expiry
+                .insert((expires_at, key), ());
+        }
+
+        self.active_invoices
+            .insert((inv.exchange_rates_timestamp, id), ());
+        self.active_invoices_by_expiry
+            .insert((new_invoice.expires_at, id), ());
+        self.active_invoices_by_memo
+            .insert(Self::make_invoice_memo(&id), id);
+
+        if let (Some(owner), Some(subaccount)) =
+            (new_invoice.deposit_account_owner, deposit_subaccount)
+        {
+            for address in Self::deposit_addresses(&owner, &subaccount) {
+                self.active_invoices_by_deposit_address.insert(address, id);
             }
         }
 
-        self.all_invoices.insert(id, inv);
+        self.all_invoices.insert(id, Candid(inv));
+
+        Ok(id)
+    }
+
+    pub fn get(&self, invoice_id: &InvoiceId) -> Option<Invoice> {
+        self.all_invoices.get(invoice_id).map(|it| it.0)
+    }
+
+    pub fn get_id_by_external_order_id(
+        &self,
+        shop_id: ShopId,
+        external_order_id: &str,
+    ) -> Option<InvoiceId> {
+        self.invoices_by_external_order_id
+            .get(&(shop_id, Self::make_external_order_key(external_order_id)))
+    }
+
+    /**
+     * The invoice, created by this caller with this idempotency key, if the key is still remembered
+     */
+    pub fn get_id_by_idempotency_key(
+        &self,
+        shop_id: ShopId,
+        caller: Principal,
+        key: &str,
+        now: Timestamp,
+    ) -> Option<InvoiceId> {
+        let (invoice_id, expires_at) = self
+            .idempotency_keys
+            .get(&Self::make_idempotency_key(shop_id, caller, key))?;
+
+        if expires_at <= now {
+            return None;
+        }
+
+        Some(invoice_id)
+    }
+
+    pub fn purge_expired_idempotency_keys(&mut self, now: Timestamp) {
+        let expired = self
+            .idempotency_keys_by_expiry
+            .range((Timestamp::MIN, [u8::MIN; 32])..=(now, [u8::MAX; 32]))
+            .map(|(it, _)| it)
+            .collect::<Vec<_>>();
+
+        for (expires_at, key) in expired {
+            self.idempotency_keys_by_expiry.remove(&(expires_at, key));
+            self.idempotency_keys.remove(&key);
+        }
+    }
+
+    pub fn get_active_by_memo(&self, memo: &[u8; 32]) -> Option<InvoiceId> {
+        self.active_invoices_by_memo.get(memo)
+    }
+
+    pub fn get_active_by_deposit_address(&self, address: &[u8; 32]) -> Option<InvoiceId> {
+        self.active_invoices_by_deposit_address.get(address)
+    }
+
+    /**
+     * Legacy ICP transfers only carry a `u64` memo - the first 8 bytes of the invoice memo
+     */
+    pub fn get_active_by_legacy_memo(&self, legacy_memo: u64) -> Option<InvoiceId> {
+        let mut from = [u8::MIN; 32];
+        let mut to = [u8::MAX; 32];
+
+        from[..8].copy_from_slice(&legacy_memo.to_be_bytes());
+        to[..8].copy_from_slice(&legacy_memo.to_be_bytes());
 
-        id
+        self.active_invoices_by_memo
+            .range(from..=to)
+            .next()
+            .map(|(_, id)| id)
     }
 
+    pub fn set_status(&mut self, invoice_id: &InvoiceId, status: InvoiceStatus) -> Option<()> {
+        let mut invoice = self.get(invoice_id)?;
+        invoice.status = status;
+
+        self.all_invoices.insert(*invoice_id, Candid(invoice));
+
+        Some(())
+    }
+
+    pub fn has_active_invoices(&self, exchange_rates_timestamp: Timestamp) -> bool {
+        self.active_invoices
+            .range(
+                (exchange_rates_timestamp, [u8::MIN; 32])
+                    ..=(exchange_rates_timestamp, [u8::MAX; 32]),
+            )
+            .next()
+            .is_some()
+    }
+
+    /**
+     * Applies the transfer to an invoice in `VerifyPayment` status. The invoice becomes `Paid`, once its payments cover it,
+     * and `PartiallyPaid` otherwise. Returns the invoice, the applied payment and whether the exchange rates of the
+     * invoice are not referred by any active invoice anymore.
+     */
     pub fn verify_payment(
         &mut self,
         invoice_id: &InvoiceId,
         transfer_txn: TransferTxn,
-        exchange_rate: E8s,
+        block_idx: Nat,
+        exchange_rate: Option<E8s>,
         this_canister_id: Principal,
         now: Timestamp,
-    ) -> Result<(Invoice, bool), String> {
-        let invoice = self
-            .all_invoices
-            .get_mut(invoice_id)
+    ) -> Result<(Invoice, InvoicePayment, bool), String> {
+        let mut invoice = self
+            .get(invoice_id)
             .ok_or("Invoice not found".to_string())?;
 
         if !matches!(invoice.status, InvoiceStatus::VerifyPayment) {
             return Err("Invalid invoice status".to_string());
         }
 
+        if Self::is_expired(&invoice, now) {
+            return Err("The invoice is expired".to_string());
+        }
+
+        let consumed_block =
+            self.validate_transfer(&invoice, &transfer_txn, &block_idx, this_canister_id)?;
+        let mut payments = invoice.payments.take().unwrap_or_default();
+
+        let rate_eds = match (&invoice.token_price, exchange_rate) {
+            (Some(price), _) => Self::token_priced_payment_rate(&invoice, price, &transfer_txn)?,
+            (None, Some(exchange_rate)) => exchange_rate
+                .to_dynamic()
+                .to_decimals(transfer_txn.qty.decimals),
+            (None, None) => return Err("No exchange rate to verify the payment with".to_string()),
+        };
+
+        let payment = InvoicePayment {
+            token_id: transfer_txn.token_id,
+            block_idx,
+            from: transfer_txn.from,
+            qty_usd: (&rate_eds * &transfer_txn.qty)
+                .to_decimals(8)
+                .to_const::<8>(),
+            qty: transfer_txn.qty,
+            exchange_rate: rate_eds,
+            timestamp: now,
+        };
+
+        let paid_qty_usd = invoice.paid_qty_usd.take().unwrap_or_else(E8s::zero) + &payment.qty_usd;
+        payments.push(payment.clone());
+
+        self.consumed_blocks.insert(consumed_block, *invoice_id);
+
+        invoice.payments = Some(payments);
+        invoice.paid_qty_usd = Some(paid_qty_usd);
+
+        // the funds stay in the deposit subaccount until they are swept into the shop one
+        if let Some(deposit_subaccount) = invoice.deposit_subaccount {
+            self.schedule_deposit_sweep(
+                invoice_id,
+                invoice.shop_id,
+                payment.token_id,
+                deposit_subaccount,
+            );
+        }
+
+        if !Self::is_covered(&invoice) {
+            // still expires, so an abandoned partial payment could be refunded to its payer
+            invoice.status = InvoiceStatus::PartiallyPaid;
+            self.all_invoices
+                .insert(*invoice_id, Candid(invoice.clone()));
+
+            return Ok((invoice, payment, false));
+        }
+
+        invoice.overpayment = Self::calc_overpayment(&invoice, &payment);
+        invoice.status = InvoiceStatus::Paid {
+            timestamp: now,
+            token_id: payment.token_id,
+            qty: payment.qty.clone(),
+            exchange_rate: payment.exchange_rate.clone(),
+            block_idx: Some(payment.block_idx.clone()),
+            from: payment.from,
+        };
+
+        self.deactivate(&invoice, this_canister_id);
+
+        // move the invoice to paid list
+        self.mark_inactive(&invoice);
+        self.all_invoices
+            .insert(*invoice_id, Candid(invoice.clone()));
+
+        let should_delete_outdated = !self.has_active_invoices(invoice.exchange_rates_timestamp);
+
+        Ok((invoice, payment, should_delete_outdated))
+    }
+
+    /**
+     * Checks that the transfer is made to the invoice and its block was not used before.
+     * Returns the key of the block in the consumed blocks set.
+     */
+    fn validate_transfer(
+        &self,
+        invoice: &Invoice,
+        transfer_txn: &TransferTxn,
+        block_idx: &Nat,
+        this_canister_id: Principal,
+    ) -> Result<(TokenId, u64), String> {
         // check if the transfer was sent to the correct recepient
         let expected_recepient_principal = this_canister_id;
         let actual_recepient_principal = transfer_txn.to.owner;
@@ -94,90 +430,556 @@ impl InvoicesState {
             ));
         }
 
-        let expected_shop_subaccount = calc_shop_subaccount(invoice.shop_id);
-        let actual_shop_subaccount = transfer_txn.to.subaccount.unwrap_or([0u8; 32]);
+        let expected_subaccount = invoice
+            .deposit_subaccount
+            .unwrap_or_else(|| calc_shop_subaccount(invoice.shop_id));
+        let actual_subaccount = transfer_txn.to.subaccount.unwrap_or([0u8; 32]);
 
-        if actual_shop_subaccount != expected_shop_subaccount {
+        if actual_subaccount != expected_subaccount {
             return Err(format!(
                 "Invalid recepient subaccount: expected {:?}, actual {:?}",
-                expected_shop_subaccount, actual_shop_subaccount
+                expected_subaccount, actual_subaccount
             ));
         }
 
-        // is memo valid
-        let expected_memo = Self::make_invoice_memo(invoice_id);
-        let actual_memo = transfer_txn.memo;
+        // is memo valid - a deposit subaccount already identifies the invoice, so no memo is required there
+        let expected_memo = Self::make_invoice_memo(&invoice.id);
+        let actual_memo = &transfer_txn.memo;
 
-        if expected_memo != actual_memo {
+        if invoice.deposit_subaccount.is_none() && Some(expected_memo) != *actual_memo {
             return Err(format!(
                 "Txn memo field doesn't match the invoice one: expected {:?}, actual {:?}",
                 expected_memo, actual_memo
             ));
         }
 
-        // check if the sum sent is enough to cover the invoice
-        let rate_eds = exchange_rate
-            .to_dynamic()
-            .to_decimals(transfer_txn.qty.decimals);
+        // each block pays for a single invoice only once
+        let consumed_block = (
+            transfer_txn.token_id,
+            u64::try_from(&block_idx.0)
+                .map_err(|_| format!("Invalid block index {}", block_idx))?,
+        );
+
+        if let Some(paid_invoice_id) = self.consumed_blocks.get(&consumed_block) {
+            return Err(format!(
+                "Block {} is already used to pay invoice {:?}",
+                block_idx, paid_invoice_id
+            ));
+        }
+
+        if transfer_txn.qty == EDs::zero(transfer_txn.qty.decimals) {
+            return Err("Empty transfer".to_string());
+        }
+
+        Ok(consumed_block)
+    }
+
+    /**
+     * Records a transfer made to an expired or cancelled invoice. It pays for nothing, so it is not valued in USD -
+     * the shop is expected to refund it with `refund_invoice`.
+     */
+    pub fn record_late_payment(
+        &mut self,
+        invoice_id: &InvoiceId,
+        transfer_txn: TransferTxn,
+        block_idx: Nat,
+        this_canister_id: Principal,
+        now: Timestamp,
+    ) -> Result<(Invoice, InvoicePayment), String> {
+        let mut invoice = self
+            .get(invoice_id)
+            .ok_or("Invoice not found".to_string())?;
+
+        if !matches!(
+            invoice.status,
+            InvoiceStatus::Expired { .. } | InvoiceStatus::Cancelled { .. }
+        ) {
+            return Err("Invalid invoice status".to_string());
+        }
+
+        let consumed_block =
+            self.validate_transfer(&invoice, &transfer_txn, &block_idx, this_canister_id)?;
+
+        let payment = InvoicePayment {
+            token_id: transfer_txn.token_id,
+            block_idx,
+            from: transfer_txn.from,
+            qty_usd: E8s::zero(),
+            exchange_rate: EDs::zero(transfer_txn.qty.decimals),
+            qty: transfer_txn.qty,
+            timestamp: now,
+        };
+
+        invoice
+            .late_payments
+            .get_or_insert_with(Vec::new)
+            .push(payment.clone());
+
+        self.consumed_blocks.insert(consumed_block, *invoice_id);
+
+        if let Some(deposit_subaccount) = invoice.deposit_subaccount {
+            self.schedule_deposit_sweep(
+                invoice_id,
+                invoice.shop_id,
+                payment.token_id,
+                deposit_subaccount,
+            );
+        }
 
-        let expected_qty_usd = invoice
+        self.all_invoices
+            .insert(*invoice_id, Candid(invoice.clone()));
+
+        Ok((invoice, payment))
+    }
+
+    /**
+     * Moves an invoice, which is not going to be paid anymore, into the inactive ones, so it is archived later
+     */
+    pub fn close(
+        &mut self,
+        invoice: &mut Invoice,
+        status: InvoiceStatus,
+        this_canister_id: Principal,
+    ) {
+        invoice.status = status;
+
+        self.deactivate(invoice, this_canister_id);
+        self.mark_inactive(invoice);
+        self.all_invoices
+            .insert(invoice.id, Candid(invoice.clone()));
+    }
+
+    /**
+     * Token-priced invoices are paid with exactly the token they are priced in.
+     * Returns the exchange rate implied by the USD estimate of the invoice.
+     */
+    fn token_priced_payment_rate(
+        invoice: &Invoice,
+        price: &TokenPrice,
+        transfer_txn: &TransferTxn,
+    ) -> Result<EDs, String> {
+        if transfer_txn.token_id != price.token_id {
+            return Err(format!(
+                "Invalid token: expected {}, actual {}",
+                price.token_id, transfer_txn.token_id
+            ));
+        }
+
+        let price_qty = price.qty.clone().to_decimals(transfer_txn.qty.decimals);
+        let qty_usd = invoice
             .qty_usd
             .clone()
             .to_dynamic()
-            .to_decimals(transfer_txn.qty.decimals);
+            .to_decimals(price_qty.decimals);
 
-        let actual_qty_usd = &rate_eds * &transfer_txn.qty;
+        Ok(&qty_usd / &price_qty)
+    }
 
-        let der = &expected_qty_usd / 100u64;
-        let min_actual_qty = &expected_qty_usd - &der;
+    /**
+     * Token-priced invoices should be paid in full, while USD-priced ones may be underpaid by the tolerance,
+     * recorded on the invoice at creation (the shop's setting, or the platform default) - to account for rounding of the exchange rates
+     */
+    fn is_covered(invoice: &Invoice) -> bool {
+        if let Some(price) = &invoice.token_price {
+            return Self::paid_token_qty(invoice, price.qty.decimals) >= price.qty;
+        }
 
-        if actual_qty_usd < min_actual_qty {
-            return Err(format!(
-                "Insufficient transfer: expected at least ${}, actual ${}",
-                min_actual_qty, actual_qty_usd
-            ));
+        // the tolerance for the token of the latest payment, which is the one to complete the invoice
+        let tolerance_bps = match invoice.payments.as_ref().and_then(|it| it.last()) {
+            Some(payment) => invoice
+                .underpayment_tolerance
+                .clone()
+                .unwrap_or_default()
+                .bps_for(&payment.token_id),
+            None => return false,
+        };
+
+        let expected_qty_usd = invoice.qty_usd.clone().to_dynamic();
+        let der = &(&expected_qty_usd * u64::from(tolerance_bps)) / 10_000u64;
+        let min_paid_qty_usd = &expected_qty_usd - &der;
+
+        Self::paid_qty_usd(invoice).to_dynamic() >= min_paid_qty_usd
+    }
+
+    /**
+     * Only the payment, which completes the invoice, can exceed it - the invoice was not covered before it
+     */
+    fn calc_overpayment(invoice: &Invoice, payment: &InvoicePayment) -> Option<Overpayment> {
+        let decimals = payment.qty.decimals;
+
+        let excess_qty = match &invoice.token_price {
+            Some(price) => {
+                let price_qty = price.qty.clone().to_decimals(decimals);
+                let paid_qty = Self::paid_token_qty(invoice, decimals);
+
+                if paid_qty <= price_qty {
+                    return None;
+                }
+
+                &paid_qty - &price_qty
+            }
+            None => {
+                let qty_usd = invoice.qty_usd.clone().to_dynamic().to_decimals(decimals);
+                let paid_qty_usd = Self::paid_qty_usd(invoice)
+                    .to_dynamic()
+                    .to_decimals(decimals);
+
+                if paid_qty_usd <= qty_usd {
+                    return None;
+                }
+
+                &(&paid_qty_usd - &qty_usd) / &payment.exchange_rate
+            }
+        };
+
+        let qty = if excess_qty > payment.qty {
+            payment.qty.clone()
+        } else {
+            excess_qty
+        };
+
+        if qty == EDs::zero(decimals) {
+            return None;
         }
 
-        invoice.status = InvoiceStatus::Paid {
-            timestamp: now,
-            token_id: transfer_txn.token_id,
-            qty: transfer_txn.qty,
-            exchange_rate: rate_eds,
+        Some(Overpayment {
+            token_id: payment.token_id,
+            qty_usd: (&qty * &payment.exchange_rate)
+                .to_decimals(8)
+                .to_const::<8>(),
+            qty,
+            status: OverpaymentStatus::Kept,
+        })
+    }
+
+    /**
+     * The smallest token amount, which `verify_payment` values at no less than `qty_usd` with this rate
+     */
+    pub fn token_qty_covering(qty_usd: &E8s, rate: &EDs) -> EDs {
+        let decimals = rate.decimals;
+        let ceil_div = |a: BigUint, b: &BigUint| (a + b - 1u32) / b;
+
+        // unlike `to_decimals`, rounds up
+        let qty_usd = if decimals >= 8 {
+            &qty_usd.val * EDs::base(decimals - 8)
+        } else {
+            ceil_div(qty_usd.val.clone(), EDs::base(8 - decimals))
         };
 
-        // delete the invoice from the list of active invoices (which is segregated by exchange rate used)
-        let active_invoices = self
-            .active_invoices
-            .get_mut(&invoice.exchange_rates_timestamp)
-            .unwrap();
+        EDs::new(ceil_div(qty_usd * EDs::base(decimals), &rate.val), decimals)
+    }
 
-        active_invoices.remove(invoice_id);
+    /**
+     * Sum of the payments of a token-priced invoice, all of which are in the same token
+     */
+    pub fn paid_token_qty(invoice: &Invoice, decimals: u8) -> EDs {
+        invoice
+            .payments
+            .iter()
+            .flatten()
+            .fold(EDs::zero(decimals), |acc, it| {
+                &acc + &it.qty.clone().to_decimals(decimals)
+            })
+    }
 
-        // move the invoice to paid list
-        self.inactive_invoices.insert(*invoice_id);
+    pub fn paid_qty_usd(invoice: &Invoice) -> E8s {
+        invoice.paid_qty_usd.clone().unwrap_or_else(E8s::zero)
+    }
+
+    /**
+     * What is left to refund of the payment. The excess of an overpayment, which is refunded automatically, is excluded.
+     */
+    pub fn refundable_qty(invoice: &Invoice, payment: &InvoicePayment) -> EDs {
+        let decimals = payment.qty.decimals;
+        let is_same_payment = |token_id: &TokenId, block_idx: &Nat| {
+            *token_id == payment.token_id && *block_idx == payment.block_idx
+        };
+
+        let mut refunded_qty = invoice
+            .refunds
+            .iter()
+            .flatten()
+            .filter(|it| is_same_payment(&it.token_id, &it.payment_block_idx))
+            .filter(|it| !matches!(it.status, InvoiceRefundStatus::Failed { .. }))
+            .fold(EDs::zero(decimals), |acc, it| {
+                &acc + &it.qty.clone().to_decimals(decimals)
+            });
+
+        // only the last payment, which completed the invoice, can be an overpayment
+        let is_last_payment = invoice
+            .payments
+            .as_ref()
+            .and_then(|it| it.last())
+            .map(|it| is_same_payment(&it.token_id, &it.block_idx))
+            .unwrap_or_default();
 
-        Ok((invoice.clone(), self.active_invoices.is_empty()))
+        if let (true, Some(overpayment)) = (is_last_payment, &invoice.overpayment) {
+            if matches!(
+                overpayment.status,
+                OverpaymentStatus::RefundPending { .. } | OverpaymentStatus::Refunded { .. }
+            ) {
+                refunded_qty = &refunded_qty + &overpayment.qty.clone().to_decimals(decimals);
+            }
+        }
+
+        if payment.qty > refunded_qty {
+            &payment.qty - &refunded_qty
+        } else {
+            EDs::zero(decimals)
+        }
     }
 
-    pub fn prepare_archive_batch(&mut self, size: usize) -> Vec<Invoice> {
-        let mut ids_to_archive = Vec::new();
+    pub fn add_refund(&mut self, invoice_id: &InvoiceId, refund: InvoiceRefund) -> Option<()> {
+        let mut invoice = self.get(invoice_id)?;
 
-        let mut i = 0;
-        for id in self.inactive_invoices.iter() {
-            if i == size {
-                break;
+        self.pending_refunds.insert(
+            refund.withdrawal_id,
+            PendingRefund {
+                invoice_id: *invoice_id,
+                qty_usd: refund.qty_usd.clone(),
+                volume_timestamp: Some(Self::closed_at(&invoice)),
+            },
+        );
+
+        invoice.refunds.get_or_insert_with(Vec::new).push(refund);
+        invoice.status = Self::refund_status(&invoice);
+
+        self.all_invoices.insert(*invoice_id, Candid(invoice));
+
+        Some(())
+    }
+
+    /**
+     * Returns the refund, if it was pending before. Works for archived invoices too - only the hub's copy of
+     * the invoice is updated then, if there is one.
+     */
+    pub fn complete_refund(
+        &mut self,
+        invoice_id: &InvoiceId,
+        withdrawal_id: WithdrawalId,
+        status: InvoiceRefundStatus,
+        supported_tokens: &SupportedTokensState,
+    ) -> Option<PendingRefund> {
+        let pending_refund = self.pending_refunds.remove(&withdrawal_id)?;
+
+        let Some(mut invoice) = self.get(invoice_id) else {
+            return Some(pending_refund);
+        };
+
+        if let Some(refund) = invoice
+            .refunds
+            .iter_mut()
+            .flatten()
+            .find(|it| it.withdrawal_id == withdrawal_id)
+        {
+            refund.status = status;
+        }
+
+        invoice.status = Self::refund_status(&invoice);
+
+        // the invoice was held back from archiving until now
+        if self.inactive_invoices.contains_key(invoice_id)
+            && !Self::is_held(&invoice, supported_tokens)
+        {
+            self.archive_queue
+                .insert((Self::closed_at(&invoice), *invoice_id), ());
+        }
+
+        self.all_invoices.insert(*invoice_id, Candid(invoice));
+
+        Some(pending_refund)
+    }
+
+    /**
+     * An invoice without refunds (or with failed ones only) is back to `Paid` by the payment, which completed it
+     */
+    fn refund_status(invoice: &Invoice) -> InvoiceStatus {
+        // refunds of partial or late payments don't change the status of an unpaid invoice
+        if Self::is_unpaid(invoice) {
+            return invoice.status.clone();
+        }
+
+        let payments = invoice.payments.as_deref().unwrap_or_default();
+        let last_refund_timestamp = invoice
+            .refunds
+            .iter()
+            .flatten()
+            .filter(|it| !matches!(it.status, InvoiceRefundStatus::Failed { .. }))
+            .map(|it| it.timestamp)
+            .max();
+
+        let timestamp = match (last_refund_timestamp, payments.last()) {
+            (Some(it), _) => it,
+            (None, Some(payment)) => {
+                return InvoiceStatus::Paid {
+                    timestamp: payment.timestamp,
+                    token_id: payment.token_id,
+                    qty: payment.qty.clone(),
+                    exchange_rate: payment.exchange_rate.clone(),
+                    block_idx: Some(payment.block_idx.clone()),
+                    from: payment.from,
+                }
+            }
+            (None, None) => return invoice.status.clone(),
+        };
+
+        let is_refunded = payments
+            .iter()
+            .all(|it| Self::refundable_qty(invoice, it) == EDs::zero(it.qty.decimals));
+
+        if is_refunded {
+            InvoiceStatus::Refunded { timestamp }
+        } else {
+            InvoiceStatus::PartiallyRefunded { timestamp }
+        }
+    }
+
+    pub fn set_overpayment_status(
+        &mut self,
+        invoice_id: &InvoiceId,
+        status: OverpaymentStatus,
+    ) -> Option<()> {
+        let mut invoice = self.get(invoice_id)?;
+        invoice.overpayment.as_mut()?.status = status;
+
+        self.all_invoices.insert(*invoice_id, Candid(invoice));
+
+        Some(())
+    }
+
+    /**
+     * Partial payments don't extend the lifetime of an invoice
+     */
+    pub fn is_expired(invoice: &Invoice, now: Timestamp) -> bool {
+        invoice.expires_at.is_some_and(|it| it <= now)
+    }
+
+    /**
+     * Expired and cancelled invoices pay for nothing - their payments (partial or late ones) are only to be refunded
+     */
+    pub fn is_unpaid(invoice: &Invoice) -> bool {
+        matches!(
+            invoice.status,
+            InvoiceStatus::Expired { .. } | InvoiceStatus::Cancelled { .. }
+        )
+    }
+
+    pub fn expired_ids(&self, now: Timestamp) -> Vec<InvoiceId> {
+        self.active_invoices_by_expiry
+            .range((Timestamp::MIN, [u8::MIN; 32])..=(now, [u8::MAX; 32]))
+            .map(|((_, id), _)| id)
+            .collect()
+    }
+
+    /**
+     * Deletes the invoice from the list of active invoices (which is segregated by exchange rate used) and its lookup indices
+     */
+    pub fn deactivate(&mut self, invoice: &Invoice, this_canister_id: Principal) {
+        self.active_invoices
+            .remove(&(invoice.exchange_rates_timestamp, invoice.id));
+
+        if let Some(expires_at) = invoice.expires_at {
+            self.active_invoices_by_expiry
+                .remove(&(expires_at, invoice.id));
+        }
+
+        self.active_invoices_by_memo
+            .remove(&Self::make_invoice_memo(&invoice.id));
+
+        if let Some(subaccount) = invoice.deposit_subaccount {
+            for address in Self::deposit_addresses(&this_canister_id, &subaccount) {
+                self.active_invoices_by_deposit_address.remove(&address);
+            }
+        }
+    }
+
+    pub fn pending_deposit_sweeps(&self) -> Vec<(InvoiceId, DepositSweep)> {
+        self.pending_deposit_sweeps.iter().collect()
+    }
+
+    /**
+     * Sweeps into the same subaccount are queued, so the ones in progress are never overwritten
+     */
+    fn schedule_deposit_sweep(
+        &mut self,
+        invoice_id: &InvoiceId,
+        shop_id: ShopId,
+        token_id: TokenId,
+        deposit_subaccount: [u8; 32],
+    ) {
+        let sweep = match self.pending_deposit_sweeps.get(invoice_id) {
+            Some(mut sweep) => {
+                let queued_token_ids = sweep.queued_token_ids.get_or_insert_with(Vec::new);
+                if !queued_token_ids.contains(&token_id) {
+                    queued_token_ids.push(token_id);
+                }
+
+                sweep
             }
+            None => DepositSweep {
+                shop_id,
+                token_id,
+                deposit_subaccount,
+                queued_token_ids: None,
+            },
+        };
 
-            ids_to_archive.push(*id);
+        self.pending_deposit_sweeps.insert(*invoice_id, sweep);
+    }
+
+    /**
+     * Moves on to the next queued token of the sweep, if there is one
+     */
+    pub fn complete_deposit_sweep(&mut self, invoice_id: &InvoiceId) {
+        let mut sweep = match self.pending_deposit_sweeps.remove(invoice_id) {
+            Some(it) => it,
+            None => return,
+        };
 
-            i += 1;
+        let mut queued_token_ids = sweep.queued_token_ids.take().unwrap_or_default();
+        if queued_token_ids.is_empty() {
+            return;
         }
 
+        sweep.token_id = queued_token_ids.remove(0);
+        sweep.queued_token_ids = Some(queued_token_ids).filter(|it| !it.is_empty());
+
+        self.pending_deposit_sweeps.insert(*invoice_id, sweep);
+    }
+
+    /**
+     * Takes up to `size` invoices, closed before the given time, out of the hub. Invoices, which are held,
+     * are only dropped from the archive queue - they get back into it, once their refunds are settled.
+     */
+    pub fn prepare_archive_batch(
+        &mut self,
+        size: usize,
+        closed_before: Timestamp,
+        supported_tokens: &SupportedTokensState,
+    ) -> Vec<Invoice> {
         let mut batch = Vec::new();
 
-        for id in ids_to_archive.iter() {
-            self.inactive_invoices.remove(id);
-            let invoice = self.all_invoices.remove(id).unwrap();
+        while batch.len() < size {
+            let Some(((closed_at, id), _)) = self.archive_queue.first_key_value() else {
+                break;
+            };
+
+            if closed_at > closed_before {
+                break;
+            }
+
+            self.archive_queue.remove(&(closed_at, id));
+
+            let Some(Candid(invoice)) = self.all_invoices.get(&id) else {
+                continue;
+            };
+
+            if Self::is_held(&invoice, supported_tokens) {
+                continue;
+            }
+
+            self.inactive_invoices.remove(&id);
+            self.all_invoices.remove(&id);
 
             batch.push(invoice);
         }
@@ -187,11 +989,73 @@ impl InvoicesState {
 
     pub fn reapply_archive_batch(&mut self, batch: Vec<Invoice>) {
         for invoice in batch {
-            self.inactive_invoices.insert(invoice.id);
-            self.all_invoices.insert(invoice.id, invoice);
+            self.mark_inactive(&invoice);
+            self.all_invoices.insert(invoice.id, Candid(invoice));
         }
     }
 
+    /**
+     * When the invoice was closed - refunds don't move it
+     */
+    pub fn closed_at(invoice: &Invoice) -> Timestamp {
+        match &invoice.status {
+            InvoiceStatus::Paid { timestamp, .. }
+            | InvoiceStatus::Expired { timestamp }
+            | InvoiceStatus::Cancelled { timestamp } => *timestamp,
+            _ => invoice
+                .payments
+                .as_ref()
+                .and_then(|it| it.last())
+                .map(|it| it.timestamp)
+                .unwrap_or(invoice.created_at),
+        }
+    }
+
+    /**
+     * Invoices with refunds still being sent, or with payments of an unpaid invoice, which can still be refunded, are not archived
+     */
+    pub fn is_held(invoice: &Invoice, supported_tokens: &SupportedTokensState) -> bool {
+        let has_pending_refunds = invoice
+            .refunds
+            .iter()
+            .flatten()
+            .any(|it| matches!(it.status, InvoiceRefundStatus::Pending));
+
+        let partial_payments = invoice
+            .payments
+            .iter()
+            .flatten()
+            .filter(|_| Self::is_unpaid(invoice));
+
+        // what is left of a payment can't be refunded, once it doesn't cover the transfer fee
+        let has_unrefunded_payments = invoice
+            .late_payments
+            .iter()
+            .flatten()
+            .chain(partial_payments)
+            .any(|payment| {
+                supported_tokens
+                    .get_by_id(&payment.token_id)
+                    .is_some_and(|token| Self::refundable_qty(invoice, payment) > token.fee)
+            });
+
+        has_pending_refunds || has_unrefunded_payments
+    }
+
+    fn mark_inactive(&mut self, invoice: &Invoice) {
+        self.inactive_invoices.insert(invoice.id, ());
+        self.archive_queue
+            .insert((Self::closed_at(invoice), invoice.id), ());
+    }
+
+    /**
+     * Puts an invoice of a prepared batch back, out of the way of the following batches
+     */
+    pub fn set_aside_unarchivable(&mut self, invoice: Invoice) {
+        self.unarchivable_invoices.insert(invoice.id, ());
+        self.all_invoices.insert(invoice.id, Candid(invoice));
+    }
+
     fn generate_id(&mut self, salt: &[u8]) -> InvoiceId {
         let mut hasher = sha2::Sha256::new();
 
@@ -202,7 +1066,101 @@ impl InvoicesState {
         hasher.finalize().into()
     }
 
-    fn make_invoice_memo(id: &InvoiceId) -> [u8; 32] {
+    fn validate_metadata(metadata: &InvoiceMetadata) -> Result<(), String> {
+        let check_len = |field: &str, value: &str, max: usize| {
+            if value.len() > max {
+                Err(format!(
+                    "Invalid invoice metadata: {} is longer than {} bytes",
+                    field, max
+                ))
+            } else {
+                Ok(())
+            }
+        };
+
+        if let Some(external_order_id) = &metadata.external_order_id {
+            if external_order_id.is_empty() {
+                return Err("Invalid invoice metadata: empty external order id".to_string());
+            }
+
+            check_len(
+                "external order id",
+                external_order_id,
+                MAX_EXTERNAL_ORDER_ID_LEN,
+            )?;
+        }
+
+        if let Some(description) = &metadata.description {
+            check_len("description", description, MAX_DESCRIPTION_LEN)?;
+        }
+
+        let line_items = metadata.line_items.as_deref().unwrap_or_default();
+        if line_items.len() > MAX_LINE_ITEMS {
+            return Err(format!(
+                "Invalid invoice metadata: more than {} line items",
+                MAX_LINE_ITEMS
+            ));
+        }
+
+        for item in line_items {
+            check_len("line item name", &item.name, MAX_SHORT_FIELD_LEN)?;
+            check_len(
+                "line item SKU",
+                item.sku.as_deref().unwrap_or_default(),
+                MAX_SHORT_FIELD_LEN,
+            )?;
+        }
+
+        let extra = metadata.extra.iter().flatten().collect::<Vec<_>>();
+        if extra.len() > MAX_EXTRA_ENTRIES {
+            return Err(format!(
+                "Invalid invoice metadata: more than {} extra entries",
+                MAX_EXTRA_ENTRIES
+            ));
+        }
+
+        for (key, value) in extra {
+            check_len("extra key", key, MAX_SHORT_FIELD_LEN)?;
+            check_len("extra value", value, MAX_DESCRIPTION_LEN)?;
+        }
+
+        Ok(())
+    }
+
+    fn make_external_order_key(external_order_id: &str) -> [u8; 32] {
+        let mut hasher = sha2::Sha256::new();
+
+        hasher.update(EXTERNAL_ORDER_ID_DOMAIN);
+        hasher.update(external_order_id.as_bytes());
+
+        hasher.finalize().into()
+    }
+
+    fn make_idempotency_key(shop_id: ShopId, caller: Principal, key: &str) -> [u8; 32] {
+        let mut hasher = sha2::Sha256::new();
+
+        hasher.update(IDEMPOTENCY_KEY_DOMAIN);
+        hasher.update(shop_id.to_le_bytes());
+        hasher.update(caller.as_slice());
+        hasher.update(key.as_bytes());
+
+        hasher.finalize().into()
+    }
+
+    fn deposit_addresses(owner: &Principal, deposit_subaccount: &[u8; 32]) -> [[u8; 32]; 2] {
+        [
+            *deposit_subaccount,
+            AccountIdentifier::new(owner, deposit_subaccount).0,
+        ]
+    }
+
+    pub fn make_legacy_invoice_memo(id: &InvoiceId) -> u64 {
+        let memo = Self::make_invoice_memo(id);
+
+        u64::from_be_bytes(memo[..8].try_into().unwrap())
+    }
+
+    pub fn make_invoice_memo(id: &InvoiceId) -> [u8; 32] {
         let mut hasher = sha2::Sha256::new();
 
         hasher.update(MEMO_GENERATION_DOMAIN);
@@ -211,3 +1169,132 @@ impl InvoicesState {
         hasher.finalize().into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use candid::{Nat, Principal};
+    use ic_e8s::{c::E8s, d::EDs};
+    use ic_stable_structures::{memory_manager::MemoryManager, DefaultMemoryImpl};
+    use msq_pay_types::{InvoicePayment, InvoiceStatus, RateLockPolicy, UnderpaymentTolerance};
+
+    use crate::{invoices::types::NewInvoice, payment_hub::state::State};
+
+    use super::InvoicesState;
+
+    const QTY_USD: u64 = 100_0000_0000;
+
+    fn invoice_paid_with(tolerance_bps: u16, paid_qty_usd: u64) -> msq_pay_types::Invoice {
+        let mut state = State::init(&MemoryManager::init(DefaultMemoryImpl::default()));
+
+        let id = state
+            .invoices
+            .create(
+                NewInvoice {
+                    shop_id: 0,
+                    creator: Principal::anonymous(),
+                    qty_usd: E8s::from(QTY_USD),
+                    token_price: None,
+                    fiat_price: None,
+                    exchange_rates_timestamp: 0,
+                    expires_at: 100,
+                    rate_lock_policy: RateLockPolicy::LockAtCreation,
+                    underpayment_tolerance: UnderpaymentTolerance {
+                        bps: tolerance_bps,
+                        by_token: None,
+                    },
+                    metadata: None,
+                    idempotency_key: None,
+                    deposit_account_owner: None,
+                },
+                0,
+            )
+            .unwrap();
+
+        let mut invoice = state.invoices.get(&id).unwrap();
+        invoice.payments = Some(vec![InvoicePayment {
+            token_id: Principal::anonymous(),
+            block_idx: Nat::from(0u64),
+            from: None,
+            qty: EDs::new(paid_qty_usd.into(), 8),
+            qty_usd: E8s::from(paid_qty_usd),
+            exchange_rate: EDs::new(1_0000_0000u64.into(), 8),
+            timestamp: 1,
+        }]);
+        invoice.paid_qty_usd = Some(E8s::from(paid_qty_usd));
+
+        invoice
+    }
+
+    #[test]
+    fn zero_tolerance_rejects_one_unit_short_payments() {
+        assert!(!InvoicesState::is_covered(&invoice_paid_with(
+            0,
+            QTY_USD - 1
+        )));
+        assert!(InvoicesState::is_covered(&invoice_paid_with(0, QTY_USD)));
+    }
+
+    #[test]
+    fn default_tolerance_accepts_payments_short_by_one_percent() {
+        assert!(InvoicesState::is_covered(&invoice_paid_with(
+            100,
+            QTY_USD - QTY_USD / 100
+        )));
+        assert!(!InvoicesState::is_covered(&invoice_paid_with(
+            100,
+            QTY_USD - QTY_USD / 100 - 1
+        )));
+    }
+
+    #[test]
+    fn token_qty_covering_rounds_up() {
+        // a rate that doesn't divide the remaining amount evenly, for tokens with fewer and more decimals than USD
+        for decimals in [6u8, 8, 18] {
+            let rate = E8s::from(3_0000_0001u64).to_dynamic().to_decimals(decimals);
+            let qty_usd = E8s::from(QTY_USD - 1);
+
+            let qty = InvoicesState::token_qty_covering(&qty_usd, &rate);
+            let valued = |qty: &EDs| (&rate * qty).to_decimals(8).to_const::<8>();
+
+            assert!(valued(&qty) >= qty_usd);
+            assert!(valued(&EDs::new(&qty.val - 1u32, decimals)) < qty_usd);
+        }
+    }
+
+    #[test]
+    fn created_status_reports_the_lifetime_in_minutes() {
+        let mut state = State::init(&MemoryManager::init(DefaultMemoryImpl::default()));
+        let minute = 60_000_000_000u64;
+
+        for (lifetime, expected_ttl) in [(20 * minute, 20u8), (30 * 24 * 60 * minute, u8::MAX)] {
+            let id = state
+                .invoices
+                .create(
+                    NewInvoice {
+                        shop_id: 0,
+                        creator: Principal::anonymous(),
+                        qty_usd: E8s::from(QTY_USD),
+                        token_price: None,
+                        fiat_price: None,
+                        exchange_rates_timestamp: 0,
+                        expires_at: minute + lifetime,
+                        rate_lock_policy: RateLockPolicy::LockAtCreation,
+                        underpayment_tolerance: UnderpaymentTolerance {
+                            bps: 0,
+                            by_token: None,
+                        },
+                        metadata: None,
+                        idempotency_key: None,
+                        deposit_account_owner: None,
+                    },
+                    minute,
+                )
+                .unwrap();
+
+            assert!(matches!(
+                state.invoices.get(&id).unwrap().status,
+                InvoiceStatus::Created { ttl } if ttl == expected_ttl
+            ));
+        }
+    }
+}
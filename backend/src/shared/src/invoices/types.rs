@@ -0,0 +1,59 @@
+use candid::{CandidType, Principal};
+use ic_e8s::c::E8s;
+use msq_pay_types::{
+    FiatPrice, InvoiceId, InvoiceMetadata, RateLockPolicy, TokenPrice, UnderpaymentTolerance,
+};
+use serde::Deserialize;
+
+use crate::{
+    impl_candid_storable,
+    utils::{ShopId, Timestamp, TokenId},
+};
+
+/**
+ * Everything an invoice is created from, except what the invoices state generates by itself
+ */
+pub struct NewInvoice {
+    pub shop_id: ShopId,
+    pub creator: Principal,
+    pub qty_usd: E8s,
+    pub token_price: Option<TokenPrice>,
+    pub fiat_price: Option<FiatPrice>,
+    pub exchange_rates_timestamp: Timestamp,
+    pub expires_at: Timestamp,
+    pub rate_lock_policy: RateLockPolicy,
+    pub underpayment_tolerance: UnderpaymentTolerance,
+    pub metadata: Option<InvoiceMetadata>,
+    // the idempotency key of the request and until when it is remembered
+    pub idempotency_key: Option<(String, Timestamp)>,
+    // if set, the invoice gets its own deposit subaccount of this principal
+    pub deposit_account_owner: Option<Principal>,
+}
+
+/**
+ * Funds of invoice payments, which are still in the deposit subaccount of the invoice and should be moved to the shop subaccount
+ */
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DepositSweep {
+    pub shop_id: ShopId,
+    pub token_id: TokenId,
+    pub deposit_subaccount: [u8; 32],
+    // tokens of later payments into the same subaccount - these are swept one after another
+    pub queued_token_ids: Option<Vec<TokenId>>,
+}
+
+impl_candid_storable!(DepositSweep);
+
+/**
+ * A refund, which is still being sent. Kept apart from the invoice, so it could be settled even if the invoice is archived.
+ */
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PendingRefund {
+    pub invoice_id: InvoiceId,
+    pub qty_usd: E8s,
+    // when the invoice was paid - the refund is taken out of that month's fee volume until it fails.
+    // Not set for refunds started before the volume was adjusted by them.
+    pub volume_timestamp: Option<Timestamp>,
+}
+
+impl_candid_storable!(PendingRefund);
@@ -3,14 +3,21 @@ use env::{CAN_INVOICE_HISTORY_CANISTER_ID, CAN_PAYMENT_HUB_CANISTER_ID, CAN_ROOT
 use lazy_static::lazy_static;
 use serde::Deserialize;
 
+pub mod admins;
+pub mod balances;
+pub mod block_scanner;
 mod env;
 pub mod exchange_rates;
+pub mod fees;
+pub mod icp_ledger;
+pub mod invoice_archives;
 pub mod invoice_history;
 pub mod invoices;
 pub mod payment_hub;
 pub mod shops;
 pub mod supported_tokens;
 pub mod utils;
+pub mod withdrawals;
 
 lazy_static! {
     pub static ref ENV_VARS: EnvVarsState = EnvVarsState::new();
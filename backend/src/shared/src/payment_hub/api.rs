@@ -0,0 +1,20 @@
+use candid::CandidType;
+use icrc_ledger_types::icrc1::account::Account;
+use serde::Deserialize;
+
+#[derive(CandidType, Deserialize)]
+pub struct GetPlatformSettingsRequest {}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetPlatformSettingsResponse {
+    pub fee_collector_account: Option<Account>,
+    pub should_mock_exchange_rates: bool,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SetFeeCollectorAccountRequest {
+    pub fee_collector_account: Option<Account>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SetFeeCollectorAccountResponse {}
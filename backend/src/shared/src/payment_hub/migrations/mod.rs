@@ -0,0 +1,633 @@
+use std::fmt::Display;
+
+use msq_pay_types::{InvoiceRefundStatus, InvoiceStatus};
+
+use crate::{
+    invoices::{state::InvoicesState, types::PendingRefund},
+    utils::{Candid, DEFAULT_INVOICE_TTL_NS},
+};
+
+use self::v0::StateV0;
+
+use super::state::State;
+
+pub mod v0;
+
+/**
+ * Version of the state layout this code works with. Each time a stored type changes in a
+ * non-backward-compatible way, this should be bumped, the old types frozen in a `vN` module and
+ * a migration step from the previous version added to `migrate`.
+ *
+ * v0 - the whole state is saved with `stable_save` as a single candid blob
+ * v1 - shops, invoices and exchange rates live in stable structures
+ * v2 - active invoices are indexed by their transfer memo
+ * v3 - blocks applied to invoices are tracked, so none of them could be used twice
+ * v4 - active invoices expire at a wall-clock time instead of a timer tick and are indexed by it
+ * v5 - inactive invoices are queued for archiving by the time they were closed, pending refunds are tracked apart
+ * v6 - partially paid invoices expire too
+ * v7 - shops are counted by their pricing currency
+ */
+pub const STATE_VERSION: u32 = 7;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub from_version: u32,
+    pub to_version: u32,
+    pub migrations: Vec<&'static str>,
+}
+
+impl Display for MigrationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.migrations.is_empty() {
+            write!(
+                f,
+                "State is up to date (v{}), no migrations ran",
+                self.to_version
+            )
+        } else {
+            write!(
+                f,
+                "State migrated from v{} to v{}: {}",
+                self.from_version,
+                self.to_version,
+                self.migrations.join(", ")
+            )
+        }
+    }
+}
+
+/**
+ * Brings the stored state up to `STATE_VERSION`, step by step, and loads its heap part.
+ * `v0_opt` is the state restored with `stable_restore`, if the stable memory still had the v0 layout.
+ */
+pub fn migrate(state: &mut State, v0_opt: Option<StateV0>) -> Result<MigrationReport, String> {
+    let from_version = if v0_opt.is_some() { 0 } else { state.version() };
+
+    if from_version > STATE_VERSION {
+        return Err(format!(
+            "Unable to downgrade the state from v{} to v{}",
+            from_version, STATE_VERSION
+        ));
+    }
+
+    let mut migrations = Vec::new();
+
+    if let Some(v0) = v0_opt {
+        migrate_v0_to_v1(state, v0);
+        migrations.push("v0 -> v1 (stable_save blob to stable structures)");
+    }
+
+    if from_version < 2 {
+        migrate_v1_to_v2(state);
+        migrations.push("v1 -> v2 (index active invoices by memo)");
+    }
+
+    if from_version < 3 {
+        migrate_v2_to_v3(state);
+        migrations.push("v2 -> v3 (track consumed blocks)");
+    }
+
+    if from_version < 4 {
+        migrate_v3_to_v4(state);
+        migrations.push("v3 -> v4 (index active invoices by expiry)");
+    }
+
+    if from_version < 5 {
+        migrate_v4_to_v5(state);
+        migrations.push("v4 -> v5 (queue inactive invoices for archiving, track pending refunds)");
+    }
+
+    if from_version < 6 {
+        migrate_v5_to_v6(state);
+        migrations.push("v5 -> v6 (index partially paid invoices by expiry)");
+    }
+
+    if from_version < 7 {
+        migrate_v6_to_v7(state);
+        migrations.push("v6 -> v7 (count shops by pricing currency)");
+    }
+
+    state.set_version(STATE_VERSION);
+    state.load_heap_state();
+
+    Ok(MigrationReport {
+        from_version,
+        to_version: STATE_VERSION,
+        migrations,
+    })
+}
+
+fn migrate_v0_to_v1(state: &mut State, v0: StateV0) {
+    state
+        .shops
+        .shop_id_generator
+        .set(v0.shops.shop_id_generator)
+        .expect("Unable to migrate shop id generator");
+
+    for (id, shop) in v0.shops.shops {
+        state.shops.shops.insert(id, shop.into());
+    }
+
+    for (owner, ids) in v0.shops.owner_to_shops {
+        for id in ids {
+            state.shops.owner_to_shops.insert((owner, id), ());
+        }
+    }
+
+    for (referral, shops) in v0.shops.referral_to_shops {
+        for (id, earnings) in shops {
+            state
+                .shops
+                .referral_to_shops
+                .insert((referral, id), Candid(earnings));
+        }
+    }
+
+    for (id, invoice) in v0.invoices.all_invoices {
+        state
+            .invoices
+            .all_invoices
+            .insert(id, Candid(invoice.into()));
+    }
+
+    for (exchange_rates_timestamp, ids) in v0.invoices.active_invoices {
+        for id in ids {
+            state
+                .invoices
+                .active_invoices
+                .insert((exchange_rates_timestamp, id), ());
+        }
+    }
+
+    for id in v0.invoices.inactive_invoices {
+        state.invoices.inactive_invoices.insert(id, ());
+    }
+
+    state
+        .invoices
+        .total_processed_in_usd
+        .set(Candid(v0.invoices.total_processed_in_usd))
+        .expect("Unable to migrate total processed");
+
+    state.exchange_rates.set_should_mock(v0.exchange_rates.mock);
+    state
+        .exchange_rates
+        .set_last_updated_at(v0.exchange_rates.last_updated_at);
+
+    for (timestamp, rates) in v0.exchange_rates.rates {
+        state.exchange_rates.rates.insert(timestamp, Candid(rates));
+    }
+
+    state.supported_tokens = v0.supported_tokens.into();
+    state.fee_collector_account = v0.fee_collector_account;
+    state.invoice_archives = v0.invoice_archives.map(|it| it.into()).unwrap_or_default();
+
+    // the heap part is loaded back from stable memory in the end of the migration
+    state.save_heap_state();
+}
+
+fn migrate_v1_to_v2(state: &mut State) {
+    let active_ids = state
+        .invoices
+        .active_invoices
+        .iter()
+        .map(|((_, id), _)| id)
+        .collect::<Vec<_>>();
+
+    for id in active_ids {
+        state
+            .invoices
+            .active_invoices_by_memo
+            .insert(InvoicesState::make_invoice_memo(&id), id);
+    }
+}
+
+fn migrate_v2_to_v3(state: &mut State) {
+    let consumed_blocks = state
+        .invoices
+        .all_invoices
+        .iter()
+        .flat_map(|(id, invoice)| {
+            invoice
+                .0
+                .payments
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(move |it| {
+                    Some(((it.token_id, u64::try_from(&it.block_idx.0).ok()?), id))
+                })
+        })
+        .collect::<Vec<_>>();
+
+    for (block, id) in consumed_blocks {
+        state.invoices.consumed_blocks.insert(block, id);
+    }
+}
+
+// the tick counter let invoices live for up to 20 minutes, which is the default lifetime now
+fn migrate_v3_to_v4(state: &mut State) {
+    let active_ids = state
+        .invoices
+        .active_invoices
+        .iter()
+        .map(|((_, id), _)| id)
+        .collect::<Vec<_>>();
+
+    for id in active_ids {
+        let Candid(mut invoice) = state.invoices.all_invoices.get(&id).unwrap();
+
+        if invoice.expires_at.is_some() {
+            continue;
+        }
+
+        let expires_at = invoice.created_at + DEFAULT_INVOICE_TTL_NS;
+        invoice.expires_at = Some(expires_at);
+
+        if invoice.payments.is_none() {
+            state
+                .invoices
+                .active_invoices_by_expiry
+                .insert((expires_at, id), ());
+        }
+
+        state.invoices.all_invoices.insert(id, Candid(invoice));
+    }
+}
+
+fn migrate_v4_to_v5(state: &mut State) {
+    let mut queued = Vec::new();
+    let mut pending_refunds = Vec::new();
+
+    for (id, Candid(invoice)) in state.invoices.all_invoices.iter() {
+        for refund in invoice.refunds.iter().flatten() {
+            if matches!(refund.status, InvoiceRefundStatus::Pending) {
+                pending_refunds.push((
+                    refund.withdrawal_id,
+                    PendingRefund {
+                        invoice_id: id,
+                        qty_usd: refund.qty_usd.clone(),
+                        volume_timestamp: None,
+                    },
+                ));
+            }
+        }
+
+        // held ones are dropped from the queue by the first archiving run - the supported tokens are not loaded yet
+        if state.invoices.inactive_invoices.contains_key(&id) {
+            queued.push((InvoicesState::closed_at(&invoice), id));
+        }
+    }
+
+    for key in queued {
+        state.invoices.archive_queue.insert(key, ());
+    }
+
+    for (withdrawal_id, refund) in pending_refunds {
+        state.invoices.pending_refunds.insert(withdrawal_id, refund);
+    }
+}
+
+// these were kept active forever before, the first expiry run after the upgrade expires the abandoned ones
+fn migrate_v5_to_v6(state: &mut State) {
+    let active_ids = state
+        .invoices
+        .active_invoices
+        .iter()
+        .map(|((_, id), _)| id)
+        .collect::<Vec<_>>();
+
+    for id in active_ids {
+        let Candid(invoice) = state.invoices.all_invoices.get(&id).unwrap();
+
+        if let (InvoiceStatus::PartiallyPaid, Some(expires_at)) =
+            (&invoice.status, invoice.expires_at)
+        {
+            state
+                .invoices
+                .active_invoices_by_expiry
+                .insert((expires_at, id), ());
+        }
+    }
+}
+
+fn migrate_v6_to_v7(state: &mut State) {
+    let currencies = state
+        .shops
+        .shops
+        .iter()
+        .filter_map(|(_, shop)| shop.pricing_currency)
+        .collect::<Vec<_>>();
+
+    for currency in currencies {
+        state.shops.count_pricing_currency(None, Some(currency));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::{decode_args, Principal};
+    use ic_e8s::c::E8s;
+    use ic_stable_structures::{memory_manager::MemoryManager, DefaultMemoryImpl};
+    use msq_pay_types::InvoiceStatus;
+
+    use crate::{
+        exchange_rates::types::Ticker,
+        invoices::state::InvoicesState,
+        payment_hub::state::State,
+        utils::{Candid, DEFAULT_INVOICE_TTL_NS},
+    };
+
+    use super::{migrate, v0::StateV0, STATE_VERSION};
+
+    // both snapshots are `stable_save`-d states, populated with two shops (one referred), an active and a paid invoice,
+    // two exchange rates records and a single supported token
+    const STATE_V0_SNAPSHOT: &[u8] = include_bytes!("snapshots/state_v0.bin");
+    const STATE_V0_WITH_ARCHIVES_SNAPSHOT: &[u8] =
+        include_bytes!("snapshots/state_v0_with_archives.bin");
+
+    fn empty_state() -> State {
+        State::init(&MemoryManager::init(DefaultMemoryImpl::default()))
+    }
+
+    fn migrate_snapshot(snapshot: &[u8]) -> State {
+        let (v0,): (StateV0,) = decode_args(snapshot).expect("Unable to decode the snapshot");
+
+        let mut state = empty_state();
+        let report = migrate(&mut state, Some(v0)).unwrap();
+
+        assert_eq!(report.from_version, 0);
+        assert_eq!(report.to_version, STATE_VERSION);
+        assert_eq!(report.migrations.len(), STATE_VERSION as usize);
+
+        state
+    }
+
+    fn assert_snapshot_content(state: &State) {
+        let owner = Principal::from_text("aaaaa-aa").unwrap();
+        let referral = Principal::management_canister();
+
+        assert_eq!(state.version(), STATE_VERSION);
+
+        assert_eq!(*state.shops.shop_id_generator.get(), 2);
+        assert_eq!(state.shops.get_shops_by_owner(&owner).len(), 2);
+
+        let shop = state.shops.get_shop(&0).unwrap();
+        assert_eq!(shop.referral, Some(referral));
+        assert_eq!(shop.total_earned_usd, E8s::from(500u64));
+
+        let referred = state.shops.get_shops_by_referral(&referral);
+        assert_eq!(referred.len(), 1);
+        assert_eq!(referred[0].referral_earnings_usd, E8s::from(5u64));
+
+        assert_eq!(state.invoices.all_invoices.len(), 2);
+        assert_eq!(state.invoices.active_invoices.len(), 1);
+        assert_eq!(state.invoices.inactive_invoices.len(), 1);
+        assert!(state.invoices.has_active_invoices(200));
+        assert!(!state.invoices.has_active_invoices(100));
+
+        let ((_, active_id), _) = state.invoices.active_invoices.first_key_value().unwrap();
+        let active = state.invoices.get(&active_id).unwrap();
+        assert!(matches!(active.status, InvoiceStatus::Created { ttl: 1 }));
+        assert_eq!(active.shop_id, 0);
+
+        assert_eq!(state.invoices.active_invoices_by_memo.len(), 1);
+        assert_eq!(state.invoices.active_invoices_by_expiry.len(), 1);
+        assert_eq!(
+            active.expires_at,
+            Some(active.created_at + DEFAULT_INVOICE_TTL_NS)
+        );
+        assert_eq!(
+            state
+                .invoices
+                .get_active_by_memo(&InvoicesState::make_invoice_memo(&active_id)),
+            Some(active_id)
+        );
+
+        let (paid_id, _) = state.invoices.inactive_invoices.first_key_value().unwrap();
+        assert_eq!(
+            state.invoices.archive_queue.first_key_value(),
+            Some(((400, paid_id), ()))
+        );
+        let paid = state.invoices.get(&paid_id).unwrap();
+        assert!(matches!(
+            paid.status,
+            InvoiceStatus::Paid { timestamp: 400, .. }
+        ));
+        assert_eq!(paid.qty_usd, E8s::from(2_000u64));
+
+        let icp = Ticker::from("ICP");
+        assert!(state.exchange_rates.should_mock());
+        assert_eq!(state.exchange_rates.last_updated_at(), 200);
+        assert_eq!(
+            state.exchange_rates.get_exchange_rate(&100, &icp),
+            E8s::from(1_000_000_000u64)
+        );
+        assert_eq!(
+            state.exchange_rates.get_rates(150).unwrap().get(&icp),
+            Some(&E8s::from(1_000_000_000u64))
+        );
+        assert_eq!(
+            state.exchange_rates.get_current_rates().get(&icp),
+            Some(&E8s::from(1_100_000_000u64))
+        );
+
+        assert!(state.supported_tokens.contains_ticker("ICP"));
+        assert_eq!(state.supported_tokens.get().count(), 1);
+
+        let fee_collector = state.fee_collector_account.unwrap();
+        assert_eq!(fee_collector.owner, owner);
+        assert_eq!(fee_collector.subaccount, Some([7u8; 32]));
+    }
+
+    #[test]
+    fn decodes_and_migrates_v0_snapshot() {
+        let state = migrate_snapshot(STATE_V0_SNAPSHOT);
+
+        assert_snapshot_content(&state);
+        assert!(state.invoice_archives.archives.is_empty());
+    }
+
+    #[test]
+    fn decodes_and_migrates_v0_snapshot_with_archives() {
+        let state = migrate_snapshot(STATE_V0_WITH_ARCHIVES_SNAPSHOT);
+
+        assert_snapshot_content(&state);
+        assert!(state.invoice_archives.archive_wasm.is_none());
+        assert!(!state.invoice_archives.is_spawning);
+    }
+
+    #[test]
+    fn heap_state_survives_reload_after_migration() {
+        let mut state = migrate_snapshot(STATE_V0_SNAPSHOT);
+
+        state.fee_collector_account = None;
+        state.supported_tokens = Default::default();
+        state.load_heap_state();
+
+        assert_snapshot_content(&state);
+    }
+
+    #[test]
+    fn up_to_date_state_runs_no_migrations() {
+        let mut state = empty_state();
+        state.set_version(STATE_VERSION);
+
+        let report = migrate(&mut state, None).unwrap();
+
+        assert_eq!(report.from_version, STATE_VERSION);
+        assert!(report.migrations.is_empty());
+    }
+
+    #[test]
+    fn v1_state_gets_active_invoices_indexed_by_memo() {
+        let mut state = migrate_snapshot(STATE_V0_SNAPSHOT);
+
+        state.invoices.active_invoices_by_memo.clear_new();
+        state.set_version(1);
+
+        let report = migrate(&mut state, None).unwrap();
+
+        assert_eq!(report.from_version, 1);
+        assert_eq!(report.migrations.len(), (STATE_VERSION - 1) as usize);
+        assert_snapshot_content(&state);
+    }
+
+    #[test]
+    fn v2_state_gets_consumed_blocks_tracked() {
+        let mut state = migrate_snapshot(STATE_V0_SNAPSHOT);
+        state.set_version(2);
+
+        let report = migrate(&mut state, None).unwrap();
+
+        assert_eq!(report.from_version, 2);
+        assert_eq!(report.migrations.len(), (STATE_VERSION - 2) as usize);
+        assert_snapshot_content(&state);
+        // v0 invoices don't keep their payments
+        assert!(state.invoices.consumed_blocks.is_empty());
+    }
+
+    #[test]
+    fn v3_state_gets_active_invoices_indexed_by_expiry() {
+        let mut state = migrate_snapshot(STATE_V0_SNAPSHOT);
+
+        let ((_, active_id), _) = state.invoices.active_invoices.first_key_value().unwrap();
+        let mut active = state.invoices.get(&active_id).unwrap();
+        active.expires_at = None;
+
+        state
+            .invoices
+            .all_invoices
+            .insert(active_id, Candid(active));
+        state.invoices.active_invoices_by_expiry.clear_new();
+        state.set_version(3);
+
+        let report = migrate(&mut state, None).unwrap();
+
+        assert_eq!(report.from_version, 3);
+        assert_eq!(report.migrations.len(), (STATE_VERSION - 3) as usize);
+        assert_snapshot_content(&state);
+    }
+
+    #[test]
+    fn v4_state_gets_inactive_invoices_queued_for_archiving() {
+        let mut state = migrate_snapshot(STATE_V0_SNAPSHOT);
+        state.invoices.archive_queue.clear_new();
+        state.set_version(4);
+
+        let report = migrate(&mut state, None).unwrap();
+
+        assert_eq!(report.from_version, 4);
+        assert_eq!(report.migrations.len(), (STATE_VERSION - 4) as usize);
+        assert_snapshot_content(&state);
+        assert!(state.invoices.pending_refunds.is_empty());
+
+        // the paid invoice is archived, once the refund window has passed
+        assert!(state.prepare_archive_batch(10, 400).is_empty());
+
+        let refund_window_ns = state.invoice_archives.config.refund_window_ns();
+        let batch = state.prepare_archive_batch(10, 400 + refund_window_ns);
+
+        assert_eq!(batch.len(), 1);
+        assert!(state.invoices.inactive_invoices.is_empty());
+        assert!(state.invoices.archive_queue.is_empty());
+    }
+
+    #[test]
+    fn v5_state_gets_partially_paid_invoices_indexed_by_expiry() {
+        let mut state = migrate_snapshot(STATE_V0_SNAPSHOT);
+
+        let ((_, active_id), _) = state.invoices.active_invoices.first_key_value().unwrap();
+        let mut active = state.invoices.get(&active_id).unwrap();
+        active.status = InvoiceStatus::PartiallyPaid;
+        let expires_at = active.expires_at.unwrap();
+
+        state
+            .invoices
+            .all_invoices
+            .insert(active_id, Candid(active));
+        state.invoices.active_invoices_by_expiry.clear_new();
+        state.set_version(5);
+
+        let report = migrate(&mut state, None).unwrap();
+
+        assert_eq!(report.from_version, 5);
+        assert_eq!(report.migrations.len(), (STATE_VERSION - 5) as usize);
+        assert!(state
+            .invoices
+            .active_invoices_by_expiry
+            .contains_key(&(expires_at, active_id)));
+        assert_eq!(state.invoices.expired_ids(expires_at), vec![active_id]);
+    }
+
+    #[test]
+    fn v6_state_gets_shops_counted_by_pricing_currency() {
+        let mut state = migrate_snapshot(STATE_V0_SNAPSHOT);
+
+        for id in [0, 1] {
+            let mut shop = state.shops.get_shop(&id).unwrap();
+            shop.pricing_currency = Some(String::from("EUR"));
+            state.shops.shops.insert(id, shop);
+        }
+        state.shops.shops_by_pricing_currency.clear_new();
+        state.set_version(6);
+
+        let report = migrate(&mut state, None).unwrap();
+
+        assert_eq!(report.from_version, 6);
+        assert_eq!(report.migrations.len(), (STATE_VERSION - 6) as usize);
+        assert_eq!(
+            state
+                .shops
+                .shops_by_pricing_currency
+                .get(&String::from("EUR")),
+            Some(2)
+        );
+
+        // a shop moving to another currency moves its count with it
+        state
+            .shops
+            .set_pricing_currency(&0, String::from("GBP"))
+            .unwrap();
+        assert_eq!(
+            state.shops.pricing_currencies(),
+            [String::from("EUR"), String::from("GBP")].into()
+        );
+
+        state
+            .shops
+            .set_pricing_currency(&1, String::from("GBP"))
+            .unwrap();
+        assert_eq!(
+            state.shops.pricing_currencies(),
+            [String::from("GBP")].into()
+        );
+    }
+
+    #[test]
+    fn newer_state_is_rejected() {
+        let mut state = empty_state();
+        state.set_version(STATE_VERSION + 1);
+
+        assert!(migrate(&mut state, None).is_err());
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use candid::{CandidType, Principal};
+use ic_e8s::{c::E8s, d::EDs};
+use icrc_ledger_types::icrc1::account::Account;
+use msq_pay_types::{Invoice, InvoiceId, InvoiceStatus};
+use serde::Deserialize;
+
+use crate::{
+    exchange_rates::types::Ticker,
+    invoice_archives::{
+        state::InvoiceArchivesState,
+        types::{ArchiveInfo, ArchivingConfig},
+    },
+    shops::types::Shop,
+    supported_tokens::{state::SupportedTokensState, types::Token},
+    utils::{ShopId, Timestamp, TokenId},
+};
+
+/**
+ * Frozen copy of the state layout, which was saved with `stable_save` as a single candid blob.
+ * These types should never change - they are only used to decode old states and migrate them.
+ */
+#[derive(CandidType, Deserialize)]
+pub struct StateV0 {
+    pub shops: ShopsStateV0,
+    pub invoices: InvoicesStateV0,
+    pub supported_tokens: SupportedTokensStateV0,
+    pub exchange_rates: ExchangeRatesStateV0,
+    pub fee_collector_account: Option<Account>,
+    // only present in states saved after invoice archiving was introduced
+    pub invoice_archives: Option<InvoiceArchivesStateV0>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ShopsStateV0 {
+    pub shop_id_generator: ShopId,
+    pub shops: BTreeMap<ShopId, ShopV0>,
+    pub owner_to_shops: BTreeMap<Principal, BTreeSet<ShopId>>,
+    pub referral_to_shops: BTreeMap<Principal, BTreeMap<ShopId, E8s>>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ShopV0 {
+    pub id: ShopId,
+    pub owner: Principal,
+    pub invoice_creators: BTreeSet<Principal>,
+    pub name: String,
+    pub description: String,
+    pub icon_base64: String,
+    pub referral: Option<Principal>,
+    pub total_earned_usd: E8s,
+}
+
+impl From<ShopV0> for Shop {
+    fn from(it: ShopV0) -> Self {
+        Self {
+            id: it.id,
+            owner: it.owner,
+            invoice_creators: it.invoice_creators,
+            name: it.name,
+            description: it.description,
+            icon_base64: it.icon_base64,
+            referral: it.referral,
+            total_earned_usd: it.total_earned_usd,
+            pricing_currency: None,
+            overpayment_handling: None,
+            refund_managers: None,
+            invoice_ttl: None,
+            underpayment_tolerance: None,
+            rate_lock_policy: None,
+            idempotency_window: None,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct InvoicesStateV0 {
+    pub invoice_id_generator: InvoiceId,
+    pub all_invoices: BTreeMap<InvoiceId, InvoiceV0>,
+    pub active_invoices: HashMap<Timestamp, BTreeSet<InvoiceId>>,
+    pub inactive_invoices: BTreeSet<InvoiceId>,
+    pub total_processed_in_usd: E8s,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct InvoiceV0 {
+    pub id: InvoiceId,
+    pub status: InvoiceStatusV0,
+    pub creator: Principal,
+    pub qty_usd: E8s,
+    pub created_at: u64,
+    pub exchange_rates_timestamp: u64,
+    pub shop_id: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum InvoiceStatusV0 {
+    Created {
+        ttl: u8,
+    },
+    VerifyPayment,
+    Paid {
+        timestamp: u64,
+        token_id: Principal,
+        qty: EDs,
+        exchange_rate: EDs,
+    },
+}
+
+impl From<InvoiceV0> for Invoice {
+    fn from(it: InvoiceV0) -> Self {
+        let status = match it.status {
+            InvoiceStatusV0::Created { ttl } => InvoiceStatus::Created { ttl },
+            InvoiceStatusV0::VerifyPayment => InvoiceStatus::VerifyPayment,
+            InvoiceStatusV0::Paid {
+                timestamp,
+                token_id,
+                qty,
+                exchange_rate,
+            } => InvoiceStatus::Paid {
+                timestamp,
+                token_id,
+                qty,
+                exchange_rate,
+                block_idx: None,
+                from: None,
+            },
+        };
+
+        Self {
+            id: it.id,
+            status,
+            creator: it.creator,
+            qty_usd: it.qty_usd,
+            created_at: it.created_at,
+            exchange_rates_timestamp: it.exchange_rates_timestamp,
+            shop_id: it.shop_id,
+            deposit_subaccount: None,
+            token_price: None,
+            fiat_price: None,
+            payments: None,
+            paid_qty_usd: None,
+            overpayment: None,
+            refunds: None,
+            late_payments: None,
+            expires_at: None,
+            rate_lock_policy: None,
+            underpayment_tolerance: None,
+            metadata: None,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct SupportedTokensStateV0 {
+    pub tokens: BTreeMap<TokenId, TokenV0>,
+    pub tokens_by_ticker: BTreeMap<Ticker, TokenId>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TokenV0 {
+    pub id: TokenId,
+    pub ticker: Ticker,
+    pub xrc_ticker: Ticker,
+    pub fee: EDs,
+    pub logo_src: String,
+}
+
+impl From<SupportedTokensStateV0> for SupportedTokensState {
+    fn from(it: SupportedTokensStateV0) -> Self {
+        let mut state = Self::default();
+
+        for (_, token) in it.tokens {
+            state.add_token(Token {
+                id: token.id,
+                ticker: token.ticker,
+                xrc_ticker: token.xrc_ticker,
+                fee: token.fee,
+                logo_src: token.logo_src,
+            });
+        }
+
+        state
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ExchangeRatesStateV0 {
+    pub mock: bool,
+    pub last_updated_at: Timestamp,
+    pub rates: HashMap<Timestamp, BTreeMap<Ticker, E8s>>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct InvoiceArchivesStateV0 {
+    pub archives: Vec<ArchiveInfoV0>,
+    pub config: ArchivingConfigV0,
+    pub archive_wasm: Option<Vec<u8>>,
+    pub is_spawning: bool,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ArchiveInfoV0 {
+    pub canister_id: Principal,
+    pub start_idx: u64,
+    pub len: u64,
+    pub memory_size_bytes: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ArchivingConfigV0 {
+    pub max_entries_per_archive: u64,
+    pub max_memory_per_archive_bytes: u64,
+    pub cycles_per_archive: u128,
+}
+
+impl From<InvoiceArchivesStateV0> for InvoiceArchivesState {
+    fn from(it: InvoiceArchivesStateV0) -> Self {
+        Self {
+            archives: it
+                .archives
+                .into_iter()
+                .map(|archive| ArchiveInfo {
+                    canister_id: archive.canister_id,
+                    start_idx: archive.start_idx,
+                    len: archive.len,
+                    memory_size_bytes: archive.memory_size_bytes,
+                })
+                .collect(),
+            config: ArchivingConfig {
+                max_entries_per_archive: it.config.max_entries_per_archive,
+                max_memory_per_archive_bytes: it.config.max_memory_per_archive_bytes,
+                cycles_per_archive: it.config.cycles_per_archive,
+                refund_window_ns: None,
+            },
+            archive_wasm: it.archive_wasm,
+            is_spawning: it.is_spawning,
+        }
+    }
+}
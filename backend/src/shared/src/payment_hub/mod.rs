@@ -1 +1,3 @@
+pub mod api;
+pub mod migrations;
 pub mod state;
@@ -1,143 +1,1227 @@
-use std::collections::{hash_map::Entry, BTreeMap, HashMap};
-
-use candid::CandidType;
-use ic_e8s::d::EDs;
-use ic_xrc_types::ExchangeRate;
+use candid::{CandidType, Nat, Principal};
+use ic_e8s::{c::E8s, d::EDs};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager},
+    DefaultMemoryImpl, StableCell,
+};
+use ic_xrc_types::{AssetClass, ExchangeRate};
 use icrc_ledger_types::icrc1::account::Account;
-use msq_pay_types::InvoiceStatus;
+use msq_pay_types::{
+    FiatPrice, Invoice, InvoiceId, InvoicePayment, InvoiceRefund, InvoiceRefundStatus,
+    InvoiceStatus, OverpaymentStatus, RateLockPolicy, TokenPrice,
+};
 use num_bigint::BigUint;
 use serde::Deserialize;
 
 use crate::{
-    exchange_rates::{state::ExchangeRatesState, types::Ticker},
-    invoices::state::InvoicesState,
-    shops::state::ShopsState,
+    admins::state::AdminsState,
+    balances::state::BalancesState,
+    block_scanner::state::BlockScannerState,
+    exchange_rates::{
+        state::ExchangeRatesState,
+        types::{Ticker, BASE_CURRENCY},
+    },
+    fees::state::FeesState,
+    impl_candid_storable,
+    invoice_archives::state::InvoiceArchivesState,
+    invoices::{api::RefundInvoiceRequest, state::InvoicesState, types::DepositSweep},
+    shops::{state::ShopsState, types::OverpaymentHandling},
     supported_tokens::state::SupportedTokensState,
-    utils::{Timestamp, RECYCLING_TTL},
+    utils::{Memory, ShopId, Timestamp, TokenId, TransferTxn},
+    withdrawals::{
+        state::WithdrawalsState,
+        types::{Withdrawal, WithdrawalId, WithdrawalLeg, WithdrawalLegKind},
+    },
 };
 
-#[derive(CandidType, Deserialize, Default)]
+const HEAP_STATE_MEMORY_ID: MemoryId = MemoryId::new(0);
+const SHOP_ID_GENERATOR_MEMORY_ID: MemoryId = MemoryId::new(1);
+const SHOPS_MEMORY_ID: MemoryId = MemoryId::new(2);
+const OWNER_TO_SHOPS_MEMORY_ID: MemoryId = MemoryId::new(3);
+const REFERRAL_TO_SHOPS_MEMORY_ID: MemoryId = MemoryId::new(4);
+const ALL_INVOICES_MEMORY_ID: MemoryId = MemoryId::new(5);
+const ACTIVE_INVOICES_MEMORY_ID: MemoryId = MemoryId::new(6);
+const INACTIVE_INVOICES_MEMORY_ID: MemoryId = MemoryId::new(7);
+const TOTAL_PROCESSED_MEMORY_ID: MemoryId = MemoryId::new(8);
+const EXCHANGE_RATES_META_MEMORY_ID: MemoryId = MemoryId::new(9);
+const EXCHANGE_RATES_MEMORY_ID: MemoryId = MemoryId::new(10);
+const VERSION_MEMORY_ID: MemoryId = MemoryId::new(11);
+const ADMINS_MEMORY_ID: MemoryId = MemoryId::new(12);
+const FEE_SCHEDULE_MEMORY_ID: MemoryId = MemoryId::new(13);
+const SHOP_FEE_SCHEDULES_MEMORY_ID: MemoryId = MemoryId::new(14);
+const MONTHLY_VOLUMES_MEMORY_ID: MemoryId = MemoryId::new(15);
+const WITHDRAWAL_ID_GENERATOR_MEMORY_ID: MemoryId = MemoryId::new(16);
+const WITHDRAWALS_MEMORY_ID: MemoryId = MemoryId::new(17);
+const PENDING_WITHDRAWALS_MEMORY_ID: MemoryId = MemoryId::new(18);
+const WITHDRAWALS_BY_SHOP_MEMORY_ID: MemoryId = MemoryId::new(19);
+const BALANCES_MEMORY_ID: MemoryId = MemoryId::new(20);
+const BALANCE_DISCREPANCIES_MEMORY_ID: MemoryId = MemoryId::new(21);
+const ACTIVE_INVOICES_BY_MEMO_MEMORY_ID: MemoryId = MemoryId::new(22);
+const BLOCK_SCANNER_CURSORS_MEMORY_ID: MemoryId = MemoryId::new(23);
+const ACTIVE_INVOICES_BY_DEPOSIT_ADDRESS_MEMORY_ID: MemoryId = MemoryId::new(24);
+const PENDING_DEPOSIT_SWEEPS_MEMORY_ID: MemoryId = MemoryId::new(25);
+const CONSUMED_BLOCKS_MEMORY_ID: MemoryId = MemoryId::new(26);
+const ACTIVE_INVOICES_BY_EXPIRY_MEMORY_ID: MemoryId = MemoryId::new(27);
+const INVOICES_BY_EXTERNAL_ORDER_ID_MEMORY_ID: MemoryId = MemoryId::new(28);
+const IDEMPOTENCY_KEYS_MEMORY_ID: MemoryId = MemoryId::new(29);
+const IDEMPOTENCY_KEYS_BY_EXPIRY_MEMORY_ID: MemoryId = MemoryId::new(30);
+const UNARCHIVABLE_INVOICES_MEMORY_ID: MemoryId = MemoryId::new(31);
+const ARCHIVE_QUEUE_MEMORY_ID: MemoryId = MemoryId::new(32);
+const PENDING_REFUNDS_MEMORY_ID: MemoryId = MemoryId::new(33);
+const SHOPS_BY_PRICING_CURRENCY_MEMORY_ID: MemoryId = MemoryId::new(34);
+
+// states, which were put into stable memory before the version was tracked, are all of this version
+const UNTRACKED_STATE_VERSION: u32 = 1;
+
+/**
+ * Everything that grows with usage lives in stable structures and is never (de)serialized as a whole.
+ * The rest is small, so it stays on the heap and is only written to stable memory before upgrades.
+ */
 pub struct State {
     pub shops: ShopsState,
     pub invoices: InvoicesState,
     pub supported_tokens: SupportedTokensState,
     pub exchange_rates: ExchangeRatesState,
     pub fee_collector_account: Option<Account>,
+    pub invoice_archives: InvoiceArchivesState,
+    pub admins: AdminsState,
+    pub fees: FeesState,
+    pub withdrawals: WithdrawalsState,
+    pub balances: BalancesState,
+    pub block_scanner: BlockScannerState,
+
+    heap_state: StableCell<HeapState, Memory>,
+    version: StableCell<u32, Memory>,
+}
+
+#[derive(CandidType, Deserialize, Default, Clone)]
+pub struct HeapState {
+    pub supported_tokens: SupportedTokensState,
+    pub fee_collector_account: Option<Account>,
+    pub invoice_archives: InvoiceArchivesState,
 }
 
+impl_candid_storable!(HeapState);
+
 impl State {
+    pub fn init(memory_manager: &MemoryManager<DefaultMemoryImpl>) -> Self {
+        Self {
+            shops: ShopsState::init(
+                memory_manager.get(SHOP_ID_GENERATOR_MEMORY_ID),
+                memory_manager.get(SHOPS_MEMORY_ID),
+                memory_manager.get(OWNER_TO_SHOPS_MEMORY_ID),
+                memory_manager.get(REFERRAL_TO_SHOPS_MEMORY_ID),
+                memory_manager.get(SHOPS_BY_PRICING_CURRENCY_MEMORY_ID),
+            ),
+            invoices: InvoicesState::init(
+                memory_manager.get(ALL_INVOICES_MEMORY_ID),
+                memory_manager.get(ACTIVE_INVOICES_MEMORY_ID),
+                memory_manager.get(INACTIVE_INVOICES_MEMORY_ID),
+                memory_manager.get(UNARCHIVABLE_INVOICES_MEMORY_ID),
+                memory_manager.get(ARCHIVE_QUEUE_MEMORY_ID),
+                memory_manager.get(ACTIVE_INVOICES_BY_MEMO_MEMORY_ID),
+                memory_manager.get(ACTIVE_INVOICES_BY_DEPOSIT_ADDRESS_MEMORY_ID),
+                memory_manager.get(PENDING_DEPOSIT_SWEEPS_MEMORY_ID),
+                memory_manager.get(ACTIVE_INVOICES_BY_EXPIRY_MEMORY_ID),
+                memory_manager.get(CONSUMED_BLOCKS_MEMORY_ID),
+                memory_manager.get(INVOICES_BY_EXTERNAL_ORDER_ID_MEMORY_ID),
+                memory_manager.get(IDEMPOTENCY_KEYS_MEMORY_ID),
+                memory_manager.get(IDEMPOTENCY_KEYS_BY_EXPIRY_MEMORY_ID),
+                memory_manager.get(PENDING_REFUNDS_MEMORY_ID),
+                memory_manager.get(TOTAL_PROCESSED_MEMORY_ID),
+            ),
+            supported_tokens: SupportedTokensState::default(),
+            exchange_rates: ExchangeRatesState::init(
+                memory_manager.get(EXCHANGE_RATES_META_MEMORY_ID),
+                memory_manager.get(EXCHANGE_RATES_MEMORY_ID),
+            ),
+            fee_collector_account: None,
+            invoice_archives: InvoiceArchivesState::default(),
+            admins: AdminsState::init(memory_manager.get(ADMINS_MEMORY_ID)),
+            fees: FeesState::init(
+                memory_manager.get(FEE_SCHEDULE_MEMORY_ID),
+                memory_manager.get(SHOP_FEE_SCHEDULES_MEMORY_ID),
+                memory_manager.get(MONTHLY_VOLUMES_MEMORY_ID),
+            ),
+            withdrawals: WithdrawalsState::init(
+                memory_manager.get(WITHDRAWAL_ID_GENERATOR_MEMORY_ID),
+                memory_manager.get(WITHDRAWALS_MEMORY_ID),
+                memory_manager.get(PENDING_WITHDRAWALS_MEMORY_ID),
+                memory_manager.get(WITHDRAWALS_BY_SHOP_MEMORY_ID),
+            ),
+            balances: BalancesState::init(
+                memory_manager.get(BALANCES_MEMORY_ID),
+                memory_manager.get(BALANCE_DISCREPANCIES_MEMORY_ID),
+            ),
+            block_scanner: BlockScannerState::init(
+                memory_manager.get(BLOCK_SCANNER_CURSORS_MEMORY_ID),
+            ),
+            heap_state: StableCell::init(
+                memory_manager.get(HEAP_STATE_MEMORY_ID),
+                HeapState::default(),
+            )
+            .expect("Unable to init heap state"),
+            version: StableCell::init(
+                memory_manager.get(VERSION_MEMORY_ID),
+                UNTRACKED_STATE_VERSION,
+            )
+            .expect("Unable to init state version"),
+        }
+    }
+
+    pub fn save_heap_state(&mut self) {
+        let heap_state = HeapState {
+            supported_tokens: self.supported_tokens.clone(),
+            fee_collector_account: self.fee_collector_account,
+            invoice_archives: self.invoice_archives.clone(),
+        };
+
+        self.heap_state
+            .set(heap_state)
+            .expect("Unable to save heap state");
+    }
+
+    pub fn load_heap_state(&mut self) {
+        let heap_state = self.heap_state.get().clone();
+
+        self.supported_tokens = heap_state.supported_tokens;
+        self.fee_collector_account = heap_state.fee_collector_account;
+        self.invoice_archives = heap_state.invoice_archives;
+    }
+
+    pub fn version(&self) -> u32 {
+        *self.version.get()
+    }
+
+    pub fn set_version(&mut self, version: u32) {
+        self.version
+            .set(version)
+            .expect("Unable to set state version");
+    }
+
     pub fn set_fee_collector_account(&mut self, new_fee_collector_account: Option<Account>) {
         self.fee_collector_account = new_fee_collector_account;
     }
 
-    pub fn purge_expired_invoices(&mut self) {
-        let mut purged_invoices = HashMap::new();
+    /**
+     * Closed invoices (expired and cancelled ones too) stay in the hub for the refund window, and after it - while they
+     * have refunds being sent or payments of an unpaid invoice, which are not refunded yet
+     */
+    pub fn prepare_archive_batch(&mut self, size: usize, now: Timestamp) -> Vec<Invoice> {
+        let closed_before = now.saturating_sub(self.invoice_archives.config.refund_window_ns());
 
-        for (exchange_rates_timestamp, active_invoices) in self.invoices.active_invoices.iter() {
-            let mut cur_purged_invoices = Vec::new();
+        self.invoices
+            .prepare_archive_batch(size, closed_before, &self.supported_tokens)
+    }
 
-            for id in active_invoices {
-                let mut remove = false;
+    pub fn purge_expired_invoices(&mut self, this_canister_id: Principal, now: Timestamp) {
+        for id in self.invoices.expired_ids(now) {
+            let invoice = self.invoices.get(&id).unwrap();
 
-                {
-                    let invoice = self.invoices.all_invoices.get_mut(id).unwrap();
-
-                    if let InvoiceStatus::Created { ttl } = invoice.status {
-                        if ttl > RECYCLING_TTL {
-                            invoice.status = InvoiceStatus::Created { ttl: ttl - 1 };
-                        } else {
-                            remove = true;
-                        }
-                    } else {
-                        unreachable!("Invoice should be in Created state");
-                    }
-                }
+            // the payment is being verified right now - the verification expires the invoice itself, if it is late
+            if matches!(
+                invoice.status,
+                InvoiceStatus::Created { .. } | InvoiceStatus::PartiallyPaid
+            ) {
+                self.expire_invoice(invoice, this_canister_id, now);
+            }
+        }
+    }
 
-                if remove {
-                    self.invoices.all_invoices.remove(id);
-                    cur_purged_invoices.push(*id);
-                }
+    fn expire_invoice(
+        &mut self,
+        mut invoice: Invoice,
+        this_canister_id: Principal,
+        now: Timestamp,
+    ) {
+        let timestamp = invoice.expires_at.unwrap_or(now);
+
+        self.invoices.close(
+            &mut invoice,
+            InvoiceStatus::Expired { timestamp },
+            this_canister_id,
+        );
+
+        if !self
+            .invoices
+            .has_active_invoices(invoice.exchange_rates_timestamp)
+        {
+            self.exchange_rates
+                .delete_outdated(&invoice.exchange_rates_timestamp);
+        }
+    }
+
+    /**
+     * Applies the transfer to an invoice in `VerifyPayment` status and credits the shop with it. If the transfer
+     * can't be applied, the invoice is put back into the status it had before the verification.
+     */
+    pub fn settle_invoice_payment(
+        &mut self,
+        invoice_id: &InvoiceId,
+        transfer_txn: TransferTxn,
+        block_idx: Nat,
+        prev_status: InvoiceStatus,
+        this_canister_id: Principal,
+        now: Timestamp,
+    ) -> Result<Invoice, String> {
+        // a transfer to an invoice, which expired before it, can only be recorded as a late payment
+        if let Some(invoice) = self
+            .invoices
+            .get(invoice_id)
+            .filter(|it| InvoicesState::is_expired(it, now))
+        {
+            self.expire_invoice(invoice, this_canister_id, now);
+
+            return self.settle_late_payment(
+                invoice_id,
+                transfer_txn,
+                block_idx,
+                this_canister_id,
+                now,
+            );
+        }
+
+        // token-priced invoices are verified without any exchange rate
+        let exchange_rate = match self.invoices.get(invoice_id) {
+            Some(invoice) if invoice.token_price.is_some() => Ok(None),
+            _ => self
+                .find_payment_exchange_rate(invoice_id, &transfer_txn.token_id)
+                .map(Some),
+        };
+
+        let payer = transfer_txn.from;
+
+        let result = exchange_rate.and_then(|exchange_rate| {
+            self.invoices.verify_payment(
+                invoice_id,
+                transfer_txn,
+                block_idx,
+                exchange_rate,
+                this_canister_id,
+                now,
+            )
+        });
+
+        let (mut invoice, payment, should_delete_outdated) = match result {
+            Ok(it) => it,
+            Err(err) => {
+                self.invoices.set_status(invoice_id, prev_status).unwrap();
+
+                return Err(err);
             }
+        };
 
-            purged_invoices.insert(*exchange_rates_timestamp, cur_purged_invoices);
+        self.credit_invoice_payment(&invoice, &payment);
+
+        if !matches!(invoice.status, InvoiceStatus::Paid { .. }) {
+            return Ok(invoice);
         }
 
-        for (exchange_rates_timestamp, invoices) in purged_invoices {
-            let mut remove = false;
+        // if there are no more active invoices referring these exchange rates - delete them
+        if should_delete_outdated {
+            self.exchange_rates
+                .delete_outdated(&invoice.exchange_rates_timestamp);
+        }
 
-            {
-                let active_invoices = self
-                    .invoices
-                    .active_invoices
-                    .get_mut(&exchange_rates_timestamp)
-                    .unwrap();
+        self.shops
+            .add_total_earned_usd(&invoice.shop_id, &invoice.qty_usd);
+        self.fees.add_volume(invoice.shop_id, now, &invoice.qty_usd);
 
-                for id in invoices {
-                    active_invoices.remove(&id);
-                }
+        if invoice.overpayment.is_some() {
+            self.handle_overpayment(&mut invoice, payer, this_canister_id, now);
+        }
+
+        Ok(invoice)
+    }
 
-                if active_invoices.is_empty() {
-                    remove = true;
+    /**
+     * Applies a transfer made to an expired or cancelled invoice. The shop is credited with it, so it could be refunded.
+     */
+    pub fn settle_late_payment(
+        &mut self,
+        invoice_id: &InvoiceId,
+        transfer_txn: TransferTxn,
+        block_idx: Nat,
+        this_canister_id: Principal,
+        now: Timestamp,
+    ) -> Result<Invoice, String> {
+        let (invoice, payment) = self.invoices.record_late_payment(
+            invoice_id,
+            transfer_txn,
+            block_idx,
+            this_canister_id,
+            now,
+        )?;
+
+        self.credit_invoice_payment(&invoice, &payment);
+
+        Ok(invoice)
+    }
+
+    // funds in a deposit subaccount are charged the transfer fee later, once per sweep - see `complete_deposit_sweep`
+    fn credit_invoice_payment(&mut self, invoice: &Invoice, payment: &InvoicePayment) {
+        self.balances
+            .credit(invoice.shop_id, payment.token_id, &payment.qty);
+    }
+
+    /**
+     * A sweep moves the whole deposit subaccount with a single transfer, so the shop pays a single fee for all of
+     * the payments in it. A balance, which doesn't cover the fee, can't be swept at all and is charged in full.
+     */
+    pub fn complete_deposit_sweep(
+        &mut self,
+        invoice_id: &InvoiceId,
+        sweep: &DepositSweep,
+        swept_balance: &EDs,
+        fee: &EDs,
+    ) {
+        let charged_qty = if swept_balance > fee {
+            fee
+        } else {
+            swept_balance
+        };
+
+        self.balances
+            .debit(sweep.shop_id, sweep.token_id, charged_qty);
+        self.invoices.complete_deposit_sweep(invoice_id);
+    }
+
+    pub fn cancel_invoice(
+        &mut self,
+        invoice_id: &InvoiceId,
+        caller: Principal,
+        this_canister_id: Principal,
+        now: Timestamp,
+    ) -> Result<Invoice, String> {
+        let mut invoice = self
+            .invoices
+            .get(invoice_id)
+            .ok_or("Invoice not found".to_string())?;
+
+        if invoice.creator != caller {
+            return Err("Access denied".to_string());
+        }
+
+        // the shop releases an abandoned partial payment this way, so it could be refunded
+        if !matches!(
+            invoice.status,
+            InvoiceStatus::Created { .. } | InvoiceStatus::PartiallyPaid
+        ) {
+            return Err("Only invoices, which are not paid yet, can be cancelled".to_string());
+        }
+
+        self.invoices.close(
+            &mut invoice,
+            InvoiceStatus::Cancelled { timestamp: now },
+            this_canister_id,
+        );
+
+        if !self
+            .invoices
+            .has_active_invoices(invoice.exchange_rates_timestamp)
+        {
+            self.exchange_rates
+                .delete_outdated(&invoice.exchange_rates_timestamp);
+        }
+
+        Ok(invoice)
+    }
+
+    /**
+     * If the shop refunds overpayments, the excess is journaled as a withdrawal to the payer, which is then
+     * processed by the pending withdrawals timer. Otherwise, or if the refund is impossible, the shop keeps it.
+     */
+    fn handle_overpayment(
+        &mut self,
+        invoice: &mut Invoice,
+        payer: Option<Account>,
+        this_canister_id: Principal,
+        now: Timestamp,
+    ) {
+        let Some(overpayment) = invoice.overpayment.as_mut() else {
+            return;
+        };
+
+        let handling = self
+            .shops
+            .get_shop(&invoice.shop_id)
+            .and_then(|it| it.overpayment_handling)
+            .unwrap_or_default();
+
+        if handling == OverpaymentHandling::Keep {
+            return;
+        }
+
+        let fee = self
+            .supported_tokens
+            .get_by_id(&overpayment.token_id)
+            .map(|it| it.fee.clone());
+
+        overpayment.status = match (payer, fee) {
+            (None, _) => OverpaymentStatus::RefundFailed {
+                reason: "The payer account is unknown".to_string(),
+            },
+            (_, None) => OverpaymentStatus::RefundFailed {
+                reason: "The token is not supported anymore".to_string(),
+            },
+            (_, Some(fee)) if overpayment.qty <= fee => OverpaymentStatus::RefundFailed {
+                reason: "The excess doesn't cover the transfer fee".to_string(),
+            },
+            (Some(payer), Some(fee)) => {
+                let qty = overpayment.qty.clone();
+
+                let withdrawal = self.withdrawals.create(
+                    invoice.shop_id,
+                    overpayment.token_id,
+                    this_canister_id,
+                    Some(invoice.id),
+                    now,
+                    |id| {
+                        vec![WithdrawalLeg::new(
+                            id,
+                            WithdrawalLegKind::OverpaymentRefund,
+                            payer,
+                            qty,
+                            fee,
+                            // unique per withdrawal, so it never collides with a refund leg to the same payer
+                            None,
+                            now,
+                        )]
+                    },
+                );
+
+                self.balances
+                    .debit(invoice.shop_id, overpayment.token_id, &overpayment.qty);
+
+                OverpaymentStatus::RefundPending {
+                    withdrawal_id: withdrawal.id,
                 }
             }
+        };
+
+        self.invoices
+            .set_overpayment_status(&invoice.id, overpayment.status.clone());
+    }
+
+    /**
+     * Refunds a payment of the invoice (fully or partially) back to the payer from the shop's balance
+     */
+    pub fn refund_invoice(
+        &mut self,
+        req: RefundInvoiceRequest,
+        caller: Principal,
+        now: Timestamp,
+    ) -> Result<Withdrawal, String> {
+        let invoice = self
+            .invoices
+            .get(&req.invoice_id)
+            .ok_or("Invoice not found".to_string())?;
+
+        if !self.shops.can_refund_invoices(&invoice.shop_id, &caller) {
+            return Err("Access denied".to_string());
+        }
+
+        // partial and late payments of unpaid invoices are refunded the same way
+        if !matches!(
+            invoice.status,
+            InvoiceStatus::Paid { .. }
+                | InvoiceStatus::PartiallyRefunded { .. }
+                | InvoiceStatus::Expired { .. }
+                | InvoiceStatus::Cancelled { .. }
+        ) {
+            return Err("Only paid invoices can be refunded".to_string());
+        }
+
+        let payment = invoice
+            .payments
+            .iter()
+            .chain(invoice.late_payments.iter())
+            .flatten()
+            .find(|it| it.token_id == req.token_id && it.block_idx == req.payment_block_idx)
+            .ok_or("Payment not found".to_string())?;
+
+        let payer = payment
+            .from
+            .ok_or("The payer account is unknown".to_string())?;
+
+        let fee = self
+            .supported_tokens
+            .get_by_id(&payment.token_id)
+            .map(|it| it.fee.clone())
+            .ok_or("The token is not supported anymore".to_string())?;
+
+        let refundable_qty = InvoicesState::refundable_qty(&invoice, payment);
+        let qty = match req.qty {
+            Some(it) => it.to_decimals(payment.qty.decimals),
+            None => refundable_qty.clone(),
+        };
+
+        if qty > refundable_qty {
+            return Err("The refund exceeds the refundable amount".to_string());
+        }
+
+        if qty <= fee {
+            return Err("The refund doesn't cover the transfer fee".to_string());
+        }
+
+        let balance = self
+            .balances
+            .get(invoice.shop_id, payment.token_id, qty.decimals);
+
+        if qty > balance {
+            return Err("Insufficient shop balance".to_string());
+        }
+
+        let leg_qty = qty.clone();
+
+        let withdrawal = self.withdrawals.create(
+            invoice.shop_id,
+            payment.token_id,
+            caller,
+            Some(invoice.id),
+            now,
+            |id| {
+                vec![WithdrawalLeg::new(
+                    id,
+                    WithdrawalLegKind::Refund,
+                    payer,
+                    leg_qty,
+                    fee,
+                    // unique per refund - two equal refunds of a payment would be deduplicated by the ledger otherwise
+                    None,
+                    now,
+                )]
+            },
+        );
+
+        self.balances.debit(invoice.shop_id, payment.token_id, &qty);
+
+        // payments of an unpaid invoice never counted towards the shop's earnings and fee volume
+        let qty_usd = if InvoicesState::is_unpaid(&invoice) {
+            E8s::zero()
+        } else {
+            &qty.clone().to_decimals(8).to_const::<8>()
+                * &payment.exchange_rate.clone().to_decimals(8).to_const::<8>()
+        };
+
+        self.shops.sub_total_earned_usd(&invoice.shop_id, &qty_usd);
+        // so paying and refunding doesn't move the shop into a cheaper fee tier
+        self.fees.sub_volume(
+            invoice.shop_id,
+            InvoicesState::closed_at(&invoice),
+            &qty_usd,
+        );
+
+        let refund = InvoiceRefund {
+            withdrawal_id: withdrawal.id,
+            token_id: payment.token_id,
+            payment_block_idx: payment.block_idx.clone(),
+            qty,
+            qty_usd,
+            timestamp: now,
+            status: InvoiceRefundStatus::Pending,
+        };
+
+        self.invoices.add_refund(&invoice.id, refund);
+
+        Ok(withdrawal)
+    }
+
+    /**
+     * A failed refund counts towards the shop's earnings and fee volume again - its funds are credited back with the failed leg
+     */
+    pub fn complete_invoice_refund(
+        &mut self,
+        shop_id: &ShopId,
+        invoice_id: &InvoiceId,
+        withdrawal_id: WithdrawalId,
+        status: InvoiceRefundStatus,
+    ) {
+        let is_failed = matches!(status, InvoiceRefundStatus::Failed { .. });
+
+        let Some(refund) = self.invoices.complete_refund(
+            invoice_id,
+            withdrawal_id,
+            status,
+            &self.supported_tokens,
+        ) else {
+            return;
+        };
+
+        if is_failed {
+            self.shops.add_total_earned_usd(shop_id, &refund.qty_usd);
+
+            if let Some(volume_timestamp) = refund.volume_timestamp {
+                self.fees
+                    .add_volume(*shop_id, volume_timestamp, &refund.qty_usd);
+            }
+        }
+    }
+
+    /**
+     * Converts the price of a new invoice into USD with the given exchange rates. A token price is only estimated in USD,
+     * while a price in the pricing currency of the shop is locked to its current USD rate.
+     */
+    pub fn price_invoice(
+        &self,
+        shop_id: &ShopId,
+        qty: E8s,
+        token_price: Option<TokenPrice>,
+        exchange_rates_timestamp: Timestamp,
+    ) -> Result<(E8s, Option<TokenPrice>, Option<FiatPrice>), String> {
+        if let Some(price) = token_price {
+            let token = self
+                .supported_tokens
+                .get_by_id(&price.token_id)
+                .ok_or("Unsupported token".to_string())?;
+
+            let qty = price.qty.to_decimals(token.fee.decimals);
+            if qty == EDs::zero(token.fee.decimals) {
+                return Err("Invalid token price".to_string());
+            }
+
+            // only an estimate for the stats and fees - the payment itself is never converted
+            let qty_usd = self
+                .exchange_rates
+                .find_exchange_rate(&exchange_rates_timestamp, &token.ticker)
+                .map(|rate| {
+                    (&rate.to_dynamic().to_decimals(qty.decimals) * &qty)
+                        .to_decimals(8)
+                        .to_const::<8>()
+                })
+                .unwrap_or_else(E8s::zero);
+
+            let token_price = TokenPrice {
+                token_id: price.token_id,
+                qty,
+            };
+
+            return Ok((qty_usd, Some(token_price), None));
+        }
+
+        let currency = self
+            .shops
+            .get_shop(shop_id)
+            .ok_or("Shop not found".to_string())?
+            .pricing_currency;
 
-            if remove {
-                self.invoices
-                    .active_invoices
-                    .remove(&exchange_rates_timestamp);
+        match currency {
+            Some(currency) if currency != BASE_CURRENCY => {
+                let usd_rate = self
+                    .exchange_rates
+                    .find_exchange_rate(&exchange_rates_timestamp, &Ticker::fiat(&currency))
+                    .ok_or(format!(
+                        "The {} exchange rate is not available yet, try again later",
+                        currency
+                    ))?;
+
+                let qty_usd = &qty * &usd_rate;
+                let fiat_price = FiatPrice {
+                    currency,
+                    qty,
+                    usd_rate,
+                };
 
-                self.exchange_rates
-                    .delete_outdated(&exchange_rates_timestamp);
+                Ok((qty_usd, None, Some(fiat_price)))
             }
+            _ => Ok((qty, None, None)),
+        }
+    }
+
+    pub fn find_invoice_exchange_rate(
+        &self,
+        invoice_id: &InvoiceId,
+        token_id: &TokenId,
+    ) -> Result<E8s, String> {
+        let invoice = self
+            .invoices
+            .get(invoice_id)
+            .ok_or("Invoice not found".to_string())?;
+
+        let ticker = self
+            .supported_tokens
+            .ticker_by_token_id(token_id)
+            .ok_or("Unsuported token".to_string())?;
+
+        self.exchange_rates
+            .find_exchange_rate(&invoice.exchange_rates_timestamp, &ticker)
+            .ok_or(format!(
+                "No {} exchange rate found for the invoice",
+                ticker.0
+            ))
+    }
+
+    /**
+     * The rate a payment to the invoice is converted with - depends on the rate lock policy of the invoice
+     */
+    pub fn find_payment_exchange_rate(
+        &self,
+        invoice_id: &InvoiceId,
+        token_id: &TokenId,
+    ) -> Result<E8s, String> {
+        let rate_lock_policy = self
+            .invoices
+            .get(invoice_id)
+            .ok_or("Invoice not found".to_string())?
+            .rate_lock_policy
+            .unwrap_or_default();
+
+        match rate_lock_policy {
+            RateLockPolicy::LockAtCreation => self.find_invoice_exchange_rate(invoice_id, token_id),
+            RateLockPolicy::RequoteAtPayment => self.find_current_exchange_rate(token_id),
         }
     }
 
+    pub fn find_current_exchange_rate(&self, token_id: &TokenId) -> Result<E8s, String> {
+        let ticker = self
+            .supported_tokens
+            .ticker_by_token_id(token_id)
+            .ok_or("Unsuported token".to_string())?;
+
+        self.exchange_rates
+            .find_exchange_rate(&self.exchange_rates.last_updated_at(), &ticker)
+            .ok_or(format!("No current {} exchange rate found", ticker.0))
+    }
+
     pub fn update_exchange_rates(
         &mut self,
         exchange_rates_external: Vec<ExchangeRate>,
         timestamp: Timestamp,
     ) {
         // if there are no invoices which refer to the previosly actual exchange rates - remove those rates from memory
-        let previous_timestamp_referenced_by_active_invoices = self
-            .invoices
-            .active_invoices
-            .get(&self.exchange_rates.last_updated_at)
-            .map(|it| it.is_empty())
-            .unwrap_or_default();
+        let previous_timestamp = self.exchange_rates.last_updated_at();
 
-        if !previous_timestamp_referenced_by_active_invoices {
-            self.exchange_rates
-                .rates
-                .remove(&self.exchange_rates.last_updated_at);
+        if !self.invoices.has_active_invoices(previous_timestamp) {
+            self.exchange_rates.rates.remove(&previous_timestamp);
         }
 
         // store new exchange rates as actual
-        self.exchange_rates.last_updated_at = timestamp;
+        self.exchange_rates.set_last_updated_at(timestamp);
 
         for rate in exchange_rates_external {
             let ticker_from = rate.base_asset.symbol;
 
-            if self.supported_tokens.contains_ticker(&ticker_from) {
-                let usd_rate = EDs::new(BigUint::from(rate.rate), rate.metadata.decimals as u8)
-                    .to_decimals(8)
-                    .to_const::<8>();
-
-                match self
-                    .exchange_rates
-                    .rates
-                    .entry(self.exchange_rates.last_updated_at)
+            let ticker = match rate.base_asset.class {
+                AssetClass::FiatCurrency => Ticker::fiat(&ticker_from),
+                AssetClass::Cryptocurrency
+                    if self.supported_tokens.contains_ticker(&ticker_from) =>
                 {
-                    Entry::Occupied(mut e) => {
-                        e.get_mut().insert(Ticker::from(ticker_from), usd_rate);
-                    }
-                    Entry::Vacant(e) => {
-                        let mut m = BTreeMap::new();
-                        m.insert(Ticker::from(ticker_from), usd_rate);
-
-                        e.insert(m);
-                    }
+                    Ticker::from(ticker_from)
                 }
+                AssetClass::Cryptocurrency => continue,
+            };
+
+            let usd_rate = EDs::new(BigUint::from(rate.rate), rate.metadata.decimals as u8)
+                .to_decimals(8)
+                .to_const::<8>();
+
+            self.exchange_rates.set_rates(timestamp, ticker, usd_rate);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use candid::{Nat, Principal};
+    use ic_e8s::{c::E8s, d::EDs};
+    use ic_stable_structures::{memory_manager::MemoryManager, DefaultMemoryImpl};
+    use icrc_ledger_types::icrc1::account::Account;
+    use msq_pay_types::{
+        InvoiceRefundStatus, InvoiceStatus, RateLockPolicy, TokenPrice, UnderpaymentTolerance,
+    };
+
+    use crate::{
+        exchange_rates::types::Ticker,
+        invoice_history::{state::State as ArchiveState, types::ArchivedInvoice},
+        invoices::{api::RefundInvoiceRequest, state::InvoicesState, types::NewInvoice},
+        supported_tokens::types::Token,
+        utils::{calc_shop_subaccount, TransferTxn},
+    };
+
+    use super::State;
+
+    #[test]
+    fn late_payments_keep_expired_invoices_refundable() {
+        let mut state = State::init(&MemoryManager::init(DefaultMemoryImpl::default()));
+
+        let this_canister_id = Principal::management_canister();
+        let owner = Principal::from_slice(&[1]);
+        let payer = Account {
+            owner: Principal::from_slice(&[2]),
+            subaccount: None,
+        };
+        let token_id = Principal::from_slice(&[3]);
+
+        state.supported_tokens.add_token(Token {
+            id: token_id,
+            ticker: Ticker::from("ICP"),
+            xrc_ticker: Ticker::from("ICP"),
+            fee: EDs::new(10_000u64.into(), 8),
+            logo_src: String::new(),
+        });
+
+        let shop_id = state.shops.create_shop(
+            BTreeSet::from([owner]),
+            String::from("Shop"),
+            String::new(),
+            String::new(),
+            None,
+            owner,
+        );
+
+        let invoice_id = state
+            .invoices
+            .create(
+                NewInvoice {
+                    shop_id,
+                    creator: owner,
+                    qty_usd: E8s::from(1_0000_0000u64),
+                    token_price: None,
+                    fiat_price: None,
+                    exchange_rates_timestamp: 0,
+                    expires_at: 100,
+                    rate_lock_policy: RateLockPolicy::LockAtCreation,
+                    underpayment_tolerance: UnderpaymentTolerance::default(),
+                    metadata: None,
+                    idempotency_key: None,
+                    deposit_account_owner: None,
+                },
+                0,
+            )
+            .unwrap();
+
+        // expire
+        state.purge_expired_invoices(this_canister_id, 200);
+        assert!(matches!(
+            state.invoices.get(&invoice_id).unwrap().status,
+            InvoiceStatus::Expired { timestamp: 100 }
+        ));
+
+        // late pay
+        state
+            .settle_late_payment(
+                &invoice_id,
+                TransferTxn {
+                    from: Some(payer),
+                    to: Account {
+                        owner: this_canister_id,
+                        subaccount: Some(calc_shop_subaccount(shop_id)),
+                    },
+                    qty: EDs::new(1_0000_0000u64.into(), 8),
+                    token_id,
+                    memo: Some(InvoicesState::make_invoice_memo(&invoice_id)),
+                },
+                Nat::from(5u64),
+                this_canister_id,
+                300,
+            )
+            .unwrap();
+
+        // archive run, long after the refund window has passed
+        let refund_window_ns = state.invoice_archives.config.refund_window_ns();
+        let later = 300 + refund_window_ns;
+
+        assert!(state.prepare_archive_batch(100, later).is_empty());
+        assert!(state.invoices.get(&invoice_id).is_some());
+
+        // refund
+        let withdrawal = state
+            .refund_invoice(
+                RefundInvoiceRequest {
+                    invoice_id,
+                    token_id,
+                    payment_block_idx: Nat::from(5u64),
+                    qty: None,
+                },
+                owner,
+                later,
+            )
+            .unwrap();
+
+        assert!(state.prepare_archive_batch(100, later).is_empty());
+
+        state.complete_invoice_refund(
+            &shop_id,
+            &invoice_id,
+            withdrawal.id,
+            InvoiceRefundStatus::Completed {
+                block_idx: Nat::from(6u64),
+            },
+        );
+
+        // nothing is left to refund, so the invoice is archived with its late payment
+        let batch = state.prepare_archive_batch(100, later);
+        assert_eq!(batch.len(), 1);
+        assert!(state.invoices.get(&invoice_id).is_none());
+
+        match ArchivedInvoice::try_from(batch[0].clone()).unwrap() {
+            ArchivedInvoice::V0003(it) => assert_eq!(it.late_payments.len(), 1),
+            it => panic!("Unexpected archived invoice {:?}", it),
+        }
+    }
+
+    #[test]
+    fn abandoned_partial_payments_expire_refundable() {
+        let mut state = State::init(&MemoryManager::init(DefaultMemoryImpl::default()));
+
+        let this_canister_id = Principal::management_canister();
+        let owner = Principal::from_slice(&[1]);
+        let payer = Account {
+            owner: Principal::from_slice(&[2]),
+            subaccount: None,
+        };
+        let token_id = Principal::from_slice(&[3]);
+
+        state.supported_tokens.add_token(Token {
+            id: token_id,
+            ticker: Ticker::from("ICP"),
+            xrc_ticker: Ticker::from("ICP"),
+            fee: EDs::new(10_000u64.into(), 8),
+            logo_src: String::new(),
+        });
+
+        let shop_id = state.shops.create_shop(
+            BTreeSet::from([owner]),
+            String::from("Shop"),
+            String::new(),
+            String::new(),
+            None,
+            owner,
+        );
+
+        let invoice_id = state
+            .invoices
+            .create(
+                NewInvoice {
+                    shop_id,
+                    creator: owner,
+                    qty_usd: E8s::from(10_0000_0000u64),
+                    token_price: Some(TokenPrice {
+                        token_id,
+                        qty: EDs::new(1_0000_0000u64.into(), 8),
+                    }),
+                    fiat_price: None,
+                    exchange_rates_timestamp: 0,
+                    expires_at: 100,
+                    rate_lock_policy: RateLockPolicy::LockAtCreation,
+                    underpayment_tolerance: UnderpaymentTolerance::default(),
+                    metadata: None,
+                    idempotency_key: None,
+                    deposit_account_owner: None,
+                },
+                0,
+            )
+            .unwrap();
+
+        // pay a half and walk away
+        state
+            .invoices
+            .set_status(&invoice_id, InvoiceStatus::VerifyPayment);
+        state
+            .settle_invoice_payment(
+                &invoice_id,
+                TransferTxn {
+                    from: Some(payer),
+                    to: Account {
+                        owner: this_canister_id,
+                        subaccount: Some(calc_shop_subaccount(shop_id)),
+                    },
+                    qty: EDs::new(5000_0000u64.into(), 8),
+                    token_id,
+                    memo: Some(InvoicesState::make_invoice_memo(&invoice_id)),
+                },
+                Nat::from(5u64),
+                InvoiceStatus::Created { ttl: 0 },
+                this_canister_id,
+                50,
+            )
+            .unwrap();
+
+        assert!(matches!(
+            state.invoices.get(&invoice_id).unwrap().status,
+            InvoiceStatus::PartiallyPaid
+        ));
+
+        // expire
+        state.purge_expired_invoices(this_canister_id, 200);
+        assert!(matches!(
+            state.invoices.get(&invoice_id).unwrap().status,
+            InvoiceStatus::Expired { timestamp: 100 }
+        ));
+        assert!(!state
+            .invoices
+            .active_invoices
+            .contains_key(&(0, invoice_id)));
+
+        // the partial payment holds the invoice in the hub, until it is refunded
+        let later = 200 + state.invoice_archives.config.refund_window_ns();
+        assert!(state.prepare_archive_batch(100, later).is_empty());
+
+        let withdrawal = state
+            .refund_invoice(
+                RefundInvoiceRequest {
+                    invoice_id,
+                    token_id,
+                    payment_block_idx: Nat::from(5u64),
+                    qty: None,
+                },
+                owner,
+                later,
+            )
+            .unwrap();
+
+        assert_eq!(withdrawal.legs[0].qty, EDs::new(5000_0000u64.into(), 8));
+        // the partial payment never counted towards the earnings
+        assert_eq!(
+            state.shops.get_shop(&shop_id).unwrap().total_earned_usd,
+            E8s::zero()
+        );
+
+        state.complete_invoice_refund(
+            &shop_id,
+            &invoice_id,
+            withdrawal.id,
+            InvoiceRefundStatus::Completed {
+                block_idx: Nat::from(6u64),
+            },
+        );
+
+        let batch = state.prepare_archive_batch(100, later);
+        assert_eq!(batch.len(), 1);
+
+        // the archived invoice keeps the whole story - what was paid, by whom and how it was refunded
+        let mut archive = ArchiveState::init(&MemoryManager::init(DefaultMemoryImpl::default()));
+        archive.push(ArchivedInvoice::try_from(batch[0].clone()).unwrap());
+
+        match archive.get_by_id(&invoice_id).unwrap() {
+            ArchivedInvoice::V0003(it) => {
+                assert!(matches!(
+                    it.status,
+                    InvoiceStatus::Expired { timestamp: 100 }
+                ));
+                assert_eq!(it.closed_at, 100);
+                assert_eq!(it.payments.len(), 1);
+                assert_eq!(it.payments[0].from, Some(payer));
+                assert_eq!(it.refunds.len(), 1);
+                assert!(matches!(
+                    it.refunds[0].status,
+                    InvoiceRefundStatus::Completed { .. }
+                ));
+                assert!(it.token_price.is_some());
+                assert!(it.underpayment_tolerance.is_some());
             }
+            it => panic!("Unexpected archived invoice {:?}", it),
         }
     }
+
+    #[test]
+    fn deposit_sweeps_are_charged_a_single_fee() {
+        let mut state = State::init(&MemoryManager::init(DefaultMemoryImpl::default()));
+
+        let this_canister_id = Principal::management_canister();
+        let owner = Principal::from_slice(&[1]);
+        let payer = Account {
+            owner: Principal::from_slice(&[2]),
+            subaccount: None,
+        };
+        let token_id = Principal::from_slice(&[3]);
+        let fee = EDs::new(10_000u64.into(), 8);
+
+        state.supported_tokens.add_token(Token {
+            id: token_id,
+            ticker: Ticker::from("ICP"),
+            xrc_ticker: Ticker::from("ICP"),
+            fee: fee.clone(),
+            logo_src: String::new(),
+        });
+
+        let shop_id = state.shops.create_shop(
+            BTreeSet::from([owner]),
+            String::from("Shop"),
+            String::new(),
+            String::new(),
+            None,
+            owner,
+        );
+
+        let invoice_id = state
+            .invoices
+            .create(
+                NewInvoice {
+                    shop_id,
+                    creator: owner,
+                    qty_usd: E8s::from(10_0000_0000u64),
+                    token_price: Some(TokenPrice {
+                        token_id,
+                        qty: EDs::new(1_0000_0000u64.into(), 8),
+                    }),
+                    fiat_price: None,
+                    exchange_rates_timestamp: 0,
+                    expires_at: 100,
+                    rate_lock_policy: RateLockPolicy::LockAtCreation,
+                    underpayment_tolerance: UnderpaymentTolerance::default(),
+                    metadata: None,
+                    idempotency_key: None,
+                    deposit_account_owner: Some(this_canister_id),
+                },
+                0,
+            )
+            .unwrap();
+        let deposit_subaccount = state
+            .invoices
+            .get(&invoice_id)
+            .unwrap()
+            .deposit_subaccount
+            .unwrap();
+
+        // paid in two halves into the same deposit subaccount
+        for (block_idx, prev_status) in [
+            (5u64, InvoiceStatus::Created { ttl: 0 }),
+            (6u64, InvoiceStatus::PartiallyPaid),
+        ] {
+            state
+                .invoices
+                .set_status(&invoice_id, InvoiceStatus::VerifyPayment);
+            state
+                .settle_invoice_payment(
+                    &invoice_id,
+                    TransferTxn {
+                        from: Some(payer),
+                        to: Account {
+                            owner: this_canister_id,
+                            subaccount: Some(deposit_subaccount),
+                        },
+                        qty: EDs::new(5000_0000u64.into(), 8),
+                        token_id,
+                        memo: None,
+                    },
+                    Nat::from(block_idx),
+                    prev_status,
+                    this_canister_id,
+                    50,
+                )
+                .unwrap();
+        }
+
+        let paid = EDs::new(1_0000_0000u64.into(), 8);
+        assert_eq!(state.balances.get(shop_id, token_id, 8), paid);
+
+        // both payments are moved with a single transfer
+        let sweeps = state.invoices.pending_deposit_sweeps();
+        assert_eq!(sweeps.len(), 1);
+
+        let (_, sweep) = &sweeps[0];
+        state.complete_deposit_sweep(&invoice_id, sweep, &paid, &fee);
+
+        assert_eq!(state.balances.get(shop_id, token_id, 8), &paid - &fee);
+
+        // the second payment is swept once more, in case it arrived after the first sweep - there is nothing left by then
+        let (_, sweep) = &state.invoices.pending_deposit_sweeps()[0];
+        state.complete_deposit_sweep(&invoice_id, sweep, &EDs::zero(8), &fee);
+
+        assert_eq!(state.balances.get(shop_id, token_id, 8), &paid - &fee);
+        assert!(state.invoices.pending_deposit_sweeps().is_empty());
+    }
 }
@@ -3,11 +3,12 @@ use std::collections::BTreeSet;
 use candid::{CandidType, Nat, Principal};
 use ic_e8s::c::E8s;
 use icrc_ledger_types::icrc1::{account::Account, transfer::Memo};
+use msq_pay_types::{RateLockPolicy, UnderpaymentTolerance};
 use serde::Deserialize;
 
-use crate::utils::ShopId;
+use crate::{utils::ShopId, withdrawals::types::WithdrawalId};
 
-use super::types::{PubShop, ReferredShop, Shop};
+use super::types::{InvoiceTtlConfig, OverpaymentHandling, PubShop, ReferredShop, Shop};
 
 #[derive(CandidType, Deserialize)]
 pub struct RegisterShopRequest {
@@ -16,6 +17,8 @@ pub struct RegisterShopRequest {
     pub description: String,
     pub icon_base64: String,
     pub referal: Option<Principal>,
+    // USD, if not set
+    pub pricing_currency: Option<String>,
 }
 
 #[derive(CandidType, Deserialize)]
@@ -31,6 +34,13 @@ pub struct UpdateShopRequest {
     pub new_name_opt: Option<String>,
     pub new_description_opt: Option<String>,
     pub new_icon_base64_opt: Option<String>,
+    pub new_pricing_currency_opt: Option<String>,
+    pub new_overpayment_handling_opt: Option<OverpaymentHandling>,
+    pub new_refund_managers_opt: Option<BTreeSet<Principal>>,
+    pub new_invoice_ttl_opt: Option<InvoiceTtlConfig>,
+    pub new_underpayment_tolerance_opt: Option<UnderpaymentTolerance>,
+    pub new_rate_lock_policy_opt: Option<RateLockPolicy>,
+    pub new_idempotency_window_opt: Option<u64>,
 }
 
 #[derive(CandidType, Deserialize)]
@@ -49,13 +59,17 @@ pub struct WithdrawProfitRequest {
     pub shop_id: ShopId,
     pub asset_id: Principal,
     pub to: Account,
+    // ICP only - a hex-encoded legacy account identifier to withdraw to, instead of `to`
+    pub to_account_identifier: Option<String>,
     pub qty: E8s,
     pub memo: Option<Memo>,
 }
 
 #[derive(CandidType, Deserialize)]
 pub struct WithdrawProfitResponse {
-    pub block_idx: Nat,
+    pub withdrawal_id: WithdrawalId,
+    // empty, if the transfer to the shop owner didn't complete yet - check the withdrawal for details
+    pub block_idx: Option<Nat>,
 }
 
 #[derive(CandidType, Deserialize)]
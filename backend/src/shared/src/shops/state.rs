@@ -1,23 +1,51 @@
-use std::collections::{btree_map::Entry, BTreeMap, BTreeSet};
+use std::collections::BTreeSet;
 
-use candid::{CandidType, Principal};
+use candid::Principal;
 
 use ic_e8s::c::E8s;
-use serde::Deserialize;
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use msq_pay_types::{RateLockPolicy, UnderpaymentTolerance};
 
-use crate::utils::ShopId;
+use crate::{
+    exchange_rates::types::{is_supported_fiat_currency, BASE_CURRENCY},
+    utils::{
+        Candid, Memory, ShopId, Timestamp, DEFAULT_IDEMPOTENCY_WINDOW_NS,
+        MAX_IDEMPOTENCY_WINDOW_NS, MAX_UNDERPAYMENT_TOLERANCE_BPS, MIN_IDEMPOTENCY_WINDOW_NS,
+    },
+};
 
-use super::types::{ReferredShop, Shop};
+use super::{
+    api::UpdateShopRequest,
+    types::{ReferredShop, Shop},
+};
 
-#[derive(CandidType, Deserialize, Default)]
 pub struct ShopsState {
-    pub shop_id_generator: ShopId,
-    pub shops: BTreeMap<ShopId, Shop>,
-    pub owner_to_shops: BTreeMap<Principal, BTreeSet<ShopId>>,
-    pub referral_to_shops: BTreeMap<Principal, BTreeMap<ShopId, E8s>>,
+    pub shop_id_generator: StableCell<ShopId, Memory>,
+    pub shops: StableBTreeMap<ShopId, Shop, Memory>,
+    pub owner_to_shops: StableBTreeMap<(Principal, ShopId), (), Memory>,
+    pub referral_to_shops: StableBTreeMap<(Principal, ShopId), Candid<E8s>, Memory>,
+    // how many shops price their invoices in each currency, so the shops themselves are never scanned for it
+    pub shops_by_pricing_currency: StableBTreeMap<String, u64, Memory>,
 }
 
 impl ShopsState {
+    pub fn init(
+        shop_id_generator_memory: Memory,
+        shops_memory: Memory,
+        owner_to_shops_memory: Memory,
+        referral_to_shops_memory: Memory,
+        shops_by_pricing_currency_memory: Memory,
+    ) -> Self {
+        Self {
+            shop_id_generator: StableCell::init(shop_id_generator_memory, 0)
+                .expect("Unable to init shop id generator"),
+            shops: StableBTreeMap::init(shops_memory),
+            owner_to_shops: StableBTreeMap::init(owner_to_shops_memory),
+            referral_to_shops: StableBTreeMap::init(referral_to_shops_memory),
+            shops_by_pricing_currency: StableBTreeMap::init(shops_by_pricing_currency_memory),
+        }
+    }
+
     pub fn create_shop(
         &mut self,
         invoice_creators: BTreeSet<Principal>,
@@ -37,96 +65,201 @@ impl ShopsState {
             icon_base64,
             referral: referral_opt,
             total_earned_usd: E8s::zero(),
+            pricing_currency: None,
+            overpayment_handling: None,
+            refund_managers: None,
+            invoice_ttl: None,
+            underpayment_tolerance: None,
+            rate_lock_policy: None,
+            idempotency_window: None,
         };
 
         self.shops.insert(id, shop);
-
-        match self.owner_to_shops.entry(caller) {
-            Entry::Occupied(mut e) => {
-                e.get_mut().insert(id);
-            }
-            Entry::Vacant(e) => {
-                let mut s = BTreeSet::new();
-                s.insert(id);
-
-                e.insert(s);
-            }
-        };
+        self.owner_to_shops.insert((caller, id), ());
 
         if let Some(referral) = referral_opt {
-            match self.referral_to_shops.entry(referral) {
-                Entry::Occupied(mut e) => {
-                    e.get_mut().insert(id, E8s::zero());
-                }
-                Entry::Vacant(e) => {
-                    let mut s = BTreeMap::new();
-                    s.insert(id, E8s::zero());
-
-                    e.insert(s);
-                }
-            }
+            self.referral_to_shops
+                .insert((referral, id), Candid(E8s::zero()));
         }
 
         id
     }
 
-    pub fn update_shop(
-        &mut self,
-        id: ShopId,
-        new_owner_opt: Option<Principal>,
-        new_invoice_creators_opt: Option<BTreeSet<Principal>>,
-        new_name_opt: Option<String>,
-        new_description_opt: Option<String>,
-        new_icon_base64_opt: Option<String>,
-        caller: Principal,
-    ) -> Result<(), String> {
-        let shop = self.shops.get_mut(&id).ok_or(format!("Shop not found"))?;
+    pub fn update_shop(&mut self, req: UpdateShopRequest, caller: Principal) -> Result<(), String> {
+        let id = req.id;
+        let mut shop = self.shops.get(&id).ok_or(format!("Shop not found"))?;
 
         if shop.owner != caller {
             return Err(format!("Access denied"));
         }
 
-        if let Some(new_owner) = new_owner_opt {
+        if let Some(new_owner) = req.new_owner_opt {
             self.owner_to_shops
-                .get_mut(&shop.owner)
-                .as_mut()
-                .ok_or(format!("Unreachable - no owner to shop relation found"))?
-                .remove(&id);
-
-            match self.owner_to_shops.entry(new_owner) {
-                Entry::Occupied(mut e) => {
-                    e.get_mut().insert(id);
-                }
-                Entry::Vacant(e) => {
-                    let mut s = BTreeSet::new();
-                    s.insert(id);
+                .remove(&(shop.owner, id))
+                .ok_or(format!("Unreachable - no owner to shop relation found"))?;
 
-                    e.insert(s);
-                }
-            };
+            self.owner_to_shops.insert((new_owner, id), ());
 
             shop.owner = new_owner;
         }
 
-        if let Some(new_invoice_creators) = new_invoice_creators_opt {
+        if let Some(new_invoice_creators) = req.new_invoice_creators_opt {
             shop.invoice_creators = new_invoice_creators;
         }
 
-        if let Some(new_name) = new_name_opt {
+        if let Some(new_name) = req.new_name_opt {
             shop.name = new_name;
         }
 
-        if let Some(new_description) = new_description_opt {
+        if let Some(new_description) = req.new_description_opt {
             shop.description = new_description;
         }
 
-        if let Some(new_icon_base64) = new_icon_base64_opt {
+        if let Some(new_icon_base64) = req.new_icon_base64_opt {
             shop.icon_base64 = new_icon_base64;
         }
 
+        if let Some(new_pricing_currency) = &req.new_pricing_currency_opt {
+            Self::validate_pricing_currency(new_pricing_currency)?;
+        }
+
+        if let Some(new_overpayment_handling) = req.new_overpayment_handling_opt {
+            shop.overpayment_handling = Some(new_overpayment_handling);
+        }
+
+        if let Some(new_refund_managers) = req.new_refund_managers_opt {
+            shop.refund_managers = Some(new_refund_managers);
+        }
+
+        if let Some(new_invoice_ttl) = req.new_invoice_ttl_opt {
+            new_invoice_ttl.validate()?;
+            shop.invoice_ttl = Some(new_invoice_ttl);
+        }
+
+        if let Some(new_underpayment_tolerance) = req.new_underpayment_tolerance_opt {
+            Self::validate_underpayment_tolerance(&new_underpayment_tolerance)?;
+            shop.underpayment_tolerance = Some(new_underpayment_tolerance);
+        }
+
+        if let Some(new_rate_lock_policy) = req.new_rate_lock_policy_opt {
+            shop.rate_lock_policy = Some(new_rate_lock_policy);
+        }
+
+        if let Some(new_idempotency_window) = req.new_idempotency_window_opt {
+            if !(MIN_IDEMPOTENCY_WINDOW_NS..=MAX_IDEMPOTENCY_WINDOW_NS)
+                .contains(&new_idempotency_window)
+            {
+                return Err(format!(
+                    "Invalid idempotency window: expected {}..={} ns",
+                    MIN_IDEMPOTENCY_WINDOW_NS, MAX_IDEMPOTENCY_WINDOW_NS
+                ));
+            }
+
+            shop.idempotency_window = Some(new_idempotency_window);
+        }
+
+        if let Some(new_pricing_currency) = req.new_pricing_currency_opt {
+            self.count_pricing_currency(
+                shop.pricing_currency.take(),
+                Some(new_pricing_currency.clone()),
+            );
+            shop.pricing_currency = Some(new_pricing_currency);
+        }
+
+        self.shops.insert(id, shop);
+
         Ok(())
     }
 
+    pub fn set_pricing_currency(
+        &mut self,
+        shop_id: &ShopId,
+        currency: String,
+    ) -> Result<(), String> {
+        Self::validate_pricing_currency(&currency)?;
+
+        let mut shop = self
+            .shops
+            .get(shop_id)
+            .ok_or("Shop not found".to_string())?;
+
+        self.count_pricing_currency(shop.pricing_currency.take(), Some(currency.clone()));
+        shop.pricing_currency = Some(currency);
+
+        self.shops.insert(*shop_id, shop);
+
+        Ok(())
+    }
+
+    /**
+     * Non-USD currencies the shops price their invoices in - the exchange rates refresher fetches these
+     */
+    pub fn pricing_currencies(&self) -> BTreeSet<String> {
+        self.shops_by_pricing_currency
+            .iter()
+            .map(|(currency, _)| currency)
+            .filter(|it| it != BASE_CURRENCY)
+            .collect()
+    }
+
+    /**
+     * Moves a shop from one pricing currency to another in the per-currency counters
+     */
+    pub fn count_pricing_currency(&mut self, old: Option<String>, new: Option<String>) {
+        if let Some(old) = old {
+            match self.shops_by_pricing_currency.get(&old) {
+                Some(count) if count > 1 => {
+                    self.shops_by_pricing_currency.insert(old, count - 1);
+                }
+                _ => {
+                    self.shops_by_pricing_currency.remove(&old);
+                }
+            }
+        }
+
+        if let Some(new) = new {
+            let count = self.shops_by_pricing_currency.get(&new).unwrap_or_default();
+            self.shops_by_pricing_currency.insert(new, count + 1);
+        }
+    }
+
+    pub fn get_shop(&self, shop_id: &ShopId) -> Option<Shop> {
+        self.shops.get(shop_id)
+    }
+
+    pub fn add_total_earned_usd(&mut self, shop_id: &ShopId, qty_usd: &E8s) {
+        if let Some(mut shop) = self.shops.get(shop_id) {
+            shop.total_earned_usd += qty_usd;
+
+            self.shops.insert(*shop_id, shop);
+        }
+    }
+
+    /**
+     * Refunded amounts are not earnings of the shop
+     */
+    pub fn sub_total_earned_usd(&mut self, shop_id: &ShopId, qty_usd: &E8s) {
+        if let Some(mut shop) = self.shops.get(shop_id) {
+            shop.total_earned_usd = if shop.total_earned_usd > *qty_usd {
+                shop.total_earned_usd - qty_usd
+            } else {
+                E8s::zero()
+            };
+
+            self.shops.insert(*shop_id, shop);
+        }
+    }
+
+    pub fn add_referral_earnings(&mut self, referral: Principal, shop_id: ShopId, qty_usd: E8s) {
+        let key = (referral, shop_id);
+
+        if let Some(Candid(mut earnings)) = self.referral_to_shops.get(&key) {
+            earnings += qty_usd;
+
+            self.referral_to_shops.insert(key, Candid(earnings));
+        }
+    }
+
     pub fn get_referral(&self, shop_id: &ShopId) -> Option<Principal> {
         let shop = self.shops.get(shop_id)?;
 
@@ -134,27 +267,97 @@ impl ShopsState {
     }
 
     pub fn get_shops_by_referral(&self, referral: &Principal) -> Vec<ReferredShop> {
-        if let Some(ids) = self.referral_to_shops.get(referral) {
-            ids.iter()
-                .map(|(id, earnings)| {
-                    self.shops
-                        .get(id)
-                        .map(|it| it.as_referred(earnings.clone()))
-                        .unwrap()
-                })
-                .collect()
-        } else {
-            Vec::new()
-        }
+        self.referral_to_shops
+            .range((*referral, ShopId::MIN)..=(*referral, ShopId::MAX))
+            .map(|((_, id), Candid(earnings))| {
+                self.shops
+                    .get(&id)
+                    .map(|it| it.as_referred(earnings))
+                    .unwrap()
+            })
+            .collect()
     }
 
     pub fn get_shops_by_owner(&self, owner: &Principal) -> Vec<Shop> {
-        if let Some(ids) = self.owner_to_shops.get(owner) {
-            ids.iter()
-                .map(|id| self.shops.get(id).cloned().unwrap())
-                .collect()
+        self.owner_to_shops
+            .range((*owner, ShopId::MIN)..=(*owner, ShopId::MAX))
+            .map(|((_, id), _)| self.shops.get(&id).unwrap())
+            .collect()
+    }
+
+    pub fn invoice_expires_at(
+        &self,
+        shop_id: &ShopId,
+        ttl_opt: Option<u64>,
+        now: Timestamp,
+    ) -> Result<Timestamp, String> {
+        let ttl = self
+            .shops
+            .get(shop_id)
+            .and_then(|it| it.invoice_ttl)
+            .unwrap_or_default()
+            .resolve(ttl_opt)?;
+
+        Ok(now + ttl)
+    }
+
+    /**
+     * The rate lock policy and the underpayment tolerance new invoices of the shop are created with
+     */
+    pub fn invoice_terms(&self, shop_id: &ShopId) -> (RateLockPolicy, UnderpaymentTolerance) {
+        self.shops
+            .get(shop_id)
+            .map(|it| {
+                (
+                    it.rate_lock_policy.unwrap_or_default(),
+                    it.underpayment_tolerance.unwrap_or_default(),
+                )
+            })
+            .unwrap_or_default()
+    }
+
+    /**
+     * Until when an idempotency key, used to create an invoice of the shop right now, is remembered
+     */
+    pub fn idempotency_key_expires_at(&self, shop_id: &ShopId, now: Timestamp) -> Timestamp {
+        let window = self
+            .shops
+            .get(shop_id)
+            .and_then(|it| it.idempotency_window)
+            .unwrap_or(DEFAULT_IDEMPOTENCY_WINDOW_NS);
+
+        now + window
+    }
+
+    pub fn validate_underpayment_tolerance(
+        tolerance: &UnderpaymentTolerance,
+    ) -> Result<(), String> {
+        let is_valid = tolerance.bps <= MAX_UNDERPAYMENT_TOLERANCE_BPS
+            && tolerance
+                .by_token
+                .iter()
+                .flatten()
+                .all(|(_, bps)| *bps <= MAX_UNDERPAYMENT_TOLERANCE_BPS);
+
+        if !is_valid {
+            return Err(format!(
+                "Invalid underpayment tolerance: expected at most {} bps",
+                MAX_UNDERPAYMENT_TOLERANCE_BPS
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn can_refund_invoices(&self, shop_id: &ShopId, caller: &Principal) -> bool {
+        if let Some(shop) = self.shops.get(shop_id) {
+            shop.owner == *caller
+                || shop
+                    .refund_managers
+                    .map(|it| it.contains(caller))
+                    .unwrap_or_default()
         } else {
-            Vec::new()
+            false
         }
     }
 
@@ -166,9 +369,19 @@ impl ShopsState {
         }
     }
 
+    fn validate_pricing_currency(currency: &str) -> Result<(), String> {
+        if !is_supported_fiat_currency(currency) {
+            return Err(format!("Unsupported pricing currency {}", currency));
+        }
+
+        Ok(())
+    }
+
     fn generate_shop_id(&mut self) -> ShopId {
-        let val = self.shop_id_generator;
-        self.shop_id_generator += 1;
+        let val = *self.shop_id_generator.get();
+        self.shop_id_generator
+            .set(val + 1)
+            .expect("Unable to update shop id generator");
 
         return val;
     }
@@ -2,9 +2,72 @@ use std::collections::BTreeSet;
 
 use candid::{CandidType, Principal};
 use ic_e8s::c::E8s;
+use msq_pay_types::{RateLockPolicy, UnderpaymentTolerance};
 use serde::Deserialize;
 
-use crate::utils::ShopId;
+use crate::{
+    impl_candid_storable,
+    utils::{ShopId, DEFAULT_INVOICE_TTL_NS, MAX_INVOICE_TTL_NS, MIN_INVOICE_TTL_NS},
+};
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverpaymentHandling {
+    // the excess is kept as a tip
+    #[default]
+    Keep,
+    // the excess is sent back to the payer, minus the transfer fee
+    Refund,
+}
+
+/**
+ * Lifetime of the shop's invoices in nanoseconds - invoice creators can pick any within `min..=max`
+ */
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvoiceTtlConfig {
+    pub default: u64,
+    pub min: u64,
+    pub max: u64,
+}
+
+impl Default for InvoiceTtlConfig {
+    fn default() -> Self {
+        Self {
+            default: DEFAULT_INVOICE_TTL_NS,
+            min: MIN_INVOICE_TTL_NS,
+            max: MAX_INVOICE_TTL_NS,
+        }
+    }
+}
+
+impl InvoiceTtlConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min < MIN_INVOICE_TTL_NS
+            || self.min > self.default
+            || self.default > self.max
+            || self.max > MAX_INVOICE_TTL_NS
+        {
+            return Err(format!(
+                "Invalid invoice TTL config: expected {} <= min <= default <= max <= {}",
+                MIN_INVOICE_TTL_NS, MAX_INVOICE_TTL_NS
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn resolve(&self, ttl_opt: Option<u64>) -> Result<u64, String> {
+        let ttl = ttl_opt.unwrap_or(self.default);
+
+        if ttl < self.min || ttl > self.max {
+            return Err(format!(
+                "Invalid invoice TTL: expected {}..={}, actual {}",
+                self.min, self.max, ttl
+            ));
+        }
+
+        Ok(ttl)
+    }
+}
 
 #[derive(CandidType, Deserialize, Clone)]
 pub struct Shop {
@@ -16,6 +79,19 @@ pub struct Shop {
     pub icon_base64: String,
     pub referral: Option<Principal>,
     pub total_earned_usd: E8s,
+    // fiat currency the invoices of the shop are priced in - USD, if not set
+    pub pricing_currency: Option<String>,
+    // what happens to the excess of overpaid invoices - kept, if not set
+    pub overpayment_handling: Option<OverpaymentHandling>,
+    // besides the owner, these can refund invoices of the shop
+    pub refund_managers: Option<BTreeSet<Principal>>,
+    // the platform defaults, if not set
+    pub invoice_ttl: Option<InvoiceTtlConfig>,
+    // both are recorded on each new invoice of the shop - the defaults, if not set
+    pub underpayment_tolerance: Option<UnderpaymentTolerance>,
+    pub rate_lock_policy: Option<RateLockPolicy>,
+    // for how long `create_invoice` idempotency keys of the shop are remembered - the default, if not set
+    pub idempotency_window: Option<u64>,
 }
 
 impl Shop {
@@ -57,3 +133,5 @@ pub struct PubShop {
     pub description: String,
     pub icon_base64: String,
 }
+
+impl_candid_storable!(Shop);
@@ -1,30 +1,86 @@
 use candid::{CandidType, Principal};
+use ic_cdk::api::stable::{stable64_read, stable64_size};
+use ic_stable_structures::{
+    memory_manager::VirtualMemory, storable::Bound, DefaultMemoryImpl, Storable,
+};
 use icrc_ledger_types::icrc1::account::Account;
-use serde::Deserialize;
+use msq_pay_types::InvoiceId;
+use serde::{de::DeserializeOwned, Deserialize};
 use sha2::Digest;
+use std::borrow::Cow;
 
 use ic_e8s::d::EDs;
 
 pub const USD_DECIMALS: u8 = 8;
-pub const DEFAULT_TTL: u8 = 1;
-pub const RECYCLING_TTL: u8 = 0;
+// invoice lifetimes are in nanoseconds
+pub const DEFAULT_INVOICE_TTL_NS: u64 = 20 * 60 * 1_000_000_000;
+pub const MIN_INVOICE_TTL_NS: u64 = 60 * 1_000_000_000;
+pub const MAX_INVOICE_TTL_NS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+pub const MAX_UNDERPAYMENT_TOLERANCE_BPS: u16 = 1_000;
+// how long `create_invoice` idempotency keys are remembered for
+pub const DEFAULT_IDEMPOTENCY_WINDOW_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+pub const MIN_IDEMPOTENCY_WINDOW_NS: u64 = 60 * 1_000_000_000;
+pub const MAX_IDEMPOTENCY_WINDOW_NS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
 
 pub const ID_GENERATION_DOMAIN: &[u8] = b"msq-id-generation";
 pub const MEMO_GENERATION_DOMAIN: &[u8] = b"msq-memo_generation";
 pub const SHOP_ID_SUBACCOUNT_DOMAIN: &[u8] = b"msq-shop-id-subaccount";
+pub const WITHDRAWAL_MEMO_DOMAIN: &[u8] = b"msq-withdrawal-memo";
+pub const INVOICE_DEPOSIT_SUBACCOUNT_DOMAIN: &[u8] = b"msq-invoice-deposit-subaccount";
+pub const IDEMPOTENCY_KEY_DOMAIN: &[u8] = b"msq-idempotency-key";
 pub const EXCHANGE_RATES_CANISTER_ID: &str = "uf6dk-hyaaa-aaaaq-qaaaq-cai";
+pub const ICP_LEDGER_CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+pub const CANDID_MAGIC: &[u8; 4] = b"DIDL";
 
 pub type Timestamp = u64;
 pub type ShopId = u64;
 pub type TokenId = Principal;
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+/// Implements `Storable` for a candid-serializable type, so it could be put into stable structures
+#[macro_export]
+macro_rules! impl_candid_storable {
+    ($t:ty) => {
+        impl ic_stable_structures::Storable for $t {
+            fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+                std::borrow::Cow::Owned(candid::encode_one(self).expect("Unable to encode"))
+            }
+
+            fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+                candid::decode_one(&bytes).expect("Unable to decode")
+            }
+
+            const BOUND: ic_stable_structures::storable::Bound =
+                ic_stable_structures::storable::Bound::Unbounded;
+        }
+    };
+}
+
+/// Same as `impl_candid_storable`, but for types defined in other crates (e.g. `Invoice` or `E8s`)
+#[derive(Clone, Debug, Default)]
+pub struct Candid<T>(pub T);
+
+impl<T: CandidType + DeserializeOwned> Storable for Candid<T> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(&self.0).expect("Unable to encode"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(candid::decode_one(&bytes).expect("Unable to decode"))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
 
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct TransferTxn {
-    pub from: Account,
+    // empty for legacy ICP ledger transfers - their sender is only known by its account identifier
+    pub from: Option<Account>,
     pub to: Account,
     pub qty: EDs,
     pub token_id: TokenId,
-    pub memo: [u8; 32],
+    // empty for transfers without a 32-byte memo - these can only pay to deposit subaccounts
+    pub memo: Option<[u8; 32]>,
 }
 
 pub fn calc_shop_subaccount(shop_id: ShopId) -> [u8; 32] {
@@ -35,3 +91,24 @@ pub fn calc_shop_subaccount(shop_id: ShopId) -> [u8; 32] {
 
     hasher.finalize().into()
 }
+
+pub fn calc_invoice_deposit_subaccount(invoice_id: &InvoiceId) -> [u8; 32] {
+    let mut hasher = sha2::Sha256::new();
+
+    hasher.update(INVOICE_DEPOSIT_SUBACCOUNT_DOMAIN);
+    hasher.update(invoice_id);
+
+    hasher.finalize().into()
+}
+
+/// Whether the stable memory still holds a state written with `stable_save` (before stable structures were used)
+pub fn is_stable_saved_layout() -> bool {
+    if stable64_size() == 0 {
+        return false;
+    }
+
+    let mut magic = [0u8; 4];
+    stable64_read(0, &mut magic);
+
+    &magic == CANDID_MAGIC
+}
@@ -0,0 +1,28 @@
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::utils::ShopId;
+
+use super::types::{Withdrawal, WithdrawalId};
+
+#[derive(CandidType, Deserialize)]
+pub struct GetWithdrawalRequest {
+    pub id: WithdrawalId,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetWithdrawalResponse {
+    pub withdrawal: Option<Withdrawal>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetShopWithdrawalsRequest {
+    pub shop_id: ShopId,
+    pub start_after: Option<WithdrawalId>,
+    pub limit: u32,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetShopWithdrawalsResponse {
+    pub withdrawals: Vec<Withdrawal>,
+}
@@ -0,0 +1,173 @@
+use candid::{Nat, Principal};
+use ic_e8s::d::EDs;
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use msq_pay_types::InvoiceId;
+
+use crate::utils::{Memory, ShopId, Timestamp, TokenId};
+
+use super::types::{
+    Withdrawal, WithdrawalId, WithdrawalLeg, WithdrawalLegStatus, MAX_WITHDRAWAL_LEG_ATTEMPTS,
+};
+
+pub const MAX_WITHDRAWALS_PAGE_SIZE: u32 = 100;
+
+pub enum LegAttemptError {
+    Retryable(String),
+    Fatal(String),
+}
+
+pub struct WithdrawalsState {
+    pub withdrawal_id_generator: StableCell<WithdrawalId, Memory>,
+    pub withdrawals: StableBTreeMap<WithdrawalId, Withdrawal, Memory>,
+    // withdrawals with at least one pending leg - these are retried by the timer
+    pub pending_withdrawals: StableBTreeMap<WithdrawalId, (), Memory>,
+    pub withdrawals_by_shop: StableBTreeMap<(ShopId, WithdrawalId), (), Memory>,
+}
+
+impl WithdrawalsState {
+    pub fn init(
+        withdrawal_id_generator_memory: Memory,
+        withdrawals_memory: Memory,
+        pending_withdrawals_memory: Memory,
+        withdrawals_by_shop_memory: Memory,
+    ) -> Self {
+        Self {
+            withdrawal_id_generator: StableCell::init(withdrawal_id_generator_memory, 0)
+                .expect("Unable to init withdrawal id generator"),
+            withdrawals: StableBTreeMap::init(withdrawals_memory),
+            pending_withdrawals: StableBTreeMap::init(pending_withdrawals_memory),
+            withdrawals_by_shop: StableBTreeMap::init(withdrawals_by_shop_memory),
+        }
+    }
+
+    pub fn create(
+        &mut self,
+        shop_id: ShopId,
+        token_id: TokenId,
+        initiator: Principal,
+        invoice_id: Option<InvoiceId>,
+        now: Timestamp,
+        make_legs: impl FnOnce(WithdrawalId) -> Vec<WithdrawalLeg>,
+    ) -> Withdrawal {
+        let id = self.generate_id();
+
+        let withdrawal = Withdrawal {
+            id,
+            shop_id,
+            token_id,
+            initiator,
+            created_at: now,
+            legs: make_legs(id),
+            invoice_id,
+        };
+
+        self.withdrawals.insert(id, withdrawal.clone());
+        self.pending_withdrawals.insert(id, ());
+        self.withdrawals_by_shop.insert((shop_id, id), ());
+
+        withdrawal
+    }
+
+    pub fn get(&self, id: &WithdrawalId) -> Option<Withdrawal> {
+        self.withdrawals.get(id)
+    }
+
+    pub fn get_by_shop(
+        &self,
+        shop_id: ShopId,
+        start_after: Option<WithdrawalId>,
+        limit: u32,
+    ) -> Result<Vec<Withdrawal>, String> {
+        if limit == 0 || limit > MAX_WITHDRAWALS_PAGE_SIZE {
+            return Err(format!(
+                "Invalid limit: expected 1..={}",
+                MAX_WITHDRAWALS_PAGE_SIZE
+            ));
+        }
+
+        let from = match start_after {
+            Some(id) => (shop_id, id.saturating_add(1)),
+            None => (shop_id, WithdrawalId::MIN),
+        };
+
+        let withdrawals = self
+            .withdrawals_by_shop
+            .range(from..=(shop_id, WithdrawalId::MAX))
+            .take(limit as usize)
+            .map(|((_, id), _)| self.withdrawals.get(&id).unwrap())
+            .collect();
+
+        Ok(withdrawals)
+    }
+
+    pub fn pending_ids(&self) -> Vec<WithdrawalId> {
+        self.pending_withdrawals.iter().map(|(id, _)| id).collect()
+    }
+
+    /**
+     * Sums up the pending legs of the shop's withdrawals and refunds - these funds are already debited
+     * from the shop's balance, but are still in its subaccount
+     */
+    pub fn pending_qty(&self, shop_id: ShopId, token_id: TokenId, decimals: u8) -> EDs {
+        self.pending_withdrawals
+            .iter()
+            .filter_map(|(id, _)| self.withdrawals.get(&id))
+            .filter(|it| it.shop_id == shop_id && it.token_id == token_id)
+            .flat_map(|it| it.legs)
+            .filter(|leg| leg.is_pending())
+            .fold(EDs::zero(decimals), |acc, leg| acc + leg.qty)
+    }
+
+    /**
+     * Returns the leg, if this attempt finished it - either completed or failed (attempts on already finished legs return None)
+     */
+    pub fn record_leg_attempt(
+        &mut self,
+        id: WithdrawalId,
+        leg_idx: usize,
+        result: Result<Nat, LegAttemptError>,
+    ) -> Option<WithdrawalLeg> {
+        let mut withdrawal = self.withdrawals.get(&id)?;
+        let leg = withdrawal.legs.get_mut(leg_idx)?;
+
+        let attempts = match &leg.status {
+            WithdrawalLegStatus::Pending { attempts, .. } => attempts + 1,
+            _ => return None,
+        };
+
+        leg.status = match result {
+            Ok(block_idx) => WithdrawalLegStatus::Completed { block_idx },
+            Err(LegAttemptError::Retryable(reason)) if attempts >= MAX_WITHDRAWAL_LEG_ATTEMPTS => {
+                WithdrawalLegStatus::Failed { reason }
+            }
+            Err(LegAttemptError::Retryable(reason)) => WithdrawalLegStatus::Pending {
+                attempts,
+                last_error: Some(reason),
+            },
+            Err(LegAttemptError::Fatal(reason)) => WithdrawalLegStatus::Failed { reason },
+        };
+
+        let finished_leg = if leg.is_pending() {
+            None
+        } else {
+            Some(leg.clone())
+        };
+
+        if withdrawal.is_finished() {
+            self.pending_withdrawals.remove(&id);
+        }
+
+        self.withdrawals.insert(id, withdrawal);
+
+        finished_leg
+    }
+
+    fn generate_id(&mut self) -> WithdrawalId {
+        let val = *self.withdrawal_id_generator.get();
+        self.withdrawal_id_generator
+            .set(val + 1)
+            .expect("Unable to update withdrawal id generator");
+
+        val
+    }
+}
@@ -0,0 +1,157 @@
+use candid::{CandidType, Nat, Principal};
+use ic_e8s::d::EDs;
+use icrc_ledger_types::icrc1::{
+    account::{Account, Subaccount},
+    transfer::{Memo, TransferArg},
+};
+use msq_pay_types::InvoiceId;
+use serde::Deserialize;
+use sha2::Digest;
+
+use crate::{
+    icp_ledger::types::{AccountIdentifier, IcpTransferArgs, TimeStamp, Tokens},
+    impl_candid_storable,
+    utils::{ShopId, Timestamp, TokenId, WITHDRAWAL_MEMO_DOMAIN},
+};
+
+pub type WithdrawalId = u64;
+
+pub const MAX_WITHDRAWAL_LEG_ATTEMPTS: u32 = 10;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WithdrawalLegKind {
+    Withdraw,
+    PlatformFee,
+    ReferralFee,
+    OverpaymentRefund,
+    Refund,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum WithdrawalLegStatus {
+    Pending {
+        attempts: u32,
+        last_error: Option<String>,
+    },
+    Completed {
+        block_idx: Nat,
+    },
+    Failed {
+        reason: String,
+    },
+}
+
+/**
+ * A single planned transfer out of the shop's subaccount. Its arguments (including `memo` and
+ * `created_at_time`) never change, so retrying it is always deduplicated by the ledger.
+ */
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct WithdrawalLeg {
+    pub kind: WithdrawalLegKind,
+    pub to: Account,
+    // ICP only - if set, the leg is sent with the legacy ICP ledger `transfer` to this address instead of `to`
+    pub to_account_identifier: Option<AccountIdentifier>,
+    pub qty: EDs,
+    pub fee: EDs,
+    pub memo: Memo,
+    pub created_at_time: Timestamp,
+    pub status: WithdrawalLegStatus,
+}
+
+impl WithdrawalLeg {
+    pub fn new(
+        withdrawal_id: WithdrawalId,
+        kind: WithdrawalLegKind,
+        to: Account,
+        qty: EDs,
+        fee: EDs,
+        memo_opt: Option<Memo>,
+        created_at_time: Timestamp,
+    ) -> Self {
+        Self {
+            kind,
+            to,
+            to_account_identifier: None,
+            qty,
+            fee,
+            memo: memo_opt.unwrap_or_else(|| Self::make_memo(withdrawal_id, kind)),
+            created_at_time,
+            status: WithdrawalLegStatus::Pending {
+                attempts: 0,
+                last_error: None,
+            },
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        matches!(self.status, WithdrawalLegStatus::Pending { .. })
+    }
+
+    pub fn transfer_arg(&self, from_subaccount: Subaccount) -> TransferArg {
+        TransferArg {
+            from_subaccount: Some(from_subaccount),
+            to: self.to,
+            fee: Some((&self.fee).into()),
+            memo: Some(self.memo.clone()),
+            created_at_time: Some(self.created_at_time),
+            amount: (&self.qty - &self.fee).into(),
+        }
+    }
+
+    pub fn icp_transfer_arg(&self, from_subaccount: Subaccount) -> Option<IcpTransferArgs> {
+        let to = self.to_account_identifier?;
+
+        // legacy transfers only take a u64 memo
+        let mut legacy_memo = [0u8; 8];
+        let memo_bytes = self.memo.0.as_slice();
+        let len = memo_bytes.len().min(legacy_memo.len());
+        legacy_memo[..len].copy_from_slice(&memo_bytes[..len]);
+
+        Some(IcpTransferArgs {
+            memo: u64::from_be_bytes(legacy_memo),
+            amount: Tokens {
+                e8s: u64::try_from(&(&self.qty - &self.fee).val).expect("ICP amount overflow"),
+            },
+            fee: Tokens {
+                e8s: u64::try_from(&self.fee.val).expect("ICP amount overflow"),
+            },
+            from_subaccount: Some(from_subaccount),
+            to,
+            created_at_time: Some(TimeStamp {
+                timestamp_nanos: self.created_at_time,
+            }),
+        })
+    }
+
+    fn make_memo(withdrawal_id: WithdrawalId, kind: WithdrawalLegKind) -> Memo {
+        let mut hasher = sha2::Sha256::new();
+
+        hasher.update(WITHDRAWAL_MEMO_DOMAIN);
+        hasher.update(withdrawal_id.to_le_bytes());
+        hasher.update([kind as u8]);
+
+        let memo: [u8; 32] = hasher.finalize().into();
+
+        Memo::from(memo.to_vec())
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Withdrawal {
+    pub id: WithdrawalId,
+    pub shop_id: ShopId,
+    pub token_id: TokenId,
+    pub initiator: Principal,
+    pub created_at: Timestamp,
+    pub legs: Vec<WithdrawalLeg>,
+    // set for invoice refunds - both automatic overpayment ones and the ones made by the shop
+    pub invoice_id: Option<InvoiceId>,
+}
+
+impl_candid_storable!(Withdrawal);
+
+impl Withdrawal {
+    pub fn is_finished(&self) -> bool {
+        self.legs.iter().all(|it| !it.is_pending())
+    }
+}